@@ -0,0 +1,160 @@
+use hmac::{Hmac, Mac, NewMac};
+use netsketch_shared::{Role, UserId};
+use sha2::Sha256;
+use std::io::{self, Write};
+use std::path::Path;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// Length in bytes of a generated server key
+const SERVER_KEY_LEN: usize = 32;
+/// Time a session token stays valid after login
+pub const SESSION_LIFETIME: Duration = Duration::from_secs(30 * 24 * 60 * 60);
+
+/// Secret used to derive room access tokens and sign session tokens. Tokens are HMACs, so they
+/// don't need to be stored and stay valid across restarts and room unloads as long as the key
+/// does
+pub struct ServerKey {
+    key: Vec<u8>,
+}
+
+/// Editor and viewer tokens of a room
+#[derive(Default, Clone)]
+pub struct RoomTokens {
+    pub editor: String,
+    pub viewer: String,
+}
+
+impl ServerKey {
+    pub fn new(key: Vec<u8>) -> Self {
+        ServerKey { key }
+    }
+
+    /// Reads key from file, generating and saving a random one if nonexistant. On Unix the file
+    /// is created readable only by its owner, and an existing one readable by anyone else is
+    /// refused
+    pub fn load_or_create<P: AsRef<Path>>(path: P) -> io::Result<Self> {
+        let path = path.as_ref();
+        if path.exists() {
+            #[cfg(unix)]
+            {
+                use std::os::unix::fs::PermissionsExt;
+                let mode = std::fs::metadata(path)?.permissions().mode();
+                if mode & 0o077 != 0 {
+                    return Err(io::Error::new(
+                        io::ErrorKind::PermissionDenied,
+                        format!(
+                            "Server key {} is accessible by other users (mode {:o}), run chmod 600 on it",
+                            path.display(),
+                            mode & 0o777
+                        ),
+                    ));
+                }
+            }
+            let key = std::fs::read(path)?;
+            if key.is_empty() {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    "Empty server key",
+                ));
+            }
+            return Ok(ServerKey::new(key));
+        }
+
+        let key: Vec<u8> = (0..SERVER_KEY_LEN).map(|_| rand::random()).collect();
+        let mut options = std::fs::OpenOptions::new();
+        options.write(true).create_new(true);
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::OpenOptionsExt;
+            options.mode(0o600);
+        }
+        options.open(path)?.write_all(&key)?;
+        Ok(ServerKey::new(key))
+    }
+
+    /// Computes hex encoded HMAC-SHA256 of data
+    pub fn sign(&self, data: &[u8]) -> String {
+        let mut mac =
+            Hmac::<Sha256>::new_varkey(&self.key).expect("HMAC accepts keys of any length");
+        mac.update(data);
+        format!("{:x}", mac.finalize().into_bytes())
+    }
+
+    pub fn room_tokens(&self, room_id: &str) -> RoomTokens {
+        RoomTokens {
+            editor: self.sign(format!("editor/{}", room_id).as_bytes()),
+            viewer: self.sign(format!("viewer/{}", room_id).as_bytes()),
+        }
+    }
+
+    /// Creates a session token for a logged in user, of the form `user_id.expiry.signature`
+    /// with expiry in seconds since the unix epoch
+    pub fn session_token(&self, user_id: UserId) -> String {
+        let expiry = (SystemTime::now() + SESSION_LIFETIME)
+            .duration_since(UNIX_EPOCH)
+            .map(|x| x.as_secs())
+            .unwrap_or(0);
+        let payload = format!("{}.{}", user_id, expiry);
+        let signature = self.sign(format!("session/{}", payload).as_bytes());
+        format!("{}.{}", payload, signature)
+    }
+
+    /// Returns the user id of a session token, or None if it is forged or expired
+    pub fn verify_session(&self, token: &str) -> Option<UserId> {
+        let mut fields = token.splitn(3, '.');
+        let user_id = fields.next()?;
+        let expiry = fields.next()?;
+        let signature = fields.next()?;
+
+        let expected = self.sign(format!("session/{}.{}", user_id, expiry).as_bytes());
+        if !constant_time_eq(signature.as_bytes(), expected.as_bytes()) {
+            return None;
+        }
+        let now = SystemTime::now().duration_since(UNIX_EPOCH).ok()?.as_secs();
+        if expiry.parse::<u64>().ok()? <= now {
+            return None;
+        }
+        user_id.parse().ok()
+    }
+}
+
+impl RoomTokens {
+    /// Returns the role granted by token, or None if it matches neither
+    pub fn role(&self, token: &str) -> Option<Role> {
+        if !self.editor.is_empty() && constant_time_eq(token.as_bytes(), self.editor.as_bytes()) {
+            Some(Role::Editor)
+        } else if !self.viewer.is_empty()
+            && constant_time_eq(token.as_bytes(), self.viewer.as_bytes())
+        {
+            Some(Role::Viewer)
+        } else {
+            None
+        }
+    }
+}
+
+/// Compares byte strings without exiting early, so timing doesn't reveal how much of a guessed token
+/// is correct
+pub fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b.iter()).fold(0, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[cfg(unix)]
+    #[test]
+    fn key_file_is_private() {
+        use std::os::unix::fs::PermissionsExt;
+        let path = std::env::temp_dir().join(format!("netsketch-key-{}", rand::random::<u64>()));
+        let key = ServerKey::load_or_create(&path).unwrap();
+        let mode = std::fs::metadata(&path).unwrap().permissions().mode();
+        assert_eq!(mode & 0o777, 0o600);
+        assert_eq!(ServerKey::load_or_create(&path).unwrap().key, key.key);
+
+        std::fs::set_permissions(&path, std::fs::Permissions::from_mode(0o644)).unwrap();
+        assert!(ServerKey::load_or_create(&path).is_err());
+        std::fs::remove_file(&path).unwrap();
+    }
+}
@@ -0,0 +1,305 @@
+use netsketch_shared::AssetHash;
+use sha2::{Digest, Sha256};
+use std::collections::HashMap;
+use std::io::{self, Read};
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+
+/// Maximum size of an uploaded asset in bytes
+pub const MAX_ASSET_SIZE: u64 = 16 * 1024 * 1024;
+
+/// Bytes at the start of an image its size is read from. Only JPEGs with more metadata than
+/// this before their frame header are refused for it
+const MAX_HEADER_LEN: usize = 1024 * 1024;
+
+/// File signatures of accepted image formats
+const IMAGE_SIGNATURES: &[&[u8]] = &[
+    // PNG
+    b"\x89PNG\r\n\x1a\n",
+    // JPEG
+    b"\xff\xd8\xff",
+    // GIF
+    b"GIF87a",
+    b"GIF89a",
+];
+
+#[derive(Debug)]
+pub enum AssetError {
+    /// Not an image, or its dimensions couldn't be read
+    UnsupportedFormat,
+    /// Storing the asset would exceed the store's total size limit
+    QuotaExceeded,
+    Io(io::Error),
+}
+
+impl std::fmt::Display for AssetError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            AssetError::UnsupportedFormat => write!(f, "Unsupported image format"),
+            AssetError::QuotaExceeded => write!(f, "Asset storage is full"),
+            AssetError::Io(err) => write!(f, "{}", err),
+        }
+    }
+}
+
+impl From<io::Error> for AssetError {
+    fn from(err: io::Error) -> Self {
+        AssetError::Io(err)
+    }
+}
+
+/// Content-addressed store for uploaded images. Each asset is saved as a file named by the
+/// hex encoded SHA-256 of its contents, so identical uploads are stored once
+pub struct AssetStore {
+    root: PathBuf,
+    /// Maximum total size in bytes of stored assets
+    max_total_size: u64,
+    /// Total size in bytes of stored assets
+    total_size: tokio::sync::Mutex<u64>,
+    /// Width and height of images looked up so far, by hash
+    image_sizes: Mutex<HashMap<AssetHash, (u32, u32)>>,
+}
+
+impl AssetStore {
+    /// Opens asset store rooted at directory, creating it if nonexistant
+    pub fn new<P: AsRef<Path>>(root: P, max_total_size: u64) -> io::Result<Self> {
+        std::fs::create_dir_all(root.as_ref())?;
+        let mut total_size = 0;
+        for entry in std::fs::read_dir(root.as_ref())? {
+            let metadata = entry?.metadata()?;
+            if metadata.is_file() {
+                total_size += metadata.len();
+            }
+        }
+        Ok(AssetStore {
+            root: root.as_ref().to_path_buf(),
+            max_total_size,
+            total_size: tokio::sync::Mutex::new(total_size),
+            image_sizes: Mutex::new(HashMap::new()),
+        })
+    }
+
+    pub fn root(&self) -> &Path {
+        &self.root
+    }
+
+    /// Stores image data, returning its hash
+    pub async fn store(&self, data: &[u8]) -> Result<AssetHash, AssetError> {
+        let header = &data[..data.len().min(MAX_HEADER_LEN)];
+        let size = image_size(header).ok_or(AssetError::UnsupportedFormat)?;
+
+        let hash = format!("{:x}", Sha256::digest(data));
+        let path = self.root.join(&hash);
+        // Held until the file is written, so concurrent uploads can't overshoot the quota
+        let mut total_size = self.total_size.lock().await;
+        if path.exists() {
+            return Ok(hash);
+        }
+        let new_total_size = *total_size + data.len() as u64;
+        if new_total_size > self.max_total_size {
+            return Err(AssetError::QuotaExceeded);
+        }
+
+        // Write to temporary file first so partial uploads are never visible under the hash
+        let tmp_path = self.root.join(format!(".{}.tmp", hash));
+        tokio::fs::write(&tmp_path, data).await?;
+        tokio::fs::rename(&tmp_path, &path).await?;
+        *total_size = new_total_size;
+        self.cache_image_size(&hash, size);
+        Ok(hash)
+    }
+
+    /// Checks whether an asset with this hash has been stored
+    pub fn contains(&self, hash: &str) -> bool {
+        is_valid_hash(hash) && self.root.join(hash).is_file()
+    }
+
+    /// Returns the width and height of a stored image, or None if it doesn't exist. Only the
+    /// image's header is read, on the blocking thread pool
+    pub async fn image_size(&self, hash: &str) -> Option<(u32, u32)> {
+        if let Some(size) = self
+            .image_sizes
+            .lock()
+            .expect("asset store poisoned")
+            .get(hash)
+        {
+            return Some(*size);
+        }
+        if !self.contains(hash) {
+            return None;
+        }
+        let path = self.root.join(hash);
+        let header = tokio::task::spawn_blocking(move || -> io::Result<Vec<u8>> {
+            let mut header = Vec::new();
+            std::fs::File::open(path)?
+                .take(MAX_HEADER_LEN as u64)
+                .read_to_end(&mut header)?;
+            Ok(header)
+        })
+        .await
+        .ok()?
+        .ok()?;
+        let size = image_size(&header)?;
+        self.cache_image_size(hash, size);
+        Some(size)
+    }
+
+    fn cache_image_size(&self, hash: &str, size: (u32, u32)) {
+        self.image_sizes
+            .lock()
+            .expect("asset store poisoned")
+            .insert(hash.to_string(), size);
+    }
+}
+
+fn is_image(data: &[u8]) -> bool {
+    is_webp(data) || IMAGE_SIGNATURES.iter().any(|x| data.starts_with(x))
+}
+
+/// WebP is a RIFF container with a WEBP form type
+fn is_webp(data: &[u8]) -> bool {
+    data.len() >= 12 && &data[0..4] == b"RIFF" && &data[8..12] == b"WEBP"
+}
+
+fn is_valid_hash(hash: &str) -> bool {
+    hash.len() == 64
+        && hash
+            .chars()
+            .all(|x| x.is_ascii_digit() || ('a'..='f').contains(&x))
+}
+
+fn u16_be(data: &[u8], at: usize) -> Option<u32> {
+    Some(u16::from_be_bytes([*data.get(at)?, *data.get(at + 1)?]) as u32)
+}
+
+fn u16_le(data: &[u8], at: usize) -> Option<u32> {
+    Some(u16::from_le_bytes([*data.get(at)?, *data.get(at + 1)?]) as u32)
+}
+
+fn u24_le(data: &[u8], at: usize) -> Option<u32> {
+    Some(u16_le(data, at)? | (*data.get(at + 2)? as u32) << 16)
+}
+
+fn u32_be(data: &[u8], at: usize) -> Option<u32> {
+    Some(u16_be(data, at)? << 16 | u16_be(data, at + 2)?)
+}
+
+/// Reads the width and height of an image from its header. Returns None if it isn't an image
+/// in an accepted format, or the header is truncated
+fn image_size(data: &[u8]) -> Option<(u32, u32)> {
+    if !is_image(data) {
+        return None;
+    }
+    let size = match data.get(0..3)? {
+        b"\x89PN" => (u32_be(data, 16)?, u32_be(data, 20)?),
+        b"GIF" => (u16_le(data, 6)?, u16_le(data, 8)?),
+        b"\xff\xd8\xff" => jpeg_size(data)?,
+        _ => webp_size(data)?,
+    };
+    if size.0 == 0 || size.1 == 0 {
+        return None;
+    }
+    Some(size)
+}
+
+/// Finds the frame header among a JPEG's segments
+fn jpeg_size(data: &[u8]) -> Option<(u32, u32)> {
+    let mut at = 2;
+    loop {
+        if *data.get(at)? != 0xff {
+            return None;
+        }
+        let marker = *data.get(at + 1)?;
+        match marker {
+            // Fill bytes before a marker
+            0xff => at += 1,
+            // Start of frame, except DHT, JPG and DAC which share the range
+            0xc0..=0xcf if marker != 0xc4 && marker != 0xc8 && marker != 0xcc => {
+                return Some((u16_be(data, at + 7)?, u16_be(data, at + 5)?));
+            }
+            _ => at += 2 + u16_be(data, at + 2)? as usize,
+        }
+    }
+}
+
+/// Reads the canvas size of a lossy, lossless or extended WebP
+fn webp_size(data: &[u8]) -> Option<(u32, u32)> {
+    match data.get(12..16)? {
+        b"VP8 " => Some((u16_le(data, 26)? & 0x3fff, u16_le(data, 28)? & 0x3fff)),
+        b"VP8L" => {
+            let bits = u16_le(data, 21)? | u16_le(data, 23)? << 16;
+            Some(((bits & 0x3fff) + 1, (bits >> 14 & 0x3fff) + 1))
+        }
+        b"VP8X" => Some((u24_le(data, 24)? + 1, u24_le(data, 27)? + 1)),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn png(width: u32, height: u32) -> Vec<u8> {
+        let mut data = b"\x89PNG\r\n\x1a\n\0\0\0\x0dIHDR".to_vec();
+        data.extend_from_slice(&width.to_be_bytes());
+        data.extend_from_slice(&height.to_be_bytes());
+        data.extend_from_slice(&[8, 6, 0, 0, 0]);
+        data
+    }
+
+    #[test]
+    fn reads_image_sizes() {
+        assert_eq!(image_size(&png(640, 480)), Some((640, 480)));
+        assert_eq!(image_size(b"GIF89a\x20\x00\x10\x00"), Some((32, 16)));
+
+        // APP0 segment, then a baseline frame header
+        let jpeg = b"\xff\xd8\xff\xe0\x00\x04\x00\x00\xff\xc0\x00\x11\x08\x00\x30\x00\x40";
+        assert_eq!(image_size(jpeg), Some((64, 48)));
+
+        let mut webp = b"RIFF\0\0\0\0WEBPVP8X\x0a\0\0\0\0\0\0\0".to_vec();
+        webp.extend_from_slice(&[99, 0, 0, 49, 0, 0]);
+        assert_eq!(image_size(&webp), Some((100, 50)));
+
+        assert_eq!(image_size(&png(640, 480)[..20]), None);
+        assert_eq!(image_size(&png(0, 480)), None);
+        assert_eq!(image_size(b"<svg></svg>"), None);
+        assert!(is_image(b"RIFF\0\0\0\0WEBP"));
+        assert!(!is_image(b"RIFF\0\0\0\0WAVE"));
+    }
+
+    #[test]
+    fn validates_hashes() {
+        assert!(is_valid_hash(&"0123456789abcdef".repeat(4)));
+        assert!(!is_valid_hash(&"0123456789ABCDEF".repeat(4)));
+        assert!(!is_valid_hash("../server.key"));
+        assert!(!is_valid_hash(""));
+    }
+
+    #[tokio::test]
+    async fn stores_images_within_quota() {
+        let root = std::env::temp_dir().join(format!("netsketch-assets-{}", rand::random::<u64>()));
+        let image = png(2, 3);
+        let store = AssetStore::new(&root, image.len() as u64 + 8).unwrap();
+
+        let hash = store.store(&image).await.unwrap();
+        assert!(store.contains(&hash));
+        assert_eq!(store.image_size(&hash).await, Some((2, 3)));
+        // Storing the same image again takes no more space
+        assert_eq!(store.store(&image).await.unwrap(), hash);
+        assert!(matches!(
+            store.store(&png(3, 2)).await,
+            Err(AssetError::QuotaExceeded)
+        ));
+        assert!(matches!(
+            store.store(b"not an image").await,
+            Err(AssetError::UnsupportedFormat)
+        ));
+
+        // Reopening counts what's already stored
+        let reopened = AssetStore::new(&root, image.len() as u64 + 8).unwrap();
+        assert_eq!(reopened.image_size(&hash).await, Some((2, 3)));
+        assert_eq!(reopened.image_size(&"0".repeat(64)).await, None);
+        assert!(reopened.store(&png(3, 2)).await.is_err());
+        std::fs::remove_dir_all(&root).unwrap();
+    }
+}
@@ -0,0 +1,195 @@
+use crate::outbound::QueueLimits;
+use crate::ratelimit::{HttpRateLimits, RateLimits};
+use netsketch_shared::Limits;
+use serde::{Deserialize, Deserializer};
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+/// Server settings, read from a TOML file. Anything left out keeps its default
+#[derive(Deserialize, Debug, PartialEq, Clone)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    /// Addresses to listen on. On most systems `[::]` also accepts IPv4 connections, so listening
+    /// on `0.0.0.0` as well needs a different port
+    pub listen: Vec<SocketAddr>,
+    /// Directory the frontend is served from
+    pub static_dir: PathBuf,
+    pub storage: StorageConfig,
+    pub rooms: RoomsConfig,
+    /// Limits on the size and values of client messages
+    pub limits: Limits,
+    /// Rate limits of each connection
+    pub rate_limits: RateLimits,
+    /// How many connections' worth of traffic all connections from one address may send. Off
+    /// when unset, as behind a reverse proxy every connection comes from the proxy's address
+    pub ip_rate_scale: Option<f64>,
+    /// Rate limits of HTTP requests from each address
+    pub http_rate_limits: HttpRateLimits,
+    pub queue: QueueLimits,
+    pub metrics: MetricsConfig,
+    pub log: LogConfig,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Config {
+            listen: vec![SocketAddr::from(([0u16; 8], 8081))],
+            static_dir: PathBuf::from("static"),
+            storage: StorageConfig::default(),
+            rooms: RoomsConfig::default(),
+            limits: Limits::default(),
+            rate_limits: RateLimits::default(),
+            ip_rate_scale: None,
+            http_rate_limits: HttpRateLimits::default(),
+            queue: QueueLimits::default(),
+            metrics: MetricsConfig::default(),
+            log: LogConfig::default(),
+        }
+    }
+}
+
+/// Where uploaded and persistent data is kept
+#[derive(Deserialize, Debug, PartialEq, Clone)]
+#[serde(default, deny_unknown_fields)]
+pub struct StorageConfig {
+    /// Directory uploaded images are stored in
+    pub asset_dir: PathBuf,
+    /// Maximum total size in bytes of uploaded images
+    pub max_asset_bytes: u64,
+    /// File holding the key room access and session tokens are derived from
+    pub key_path: PathBuf,
+    /// File registered users are stored in
+    pub users_path: PathBuf,
+    /// Directory rooms are saved to when unloaded
+    pub rooms_dir: PathBuf,
+}
+
+impl Default for StorageConfig {
+    fn default() -> Self {
+        StorageConfig {
+            asset_dir: PathBuf::from("assets"),
+            max_asset_bytes: 1 << 30,
+            key_path: PathBuf::from("server.key"),
+            users_path: PathBuf::from("users.txt"),
+            rooms_dir: PathBuf::from("rooms"),
+        }
+    }
+}
+
+#[derive(Deserialize, Debug, PartialEq, Clone)]
+#[serde(default, deny_unknown_fields)]
+pub struct RoomsConfig {
+    /// Time an empty room stays loaded
+    #[serde(rename = "idle_secs", deserialize_with = "deserialize_secs")]
+    pub idle_timeout: Duration,
+    /// Number of elements recently added to a room that undo searches for the user's latest
+    pub undo_depth: usize,
+}
+
+impl Default for RoomsConfig {
+    fn default() -> Self {
+        RoomsConfig {
+            idle_timeout: Duration::from_secs(300),
+            undo_depth: 100,
+        }
+    }
+}
+
+/// Prometheus metrics endpoint
+#[derive(Deserialize, Debug, Default, PartialEq, Clone)]
+#[serde(default, deny_unknown_fields)]
+pub struct MetricsConfig {
+    /// Address `/metrics` is served on, apart from the public addresses so it can be kept
+    /// private. Metrics aren't served if unset
+    pub listen: Option<SocketAddr>,
+    /// Label series of loaded rooms with room names instead of summing all rooms. Room names are
+    /// part of their URLs, so anyone who can read the metrics can find the rooms
+    pub per_room: bool,
+}
+
+#[derive(Deserialize, Debug, Default, PartialEq, Clone)]
+#[serde(default, deny_unknown_fields)]
+pub struct LogConfig {
+    /// Log filter in `RUST_LOG` syntax, such as `info,warp=debug`. `RUST_LOG` takes precedence,
+    /// and if neither is set everything at info level and above is logged
+    pub filter: Option<String>,
+}
+
+/// Reads a duration given in seconds, which may be fractional
+pub fn deserialize_secs<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Duration, D::Error> {
+    let secs = f64::deserialize(deserializer)?;
+    if !secs.is_finite() || secs < 0.0 {
+        return Err(serde::de::Error::custom(
+            "duration must be a positive number of seconds",
+        ));
+    }
+    Ok(Duration::from_secs_f64(secs))
+}
+
+#[derive(Debug)]
+pub enum ConfigError {
+    Io(PathBuf, std::io::Error),
+    Parse(PathBuf, toml::de::Error),
+}
+
+impl std::fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            ConfigError::Io(path, err) => write!(f, "Unable to read {}: {}", path.display(), err),
+            ConfigError::Parse(path, err) => {
+                write!(f, "Invalid config {}: {}", path.display(), err)
+            }
+        }
+    }
+}
+
+impl Config {
+    pub fn load(path: &Path) -> Result<Self, ConfigError> {
+        let text =
+            std::fs::read_to_string(path).map_err(|x| ConfigError::Io(path.to_path_buf(), x))?;
+        toml::from_str(&text).map_err(|x| ConfigError::Parse(path.to_path_buf(), x))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn example_config_is_valid() {
+        let config: Config = toml::from_str(include_str!("../netsketch.example.toml")).unwrap();
+        assert_eq!(
+            Config {
+                log: LogConfig::default(),
+                ..config
+            },
+            Config::default()
+        );
+    }
+
+    #[test]
+    fn partial_config_keeps_defaults() {
+        let config: Config = toml::from_str(
+            r#"
+            listen = ["0.0.0.0:9000", "[::1]:9001"]
+            [rate_limits]
+            strokes = { rate = 100.0, burst = 200.0 }
+            [queue]
+            max_lag_secs = 2.5
+            "#,
+        )
+        .unwrap();
+        assert_eq!(config.listen.len(), 2);
+        assert_eq!(config.rate_limits.strokes.burst, 200.0);
+        assert_eq!(config.rate_limits.points, RateLimits::default().points);
+        assert_eq!(config.queue.max_lag, Duration::from_millis(2500));
+        assert_eq!(
+            config.queue.max_messages,
+            QueueLimits::default().max_messages
+        );
+        assert_eq!(config.limits, Limits::default());
+
+        assert!(toml::from_str::<Config>("listne = []").is_err());
+    }
+}
@@ -0,0 +1,508 @@
+//! In-process clients for exercising rooms without websockets
+
+use crate::access::RoomTokens;
+use crate::config::RoomsConfig;
+use crate::snapshots::SavedRoom;
+use crate::{ConnectionId, MessageSink, Room};
+use bytes::Bytes;
+use netsketch_shared::prelude::*;
+use std::sync::{Arc, Mutex};
+
+/// Sink collecting the messages sent to a connection
+#[derive(Default)]
+pub struct RecordingSink {
+    messages: Mutex<Vec<ServerMessage>>,
+    close_code: Mutex<Option<u16>>,
+}
+
+impl RecordingSink {
+    /// Removes and returns everything received so far
+    pub fn take(&self) -> Vec<ServerMessage> {
+        std::mem::take(&mut *self.messages.lock().expect("recording sink poisoned"))
+    }
+
+    /// Code the connection was closed with, if it was
+    pub fn close_code(&self) -> Option<u16> {
+        *self.close_code.lock().expect("recording sink poisoned")
+    }
+}
+
+impl MessageSink for RecordingSink {
+    fn send(&self, msg: &ServerMessage, data: &Bytes) {
+        debug_assert_eq!(netsketch_shared::from_zbincode(data).as_ref(), Ok(msg));
+        self.messages
+            .lock()
+            .expect("recording sink poisoned")
+            .push(msg.clone());
+    }
+
+    fn close(&self, code: u16, _reason: &'static str) {
+        *self.close_code.lock().expect("recording sink poisoned") = Some(code);
+    }
+}
+
+/// Client connected to a room in the same process
+pub struct TestClient {
+    pub conn_id: ConnectionId,
+    pub user_id: UserId,
+    room: Arc<Room>,
+    sink: Arc<RecordingSink>,
+}
+
+impl TestClient {
+    /// Joins a room as a new user
+    pub async fn join(room: &Arc<Room>, user_id: UserId, role: Role) -> Self {
+        TestClient::connect(room, user_id, role, None).await
+    }
+
+    /// Joins a room, resuming the connection with the resume token if it is still resumable
+    pub async fn resume(
+        room: &Arc<Room>,
+        user_id: UserId,
+        role: Role,
+        resume_token: &str,
+        seq: SequenceNumber,
+    ) -> Self {
+        let resume = Some((resume_token.to_string(), seq));
+        TestClient::connect(room, user_id, role, resume).await
+    }
+
+    async fn connect(
+        room: &Arc<Room>,
+        user_id: UserId,
+        role: Role,
+        resume: Option<(String, SequenceNumber)>,
+    ) -> Self {
+        let sink = Arc::new(RecordingSink::default());
+        let conn_id = room
+            .connect(
+                sink.clone(),
+                user_id,
+                format!("user{}", user_id),
+                role,
+                resume,
+            )
+            .await;
+        TestClient {
+            conn_id,
+            user_id,
+            room: room.clone(),
+            sink,
+        }
+    }
+
+    pub async fn send(&self, msg: ClientMessage) {
+        self.room.receive_msg(self.conn_id, msg).await;
+    }
+
+    /// Waits for the room to process everything sent so far, then returns what this client
+    /// received since last called, with sequence numbers removed
+    pub async fn received(&self) -> Vec<ServerMessage> {
+        self.room.sync().await;
+        self.sink
+            .take()
+            .into_iter()
+            .map(|x| match x {
+                ServerMessage::Sequenced(_, msg) => *msg,
+                msg => msg,
+            })
+            .collect()
+    }
+
+    /// Code the room closed this connection with, if it did
+    pub fn close_code(&self) -> Option<u16> {
+        self.sink.close_code()
+    }
+
+    pub async fn leave(self) {
+        self.room.disconnect(self.conn_id).await;
+    }
+}
+
+/// Creates an empty room, without an asset store
+pub fn test_room() -> Arc<Room> {
+    test_room_with_limits(Limits::default())
+}
+
+/// Creates an empty room with the given limits, without an asset store
+pub fn test_room_with_limits(limits: Limits) -> Arc<Room> {
+    let tokens = RoomTokens {
+        editor: "editor".to_string(),
+        viewer: "viewer".to_string(),
+    };
+    Arc::new(Room::spawn(
+        "test".to_string(),
+        tokens,
+        None,
+        limits,
+        RoomsConfig::default().undo_depth,
+        Arc::default(),
+        SavedRoom::default(),
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::users::FIRST_GUEST_ID;
+    use crate::CLOSE_SERVICE_RESTART;
+
+    fn stroke(x: i32, y: i32) -> ClientMessage {
+        ClientMessage::PaintStroke(
+            1,
+            ClientStrokeId::new_v4(),
+            PaintStroke {
+                id: 0,
+                user_id: 0,
+                brush: Brush::default(),
+                points: vec![
+                    StrokePoint { p: 1.0, x, y },
+                    StrokePoint {
+                        p: 1.0,
+                        x: x + 5,
+                        y,
+                    },
+                ],
+            },
+        )
+    }
+
+    fn viewport(x: i32, y: i32) -> ClientMessage {
+        ClientMessage::SetViewPort(
+            Offset { x, y },
+            Offset {
+                x: x + 99,
+                y: y + 99,
+            },
+        )
+    }
+
+    fn painted(messages: &[ServerMessage]) -> usize {
+        messages
+            .iter()
+            .filter(|x| matches!(x, ServerMessage::PaintStroke(..)))
+            .count()
+    }
+
+    #[tokio::test]
+    async fn broadcasts_room_changes() {
+        let room = test_room();
+        let alice = TestClient::join(&room, 1, Role::Editor).await;
+        let bob = TestClient::join(&room, 2, Role::Viewer).await;
+        assert!(matches!(
+            alice.received().await.as_slice(),
+            [ServerMessage::Welcome(Welcome {
+                role: Role::Editor,
+                ..
+            })]
+        ));
+        bob.received().await;
+
+        alice.send(ClientMessage::CreateGroup).await;
+        for client in &[&alice, &bob] {
+            assert!(matches!(
+                client.received().await.as_slice(),
+                [ServerMessage::LayerTree(_)]
+            ));
+        }
+
+        // Viewers can't change the room
+        bob.send(ClientMessage::CreateGroup).await;
+        assert!(alice.received().await.is_empty());
+        assert!(matches!(
+            bob.received().await.as_slice(),
+            [ServerMessage::Error {
+                code: ErrorCode::Forbidden,
+                ..
+            }]
+        ));
+    }
+
+    #[tokio::test]
+    async fn strokes_reach_only_viewers_of_their_tiles() {
+        let room = test_room();
+        let alice = TestClient::join(&room, 1, Role::Editor).await;
+        let bob = TestClient::join(&room, 2, Role::Editor).await;
+        let carol = TestClient::join(&room, 3, Role::Editor).await;
+        alice.send(viewport(0, 0)).await;
+        bob.send(viewport(0, 0)).await;
+        carol.send(viewport(1000, 1000)).await;
+        for client in &[&alice, &bob, &carol] {
+            client.received().await;
+        }
+
+        alice.send(stroke(10, 10)).await;
+        let received = alice.received().await;
+        assert_eq!(painted(&received), 0);
+        assert!(received
+            .iter()
+            .any(|x| matches!(x, ServerMessage::StrokeAck { .. })));
+        assert_eq!(painted(&bob.received().await), 1);
+        assert_eq!(painted(&carol.received().await), 0);
+
+        // Moving the viewport sends what's already there, and subscribes to new changes
+        carol.send(viewport(0, 0)).await;
+        assert_eq!(painted(&carol.received().await), 1);
+        bob.send(stroke(20, 20)).await;
+        assert_eq!(painted(&alice.received().await), 1);
+        assert_eq!(painted(&carol.received().await), 1);
+
+        // Nothing is sent to connections that have left
+        carol.leave().await;
+        alice.send(stroke(30, 30)).await;
+        assert_eq!(painted(&bob.received().await), 1);
+    }
+
+    #[tokio::test]
+    async fn erasing_removes_strokes_for_viewers() {
+        let room = test_room();
+        let alice = TestClient::join(&room, 1, Role::Editor).await;
+        let bob = TestClient::join(&room, 2, Role::Editor).await;
+        alice.send(viewport(0, 0)).await;
+        bob.send(viewport(0, 0)).await;
+        alice.send(stroke(10, 10)).await;
+        let id = alice
+            .received()
+            .await
+            .iter()
+            .find_map(|x| match x {
+                ServerMessage::StrokeAck { id, .. } => Some(*id),
+                _ => None,
+            })
+            .expect("stroke acknowledged");
+        bob.received().await;
+
+        let erase = StrokeErase {
+            path: vec![Offset { x: 0, y: 10 }, Offset { x: 20, y: 10 }],
+            width: 4.0,
+            mode: StrokeEraseMode::Delete,
+        };
+        bob.send(ClientMessage::StrokeErase(1, erase)).await;
+        for client in &[&alice, &bob] {
+            assert_eq!(
+                client.received().await,
+                vec![ServerMessage::RemoveElement(1, id)]
+            );
+        }
+
+        // A new viewer doesn't see the erased stroke
+        let carol = TestClient::join(&room, 3, Role::Viewer).await;
+        carol.send(viewport(0, 0)).await;
+        assert_eq!(painted(&carol.received().await), 0);
+    }
+
+    fn acked(messages: &[ServerMessage]) -> Option<ElementId> {
+        messages.iter().find_map(|x| match x {
+            ServerMessage::StrokeAck { id, .. } => Some(*id),
+            _ => None,
+        })
+    }
+
+    #[tokio::test]
+    async fn undo_removes_own_latest_element() {
+        let room = test_room();
+        let alice = TestClient::join(&room, 1, Role::Editor).await;
+        let bob = TestClient::join(&room, 2, Role::Editor).await;
+        alice.send(viewport(0, 0)).await;
+        bob.send(viewport(0, 0)).await;
+        alice.send(stroke(10, 10)).await;
+        let first = acked(&alice.received().await).expect("stroke acknowledged");
+        alice.send(stroke(10, 20)).await;
+        let second = acked(&alice.received().await).expect("stroke acknowledged");
+        bob.send(stroke(10, 30)).await;
+        bob.received().await;
+        alice.received().await;
+
+        // Bob's later stroke stays, Alice's strokes go newest first
+        for id in &[second, first] {
+            alice.send(ClientMessage::UndoMessage).await;
+            for client in &[&alice, &bob] {
+                assert_eq!(
+                    client.received().await,
+                    vec![ServerMessage::RemoveElement(1, *id)]
+                );
+            }
+        }
+
+        // Nothing left to undo
+        alice.send(ClientMessage::UndoMessage).await;
+        assert!(bob.received().await.is_empty());
+        let carol = TestClient::join(&room, 3, Role::Viewer).await;
+        carol.send(viewport(0, 0)).await;
+        assert_eq!(painted(&carol.received().await), 1);
+    }
+
+    #[tokio::test]
+    async fn undo_removes_pieces_of_split_strokes() {
+        let room = test_room();
+        let alice = TestClient::join(&room, 1, Role::Editor).await;
+        let bob = TestClient::join(&room, 2, Role::Editor).await;
+        alice.send(viewport(0, 0)).await;
+        bob.send(viewport(0, 0)).await;
+        let points = (0..7)
+            .map(|i| StrokePoint {
+                p: 1.0,
+                x: i * 10,
+                y: 10,
+            })
+            .collect();
+        alice
+            .send(ClientMessage::PaintStroke(
+                1,
+                ClientStrokeId::new_v4(),
+                PaintStroke {
+                    id: 0,
+                    user_id: 0,
+                    brush: Brush::default(),
+                    points,
+                },
+            ))
+            .await;
+        acked(&alice.received().await).expect("stroke acknowledged");
+        bob.received().await;
+
+        // Bob cuts Alice's stroke in two
+        let erase = StrokeErase {
+            path: vec![Offset { x: 30, y: 0 }, Offset { x: 30, y: 20 }],
+            width: 4.0,
+            mode: StrokeEraseMode::Split,
+        };
+        bob.send(ClientMessage::StrokeErase(1, erase)).await;
+        let pieces: Vec<ElementId> = match &alice.received().await[..] {
+            [ServerMessage::ReplaceStroke(1, _, pieces)] => pieces.iter().map(|x| x.id).collect(),
+            received => panic!("unexpected {:?}", received),
+        };
+        assert_eq!(pieces.len(), 2);
+        bob.received().await;
+
+        // Undoing the stroke removes both pieces
+        alice.send(ClientMessage::UndoMessage).await;
+        let removed: Vec<_> = pieces
+            .iter()
+            .map(|x| ServerMessage::RemoveElement(1, *x))
+            .collect();
+        for client in &[&alice, &bob] {
+            assert_eq!(client.received().await, removed);
+        }
+        let carol = TestClient::join(&room, 3, Role::Viewer).await;
+        carol.send(viewport(0, 0)).await;
+        assert_eq!(painted(&carol.received().await), 0);
+    }
+
+    fn fill(x: i32, y: i32) -> ClientMessage {
+        ClientMessage::FloodFill(
+            1,
+            FloodFill {
+                seed: Offset { x, y },
+                tolerance: 0,
+                color: Color::default(),
+            },
+        )
+    }
+
+    #[tokio::test]
+    async fn fills_are_added_once_computed() {
+        let room = test_room();
+        let alice = TestClient::join(&room, 1, Role::Editor).await;
+        alice.send(viewport(0, 0)).await;
+        alice.received().await;
+
+        // Computed on another thread, so the room keeps going meanwhile
+        alice.send(fill(10, 10)).await;
+        let mut received = Vec::new();
+        for _ in 0..100 {
+            received.extend(alice.received().await);
+            if !received.is_empty() {
+                break;
+            }
+            tokio::time::delay_for(std::time::Duration::from_millis(10)).await;
+        }
+        assert!(matches!(
+            received.as_slice(),
+            [ServerMessage::LayerTree(_), ServerMessage::Fill(1, _)]
+        ));
+    }
+
+    #[tokio::test]
+    async fn fills_drawing_too_many_elements_are_refused() {
+        let room = test_room_with_limits(Limits {
+            max_fill_elements: 1,
+            ..Limits::default()
+        });
+        let alice = TestClient::join(&room, 1, Role::Editor).await;
+        alice.send(stroke(10, 10)).await;
+        alice.send(stroke(10, 20)).await;
+        alice.received().await;
+
+        alice.send(fill(50, 50)).await;
+        assert!(matches!(
+            alice.received().await.as_slice(),
+            [ServerMessage::Error {
+                code: ErrorCode::LimitReached,
+                ..
+            }]
+        ));
+    }
+
+    fn welcome(messages: &[ServerMessage]) -> Option<&Welcome> {
+        messages.iter().find_map(|x| match x {
+            ServerMessage::Welcome(welcome) => Some(welcome),
+            _ => None,
+        })
+    }
+
+    #[tokio::test]
+    async fn resume_keeps_identity_of_same_user() {
+        let room = test_room();
+        let alice = TestClient::join(&room, 1, Role::Editor).await;
+        let resume_token = welcome(&alice.received().await)
+            .unwrap()
+            .resume_token
+            .clone();
+        alice.leave().await;
+
+        // Someone else holding the token can't take over the connection
+        let mallory = TestClient::resume(&room, 2, Role::Editor, &resume_token, 0).await;
+        let received = mallory.received().await;
+        assert_eq!(received.first(), Some(&ServerMessage::Resync));
+        assert_eq!(welcome(&received).unwrap().user_id, 2);
+
+        let alice = TestClient::resume(&room, 1, Role::Editor, &resume_token, 0).await;
+        let received = alice.received().await;
+        assert!(!received.contains(&ServerMessage::Resync));
+        assert_eq!(welcome(&received).unwrap().user_id, 1);
+
+        // Guests get a new id for each connection, and take back their old one when resuming
+        let guest = TestClient::join(&room, FIRST_GUEST_ID, Role::Editor).await;
+        let resume_token = welcome(&guest.received().await)
+            .unwrap()
+            .resume_token
+            .clone();
+        guest.leave().await;
+        let guest =
+            TestClient::resume(&room, FIRST_GUEST_ID + 1, Role::Editor, &resume_token, 0).await;
+        assert_eq!(
+            welcome(&guest.received().await).unwrap().user_id,
+            FIRST_GUEST_ID
+        );
+    }
+
+    #[tokio::test]
+    async fn shutdown_closes_connections() {
+        let room = test_room();
+        let alice = TestClient::join(&room, 1, Role::Editor).await;
+        alice.received().await;
+
+        room.shutdown().await;
+        assert_eq!(alice.received().await, vec![ServerMessage::Restarting]);
+        assert_eq!(alice.close_code(), Some(CLOSE_SERVICE_RESTART));
+
+        // Connections joining afterwards are closed right away
+        let bob = TestClient::join(&room, 2, Role::Editor).await;
+        assert!(matches!(
+            bob.received().await.as_slice(),
+            [ServerMessage::Welcome(_), ServerMessage::Restarting]
+        ));
+        assert_eq!(bob.close_code(), Some(CLOSE_SERVICE_RESTART));
+    }
+}
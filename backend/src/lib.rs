@@ -1,171 +1,1042 @@
+use bytes::Bytes;
 use netsketch_shared::prelude::*;
 use std::collections::BTreeSet;
 use std::collections::HashMap;
 use std::collections::HashSet;
+use std::collections::VecDeque;
 use std::sync::atomic::{AtomicUsize, Ordering};
-use std::vec::Vec;
-use tokio::sync::{mpsc, RwLock};
-use warp::ws::Message as WsMessage;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::sync::{mpsc, oneshot};
 
-/// Our global unique user id counter.
-static NEXT_USERID: AtomicUsize = AtomicUsize::new(1);
+pub mod access;
+pub mod assets;
+pub mod config;
+#[cfg(test)]
+pub mod harness;
+pub mod metrics;
+pub mod outbound;
+pub mod ratelimit;
+pub mod replay;
+pub mod rooms;
+pub mod snapshots;
+pub mod subscribers;
+pub mod users;
+
+use access::RoomTokens;
+use assets::AssetStore;
+use metrics::RoomMetrics;
+use replay::ReplayBuffer;
+use snapshots::SavedRoom;
+use subscribers::TileSubscribers;
+
+/// Name of a room, used in its URL
+pub type RoomId = String;
+
+/// Id of a single websocket connection. A user may have several connections open
+pub type ConnectionId = usize;
+
+/// Time a disconnected connection can be resumed for
+pub const RESUME_TIMEOUT: Duration = Duration::from_secs(5 * 60);
+/// Number of recent paint strokes per room whose client ids are remembered to ignore resends
+pub const MAX_STROKE_ACKS: usize = 4096;
+/// Number of messages waiting for a room's task before senders have to wait
+pub const ROOM_INBOX_LEN: usize = 1024;
+/// Websocket close code for connections closed because the server is restarting
+pub const CLOSE_SERVICE_RESTART: u16 = 1012;
+
+/// Our global unique connection id counter.
+static NEXT_CONNECTION_ID: AtomicUsize = AtomicUsize::new(1);
+
+/// Destination of the messages a room sends to one connection, such as the outbound queue of a
+/// websocket
+pub trait MessageSink: Send + Sync {
+    /// Queues a message. `data` is `msg` compressed with `to_zbincode`, which is done once for
+    /// every connection the message is sent to
+    fn send(&self, msg: &ServerMessage, data: &Bytes);
+
+    /// Closes the connection with `code` after the messages already sent
+    fn close(&self, code: u16, reason: &'static str);
+}
 
 pub struct Connection {
     username: String,
-    //userid: UserId,
-    tx_conn: mpsc::UnboundedSender<Result<WsMessage, warp::Error>>,
+    user_id: UserId,
+    tx_conn: Arc<dyn MessageSink>,
     active_tile_offsets: HashSet<Offset>,
+    role: Role,
+    resume_token: String,
 }
 
+impl Connection {
+    /// Queues a message along with its already compressed form
+    fn send_data(&self, msg: &ServerMessage, data: &Bytes) {
+        self.tx_conn.send(msg, data);
+    }
+}
+
+/// Ids given to recently added paint strokes, by client id
 #[derive(Default)]
+struct StrokeAcks {
+    acks: HashMap<ClientStrokeId, (LayerId, PaintStrokeId)>,
+    order: VecDeque<ClientStrokeId>,
+}
+
+impl StrokeAcks {
+    fn get(&self, client_id: &ClientStrokeId) -> Option<(LayerId, PaintStrokeId)> {
+        self.acks.get(client_id).copied()
+    }
+
+    /// Remembers a stroke, forgetting the oldest if full
+    fn insert(&mut self, client_id: ClientStrokeId, layer_id: LayerId, id: PaintStrokeId) {
+        if self.order.len() >= MAX_STROKE_ACKS {
+            if let Some(oldest) = self.order.pop_front() {
+                self.acks.remove(&oldest);
+            }
+        }
+        self.acks.insert(client_id, (layer_id, id));
+        self.order.push_back(client_id);
+    }
+}
+
+/// Recently added elements, newest last, searched for a user's latest element to undo it. An
+/// element split by the eraser is remembered as its pieces, which are undone together
+#[derive(Default)]
+struct UndoHistory {
+    added: VecDeque<(UserId, LayerId, Vec<ElementId>)>,
+    /// Number of elements remembered
+    depth: usize,
+}
+
+impl UndoHistory {
+    /// Remembers an added element, forgetting the oldest past `depth`
+    fn push(&mut self, user_id: UserId, layer_id: LayerId, element_id: ElementId) {
+        if self.depth == 0 {
+            return;
+        }
+        if self.added.len() >= self.depth {
+            self.added.pop_front();
+        }
+        self.added.push_back((user_id, layer_id, vec![element_id]));
+    }
+
+    /// Remembers the pieces an element was split into in its place
+    fn replace(&mut self, layer_id: LayerId, element_id: ElementId, pieces: &[ElementId]) {
+        let entry = self
+            .added
+            .iter_mut()
+            .find(|x| x.1 == layer_id && x.2.contains(&element_id));
+        if let Some((_, _, element_ids)) = entry {
+            element_ids.retain(|x| *x != element_id);
+            element_ids.extend_from_slice(pieces);
+        }
+    }
+
+    /// Forgets and returns the latest element added by the user
+    fn pop(&mut self, user_id: UserId) -> Option<(LayerId, Vec<ElementId>)> {
+        let i = self.added.iter().rposition(|x| x.0 == user_id)?;
+        self.added.remove(i).map(|(_, layer_id, element_ids)| (layer_id, element_ids))
+    }
+}
+
+/// What's needed to pick up where a disconnected connection left off
+struct ResumeState {
+    conn_id: ConnectionId,
+    user_id: UserId,
+    username: String,
+    active_tile_offsets: HashSet<Offset>,
+    disconnected_at: Instant,
+}
+
+/// Flood fill computed away from the room's task, to be added to the room
+struct ComputedFill {
+    conn_id: ConnectionId,
+    user_id: UserId,
+    layer_id: LayerId,
+    color: Color,
+    spans: Vec<FillSpan>,
+}
+
+/// Work for a room's task, processed in the order it was sent
+enum RoomCommand {
+    Connect {
+        conn_id: ConnectionId,
+        tx_conn: Arc<dyn MessageSink>,
+        user_id: UserId,
+        username: String,
+        role: Role,
+        resume: Option<(String, SequenceNumber)>,
+    },
+    Receive(ConnectionId, ClientMessage),
+    Error(ConnectionId, ErrorCode, String),
+    Disconnect(ConnectionId),
+    /// Replies once every command sent before has been processed
+    Sync(oneshot::Sender<()>),
+    /// Closes every connection, replying once done
+    Shutdown(oneshot::Sender<()>),
+    /// Replies with the room's contents encoded by `snapshots::encode_room`
+    Encode(oneshot::Sender<Result<Option<Vec<u8>>, String>>),
+}
+
+/// Handle to a room. The room's state is owned by a task that processes commands from an inbox
+/// one at a time, so changes are applied in order without locking, and messages to connections
+/// are only queued, so a slow connection never holds up the rest of the room
 pub struct Room {
-    pub room_id: usize,
-    connections: RwLock<HashMap<UserId, Connection>>,
-    //chat_messages: RwLock<Vec<(Username, ChatMessage)>>,
-    canvas: RwLock<Vec<Layer>>,
+    pub room_id: RoomId,
+    /// Tokens granting access to the room, the viewer token is shared with editors
+    pub tokens: RoomTokens,
+    /// Limits client messages are checked against
+    pub limits: Limits,
+    pub metrics: Arc<RoomMetrics>,
+    /// Store used to check that placed images have been uploaded
+    asset_store: Option<Arc<AssetStore>>,
+    inbox: mpsc::Sender<RoomCommand>,
+}
+
+/// Everything in a room, owned by its task
+#[derive(Default)]
+struct RoomState {
+    room_id: RoomId,
+    connections: HashMap<ConnectionId, Connection>,
+    /// Connections by the tiles they're viewing
+    subscribers: TileSubscribers,
+    //chat_messages: Vec<(Username, ChatMessage)>,
+    canvas: Canvas,
+    /// Palettes and brush presets shared by everyone in the room
+    styles: StyleLibrary,
+    limits: Limits,
+    tokens: RoomTokens,
+    /// Recent messages, numbered, for connections that resume
+    replay: ReplayBuffer,
+    /// Recently closed connections, by resume token
+    resumable: HashMap<String, ResumeState>,
+    /// Recently added paint strokes, to acknowledge resends without adding them twice
+    stroke_acks: StrokeAcks,
+    /// Recently added elements, for undo
+    undo_history: UndoHistory,
+    /// Server is shutting down, connections are closed as soon as they join
+    shutting_down: bool,
+    /// Sends flood fills back to the room's task once computed
+    tx_fills: Option<mpsc::UnboundedSender<ComputedFill>>,
+    metrics: Arc<RoomMetrics>,
 }
 
-macro_rules! room_eprintln{
-    ($room:ident,$($arg:tt)*) => {
-        eprint!("Room ID: {}: ", $room.room_id);
-        eprintln!($($arg)*);
+macro_rules! room_log{
+    ($level:ident,$room:ident,$($arg:tt)*) => {
+        log::$level!("Room ID: {}: {}", $room.room_id, format_args!($($arg)*))
     }
 }
 
 impl Room {
+    /// Creates a room holding the saved contents and starts its task, which runs until every
+    /// handle is dropped
+    pub fn spawn(
+        room_id: RoomId,
+        tokens: RoomTokens,
+        asset_store: Option<Arc<AssetStore>>,
+        limits: Limits,
+        undo_depth: usize,
+        metrics: Arc<RoomMetrics>,
+        saved: SavedRoom,
+    ) -> Self {
+        let (inbox, rx_inbox) = mpsc::channel(ROOM_INBOX_LEN);
+        let state = RoomState {
+            room_id: room_id.clone(),
+            canvas: saved.canvas,
+            styles: saved.styles,
+            limits: limits.clone(),
+            tokens: tokens.clone(),
+            metrics: metrics.clone(),
+            undo_history: UndoHistory {
+                depth: undo_depth,
+                ..UndoHistory::default()
+            },
+            ..RoomState::default()
+        };
+        tokio::task::spawn(state.run(rx_inbox));
+        Room {
+            room_id,
+            tokens,
+            limits,
+            metrics,
+            asset_store,
+            inbox,
+        }
+    }
+
+    async fn send_command(&self, command: RoomCommand) {
+        if self.inbox.clone().send(command).await.is_err() {
+            room_log!(error, self, "Room task stopped");
+        }
+    }
+
+    /// Adds a connection to the room. If `resume` holds the resume token of a recently closed
+    /// connection and the last sequence number it saw, the connection takes over its identity and
+    /// viewport, and is sent the messages it missed. If they can't be replayed, it is told to
+    /// resync
     pub async fn connect(
         &self,
-        tx_conn: mpsc::UnboundedSender<Result<WsMessage, warp::Error>>,
+        tx_conn: Arc<dyn MessageSink>,
+        user_id: UserId,
+        username: String,
+        role: Role,
+        resume: Option<(String, SequenceNumber)>,
+    ) -> ConnectionId {
+        // Use a counter to assign a new unique ID for this connection.
+        let conn_id = NEXT_CONNECTION_ID.fetch_add(1, Ordering::Relaxed);
+        self.send_command(RoomCommand::Connect {
+            conn_id,
+            tx_conn,
+            user_id,
+            username,
+            role,
+            resume,
+        })
+        .await;
+        conn_id
+    }
+
+    /// Decodes a compressed message received from a connection, telling the connection if it
+    /// couldn't be decoded. Runs on the caller's task, so rooms don't spend time decoding
+    pub async fn decode_msg(&self, conn_id: ConnectionId, data: &[u8]) -> Option<ClientMessage> {
+        // Deserialize from compressed bincode, without inflating more than the limit
+        let dataresult: Result<ClientMessage, String> =
+            netsketch_shared::from_zbincode_bounded(data, self.limits.max_inflated_size);
+
+        match dataresult {
+            Ok(data) => Some(data),
+            Err(msg) => {
+                self.metrics.decode_errors.fetch_add(1, Ordering::Relaxed);
+                self.send_error(conn_id, ErrorCode::Malformed, msg).await;
+                None
+            }
+        }
+    }
+
+    pub async fn receive_msg(&self, conn_id: ConnectionId, data: ClientMessage) {
+        // Checked before the room's task sees it, as the image's header may have to be read
+        if let ClientMessage::PlaceImage(_, image) = &data {
+            if let Err((code, message)) = self.check_image(image).await {
+                self.send_error(conn_id, code, message).await;
+                return;
+            }
+        }
+        self.send_command(RoomCommand::Receive(conn_id, data)).await;
+    }
+
+    /// Checks that a placed image has been uploaded. Tiles are found from the declared size, so
+    /// it has to be the image's real size
+    async fn check_image(&self, image: &PlacedImage) -> Result<(), (ErrorCode, String)> {
+        let asset_store = match &self.asset_store {
+            Some(asset_store) => asset_store,
+            None => return Ok(()),
+        };
+        match asset_store.image_size(&image.asset).await {
+            Some(size) if size == (image.width, image.height) => Ok(()),
+            Some((width, height)) => {
+                let message = format!(
+                    "Asset {} is {}x{}, not {}x{}",
+                    image.asset, width, height, image.width, image.height
+                );
+                Err((ErrorCode::Invalid, message))
+            }
+            None => {
+                let message = format!("Nonexistant asset {}", image.asset);
+                Err((ErrorCode::NotFound, message))
+            }
+        }
+    }
+
+    /// Tells a connection that a message it sent was rejected
+    pub async fn send_error(&self, conn_id: ConnectionId, code: ErrorCode, message: String) {
+        self.send_command(RoomCommand::Error(conn_id, code, message))
+            .await;
+    }
+
+    pub async fn disconnect(&self, conn_id: ConnectionId) {
+        self.send_command(RoomCommand::Disconnect(conn_id)).await;
+    }
+
+    /// Waits until the room has processed everything sent to it before
+    pub async fn sync(&self) {
+        let (tx, rx) = oneshot::channel();
+        self.send_command(RoomCommand::Sync(tx)).await;
+        let _ = rx.await;
+    }
+
+    /// Tells every connection that the server is restarting and closes it, returning once the
+    /// closes are queued. Connections joining later are closed right away
+    pub async fn shutdown(&self) {
+        let (tx, rx) = oneshot::channel();
+        self.send_command(RoomCommand::Shutdown(tx)).await;
+        let _ = rx.await;
+    }
+
+    /// Encodes the room's contents for `RoomStore::save`, after everything sent to it before is
+    /// applied. Returns None if the room is empty
+    pub async fn encode(&self) -> Result<Option<Vec<u8>>, String> {
+        let (tx, rx) = oneshot::channel();
+        self.send_command(RoomCommand::Encode(tx)).await;
+        rx.await.map_err(|_| "Room task stopped".to_string())?
+    }
+}
+
+impl RoomState {
+    /// Processes commands and computed fills until every command sender is dropped
+    async fn run(mut self, mut inbox: mpsc::Receiver<RoomCommand>) {
+        let (tx_fills, mut rx_fills) = mpsc::unbounded_channel();
+        self.tx_fills = Some(tx_fills);
+        loop {
+            tokio::select! {
+                command = inbox.recv() => match command {
+                    Some(command) => self.run_command(command),
+                    None => break,
+                },
+                Some(fill) = rx_fills.recv() => self.add_fill(fill),
+            }
+        }
+    }
+
+    fn run_command(&mut self, command: RoomCommand) {
+        match command {
+            RoomCommand::Connect {
+                conn_id,
+                tx_conn,
+                user_id,
+                username,
+                role,
+                resume,
+            } => self.connect(conn_id, tx_conn, user_id, username, role, resume),
+            RoomCommand::Receive(conn_id, data) => self.receive_msg(conn_id, data),
+            RoomCommand::Error(conn_id, code, message) => self.send_error(conn_id, code, message),
+            RoomCommand::Disconnect(conn_id) => self.disconnect(conn_id),
+            RoomCommand::Sync(tx) => {
+                let _ = tx.send(());
+            }
+            RoomCommand::Shutdown(tx) => {
+                self.shutdown();
+                let _ = tx.send(());
+            }
+            RoomCommand::Encode(tx) => {
+                let _ = tx.send(snapshots::encode_room(&self.canvas, &self.styles));
+            }
+        }
+    }
+
+    fn connect(
+        &mut self,
+        conn_id: ConnectionId,
+        tx_conn: Arc<dyn MessageSink>,
+        user_id: UserId,
         username: String,
-    ) -> UserId {
-        // Use a counter to assign a new unique ID for this user.
-        let userid = NEXT_USERID.fetch_add(1, Ordering::Relaxed);
+        role: Role,
+        resume: Option<(String, SequenceNumber)>,
+    ) {
+        let resume_state = match &resume {
+            Some((resume_token, _)) => self.take_resume_state(resume_token, user_id),
+            None => None,
+        };
+        // Guests get a new id on each connection, so a resuming guest takes back its old one
+        let (user_id, username) = match &resume_state {
+            Some(resume_state) => (resume_state.user_id, resume_state.username.clone()),
+            None => (user_id, username),
+        };
 
-        let connection = Connection {
+        let mut connection = Connection {
             username,
+            user_id,
             tx_conn,
             active_tile_offsets: HashSet::default(),
+            role,
+            resume_token: format!("{:032x}", rand::random::<u128>()),
+        };
+
+        let resuming = resume.is_some();
+        let missed = match (resume, resume_state) {
+            (Some((_, seq)), Some(resume_state)) => {
+                self.replay.since(seq).map(|x| (x, resume_state))
+            }
+            _ => None,
+        };
+        if resuming && missed.is_none() {
+            self.send_msg(&connection, &ServerMessage::Resync);
+        }
+
+        // Tell the user who they are and what they may do
+        let viewer_token = match role {
+            Role::Editor => Some(self.tokens.viewer.clone()),
+            Role::Viewer => None,
+        };
+        let welcome = Welcome {
+            user_id,
+            role,
+            viewer_token,
+            resume_token: connection.resume_token.clone(),
+            seq: self.replay.last_seq(),
         };
+        self.send_msg(&connection, &ServerMessage::Welcome(welcome));
+
+        match missed {
+            Some((events, resume_state)) => {
+                // Catch up on what would have been sent to the old connection
+                connection.active_tile_offsets = resume_state.active_tile_offsets;
+                for event in events {
+                    if event.is_for(resume_state.conn_id, &connection.active_tile_offsets) {
+                        connection.send_data(&event.msg, &event.data);
+                    }
+                }
+            }
+            None => {
+                // Send the room's palettes and brush presets
+                for palette in self.styles.palettes() {
+                    self.send_msg(&connection, &ServerMessage::Palette(palette.clone()));
+                }
+                for brush_preset in self.styles.brush_presets() {
+                    self.send_msg(
+                        &connection,
+                        &ServerMessage::BrushPreset(brush_preset.clone()),
+                    );
+                }
+            }
+        }
 
         // Save the sender in our list of connected users.
-        self.connections.write().await.insert(userid, connection);
+        self.subscribers
+            .update(conn_id, &HashSet::new(), &connection.active_tile_offsets);
+        if self.shutting_down {
+            self.close_for_restart(&connection);
+        }
+        self.connections.insert(conn_id, connection);
+        self.metrics.connections.fetch_add(1, Ordering::Relaxed);
+    }
 
-        userid
+    /// Removes and returns the state of a closed connection, if it hasn't expired and belonged
+    /// to the same user. Any guest may resume a guest's connection, since guests are only known
+    /// by their resume token
+    fn take_resume_state(&mut self, resume_token: &str, user_id: UserId) -> Option<ResumeState> {
+        self.resumable
+            .retain(|_, x| x.disconnected_at.elapsed() < RESUME_TIMEOUT);
+        let resume_state = self.resumable.get(resume_token)?;
+        let same_user = resume_state.user_id == user_id
+            || (users::is_guest(resume_state.user_id) && users::is_guest(user_id));
+        if !same_user {
+            return None;
+        }
+        self.resumable.remove(resume_token)
     }
 
-    pub async fn receive_msg(&self, user_id: UserId, msg: WsMessage) {
-        if msg.is_binary() {
-            // Deserialize from compressed bincode
-            let dataresult: Result<ClientMessage, String> =
-                netsketch_shared::from_zbincode(msg.as_bytes());
+    fn receive_msg(&mut self, conn_id: ConnectionId, data: ClientMessage) {
+        let (user_id, role) = match self.connections.get(&conn_id) {
+            Some(conn) => (conn.user_id, conn.role),
+            None => return,
+        };
+
+        // Viewers may only look around
+        if data.is_mutating() && role != Role::Editor {
+            self.metrics.reject(&data);
+            self.send_error(
+                conn_id,
+                ErrorCode::Forbidden,
+                "Viewers can't change the room".to_string(),
+            );
+            return;
+        }
 
-            let data = match dataresult {
-                Ok(data) => data,
-                Err(msg) => {
-                    room_eprintln!(self, "Deserialization error: {}", msg);
-                    return;
-                }
-            };
+        if let Err(err) = self.limits.validate(&data) {
+            self.metrics.reject(&data);
+            self.send_error(conn_id, err.code(), err.to_string());
+            return;
+        }
 
-            match data {
-                // Paintstroke received
-                ClientMessage::PaintStroke(layer_id, mut paint_stroke) => {
-                    // Bounds check on layer IDs
-                    if layer_id < netsketch_shared::MAX_LAYERS {
-                        let mut canvas = self.canvas.write().await;
-
-                        // If nonexistant layer, create it and everything in between
-                        let layer = match canvas.get_mut(layer_id as usize) {
-                            Some(layer) => layer,
-                            None => {
-                                canvas.resize(layer_id as usize + 1, Layer::default());
-                                &mut canvas[layer_id as usize]
-                            }
-                        };
-
-                        paint_stroke.user_id = user_id;
-
-                        // Add stroke to paint stack
-                        let (paint_stroke, tile_offsets) =
-                            layer.add_paint_stroke(paint_stroke);
-
-                        // Send paint stroke to everyone connected viewing the visible tiles
-                        let msg = ServerMessage::PaintStroke(layer_id, (*paint_stroke).clone());
-                        let zbincode_msg = netsketch_shared::to_zbincode(&msg);
-
-                        match zbincode_msg {
-                            Ok(msg) => {
-                                for (their_user_id, conn) in self.connections.read().await.iter() {
-                                    if user_id != *their_user_id
-                                        && tile_offsets
-                                            .intersection(&conn.active_tile_offsets)
-                                            .count()
-                                            != 0
-                                    {
-                                        if let Err(err) =
-                                            conn.tx_conn.send(Ok(WsMessage::binary(msg.clone())))
-                                        {
-                                            room_eprintln!(self, "Send error: {}", err.to_string());
-                                        }
-                                    }
-                                }
-                            }
-                            Err(err) => {
-                                room_eprintln!(self, "ZBincode error: {}", err.to_string());
-                            }
-                        };
+        match data {
+            // Paintstroke received
+            ClientMessage::PaintStroke(layer_id, client_id, paint_stroke) => {
+                self.add_paint_stroke(conn_id, user_id, layer_id, client_id, paint_stroke);
+            }
+            ClientMessage::Shape(layer_id, shape) => {
+                self.add_element(conn_id, user_id, layer_id, Element::Shape(shape));
+            }
+            ClientMessage::TextLabel(layer_id, text_label) => {
+                self.add_element(conn_id, user_id, layer_id, Element::TextLabel(text_label));
+            }
+            ClientMessage::EditTextLabel(layer_id, text_label) => {
+                self.edit_text_label(conn_id, user_id, layer_id, text_label);
+            }
+            // Checked by `Room::receive_msg`
+            ClientMessage::PlaceImage(layer_id, image) => {
+                self.add_element(conn_id, user_id, layer_id, Element::Image(image));
+            }
+            ClientMessage::FloodFill(layer_id, flood_fill) => {
+                self.start_fill(conn_id, user_id, layer_id, flood_fill);
+            }
+            ClientMessage::StrokeErase(layer_id, stroke_erase) => {
+                let erased_strokes = match self.canvas.layer_mut(layer_id) {
+                    Some(layer) => layer.erase_strokes(&stroke_erase),
+                    None => {
+                        let message = format!("Nonexistant layer {}", layer_id);
+                        self.send_error(conn_id, ErrorCode::NotFound, message);
+                        return;
+                    }
+                };
+                for erased in erased_strokes {
+                    let pieces: Vec<ElementId> = erased.pieces.iter().map(|x| x.id()).collect();
+                    self.undo_history.replace(layer_id, erased.id, &pieces);
+                    let msg = if erased.pieces.is_empty() {
+                        ServerMessage::RemoveElement(layer_id, erased.id)
                     } else {
-                        // Bail out on failed bounds check
-                        room_eprintln!(self, "Layer({}) > MAX_LAYERS", layer_id);
+                        let pieces = erased
+                            .pieces
+                            .iter()
+                            .filter_map(|x| match &**x {
+                                Element::PaintStroke(paint_stroke) => Some(paint_stroke.clone()),
+                                _ => None,
+                            })
+                            .collect();
+                        ServerMessage::ReplaceStroke(layer_id, erased.id, pieces)
+                    };
+                    self.send_to_viewers(&msg, &erased.tile_offsets, None);
+                }
+            }
+            ClientMessage::AddPalette(palette) => match self.styles.add_palette(palette).cloned() {
+                Some(palette) => self.broadcast_msg(&ServerMessage::Palette(palette)),
+                None => {
+                    let message = "Invalid palette or palette count > MAX_PALETTES";
+                    self.send_error(conn_id, ErrorCode::LimitReached, message.to_string());
+                }
+            },
+            ClientMessage::EditPalette(palette) => {
+                let palette_id = palette.id;
+                match self.styles.edit_palette(palette).cloned() {
+                    Some(palette) => self.broadcast_msg(&ServerMessage::Palette(palette)),
+                    None => {
+                        let message = format!("Invalid or nonexistant palette {}", palette_id);
+                        self.send_error(conn_id, ErrorCode::NotFound, message);
                     }
                 }
-                ClientMessage::SetViewPort(upper_left, lower_right) => {
-                    if let Some(conn) = self.connections.write().await.get_mut(&user_id) {
-                        conn.active_tile_offsets =
-                            netsketch_shared::tile_ops::compute_bounded_tile_offsets(
-                                &upper_left,
-                                &lower_right,
-                            );
-
-                        for (layer_id, layer) in self.canvas.read().await.iter().enumerate() {
-                            let mut visible_strokes = BTreeSet::new();
-                            for tile_offset in &conn.active_tile_offsets {
-                                visible_strokes
-                                    .append(&mut layer.get_tile_paintstrokes(&tile_offset));
-                            }
+            }
+            ClientMessage::RemovePalette(palette_id) => {
+                let removed = self.styles.remove_palette(palette_id);
+                if removed {
+                    self.broadcast_msg(&ServerMessage::RemovePalette(palette_id));
+                }
+            }
+            ClientMessage::AddBrushPreset(brush_preset) => {
+                match self.styles.add_brush_preset(brush_preset).cloned() {
+                    Some(brush_preset) => {
+                        self.broadcast_msg(&ServerMessage::BrushPreset(brush_preset))
+                    }
+                    None => {
+                        let message = "Invalid brush preset or preset count > MAX_BRUSH_PRESETS";
+                        self.send_error(conn_id, ErrorCode::LimitReached, message.to_string());
+                    }
+                }
+            }
+            ClientMessage::EditBrushPreset(brush_preset) => {
+                let brush_preset_id = brush_preset.id;
+                match self.styles.edit_brush_preset(brush_preset).cloned() {
+                    Some(brush_preset) => {
+                        self.broadcast_msg(&ServerMessage::BrushPreset(brush_preset))
+                    }
+                    None => {
+                        let message =
+                            format!("Invalid or nonexistant brush preset {}", brush_preset_id);
+                        self.send_error(conn_id, ErrorCode::NotFound, message);
+                    }
+                }
+            }
+            ClientMessage::RemoveBrushPreset(brush_preset_id) => {
+                let removed = self.styles.remove_brush_preset(brush_preset_id);
+                if removed {
+                    self.broadcast_msg(&ServerMessage::RemoveBrushPreset(brush_preset_id));
+                }
+            }
+            ClientMessage::SetViewPort(upper_left, lower_right) => {
+                let conn = match self.connections.get_mut(&conn_id) {
+                    Some(conn) => conn,
+                    None => return,
+                };
+                let active_tile_offsets = netsketch_shared::tile_ops::compute_bounded_tile_offsets(
+                    &upper_left,
+                    &lower_right,
+                );
+                self.subscribers
+                    .update(conn_id, &conn.active_tile_offsets, &active_tile_offsets);
+                conn.active_tile_offsets = active_tile_offsets;
+                let conn = &self.connections[&conn_id];
+
+                // Send layer tree first so the client knows how to composite
+                let mut sent_bytes =
+                    self.send_msg(conn, &ServerMessage::LayerTree(self.canvas.tree().clone()));
+
+                for (layer_id, layer) in self.canvas.layers() {
+                    let mut visible_elements = BTreeSet::new();
+                    for tile_offset in &conn.active_tile_offsets {
+                        visible_elements.append(&mut layer.get_tile_elements(tile_offset));
+                    }
+
+                    for element in &visible_elements {
+                        sent_bytes += self.send_msg(conn, &element.to_server_message(layer_id));
+                    }
+                }
+                self.metrics.viewport_bytes.observe(sent_bytes);
+            }
+            ClientMessage::CreateGroup => {
+                if self.canvas.tree_mut().create_group().is_some() {
+                    self.broadcast_msg(&ServerMessage::LayerTree(self.canvas.tree().clone()));
+                } else {
+                    let message = "Group count > MAX_GROUPS".to_string();
+                    self.send_error(conn_id, ErrorCode::LimitReached, message);
+                }
+            }
+            ClientMessage::MoveNode(node_id, parent, index) => {
+                if self.canvas.tree_mut().move_node(node_id, parent, index) {
+                    self.broadcast_msg(&ServerMessage::LayerTree(self.canvas.tree().clone()));
+                } else {
+                    let message = format!("Invalid move of {:?} to {:?}", node_id, parent);
+                    self.send_error(conn_id, ErrorCode::NotFound, message);
+                }
+            }
+            ClientMessage::SetNodeProperties(node_id, properties) => {
+                if self.canvas.tree_mut().set_properties(node_id, properties) {
+                    self.broadcast_msg(&ServerMessage::LayerTree(self.canvas.tree().clone()));
+                } else {
+                    let message = format!("Nonexistant node {:?}", node_id);
+                    self.send_error(conn_id, ErrorCode::NotFound, message);
+                }
+            }
+            ClientMessage::SetLayerClip(layer_id, clip) => {
+                if self.canvas.tree_mut().set_clip(layer_id, clip) {
+                    self.broadcast_msg(&ServerMessage::LayerTree(self.canvas.tree().clone()));
+                } else {
+                    let message = format!("Nonexistant layer {}", layer_id);
+                    self.send_error(conn_id, ErrorCode::NotFound, message);
+                }
+            }
+            ClientMessage::UndoMessage => self.undo(user_id),
+            ClientMessage::ChatMessage(_) | ClientMessage::FetchTile(..) => (),
+        }
+    }
+
+    /// Removes the latest element the user added that's still on the canvas, searching the
+    /// elements recently added to the room
+    fn undo(&mut self, user_id: UserId) {
+        while let Some((layer_id, element_ids)) = self.undo_history.pop(user_id) {
+            let mut undone = false;
+            for element_id in element_ids {
+                // Elements may have been erased since
+                let removed = self
+                    .canvas
+                    .layer_mut(layer_id)
+                    .and_then(|layer| layer.remove_element(element_id));
+                if let Some((_, tile_offsets)) = removed {
+                    let msg = ServerMessage::RemoveElement(layer_id, element_id);
+                    self.send_to_viewers(&msg, &tile_offsets, None);
+                    undone = true;
+                }
+            }
+            if undone {
+                return;
+            }
+        }
+    }
+
+    /// Computes a flood fill from the current layer contents, which are empty if the layer
+    /// doesn't exist yet. Rasterizing is slow, so it's done on a blocking thread from a snapshot of
+    /// the elements, and the fill is added once it's sent back
+    fn start_fill(
+        &mut self,
+        conn_id: ConnectionId,
+        user_id: UserId,
+        layer_id: LayerId,
+        flood_fill: FloodFill,
+    ) {
+        let elements = match self.canvas.layer(layer_id) {
+            Some(layer) => netsketch_shared::fill::fill_elements(layer, flood_fill.seed),
+            None => Vec::new(),
+        };
+        if elements.len() > self.limits.max_fill_elements {
+            let message = format!(
+                "Fill would draw {} elements, maximum is {}",
+                elements.len(),
+                self.limits.max_fill_elements
+            );
+            self.send_error(conn_id, ErrorCode::LimitReached, message);
+            return;
+        }
+        let tx_fills = match &self.tx_fills {
+            Some(tx_fills) => tx_fills.clone(),
+            None => return,
+        };
+        tokio::task::spawn_blocking(move || {
+            let spans = netsketch_shared::fill::compute_fill(&elements, &flood_fill);
+            // Room may have stopped meanwhile
+            let _ = tx_fills.send(ComputedFill {
+                conn_id,
+                user_id,
+                layer_id,
+                color: flood_fill.color,
+                spans,
+            });
+        });
+    }
+
+    /// Adds a computed flood fill, unless it's empty or too large
+    fn add_fill(&mut self, fill: ComputedFill) {
+        if fill.spans.is_empty() {
+            return;
+        }
+        if fill.spans.len() > self.limits.max_fill_spans {
+            let message = format!(
+                "Fill has {} spans, maximum is {}",
+                fill.spans.len(),
+                self.limits.max_fill_spans
+            );
+            self.send_error(fill.conn_id, ErrorCode::LimitReached, message);
+            return;
+        }
+        let element = Element::Fill(Fill {
+            id: 0,
+            user_id: fill.user_id,
+            color: fill.color,
+            spans: fill.spans,
+        });
+        self.add_element(fill.conn_id, fill.user_id, fill.layer_id, element);
+    }
+
+    /// Adds an element to a layer and sends it to everyone else viewing the tiles it touches
+    fn add_element(
+        &mut self,
+        conn_id: ConnectionId,
+        user_id: UserId,
+        layer_id: LayerId,
+        mut element: Element,
+    ) -> Option<ElementId> {
+        // Bounds check on layer IDs, creating the layer if nonexistant
+        let tree_changed = match self.canvas.ensure_layer(layer_id) {
+            Some(tree_changed) => tree_changed,
+            None => {
+                // Bail out on failed bounds check
+                let message = format!("Layer({}) > MAX_LAYERS", layer_id);
+                self.send_error(conn_id, ErrorCode::LimitReached, message);
+                return None;
+            }
+        };
+        if tree_changed {
+            self.broadcast_msg(&ServerMessage::LayerTree(self.canvas.tree().clone()));
+        }
+        let layer = self
+            .canvas
+            .layer_mut(layer_id)
+            .expect("layer created by ensure_layer");
+
+        element.set_user_id(user_id);
+
+        // Add element to paint stack
+        let (element, tile_offsets) = layer.add_element(element);
+        self.undo_history.push(user_id, layer_id, element.id());
+
+        // Strokes and shapes are drawn locally by the author while being created. Other elements
+        // are echoed back, so the author can draw them and learn the id needed to edit them
+        let exclude_conn_id = match *element {
+            Element::PaintStroke(_) | Element::Shape(_) => Some(conn_id),
+            _ => None,
+        };
+
+        // Send element to everyone connected viewing the visible tiles
+        self.send_to_viewers(
+            &element.to_server_message(layer_id),
+            &tile_offsets,
+            exclude_conn_id,
+        );
+
+        Some(element.id())
+    }
+
+    /// Adds a paint stroke unless one with the same client id was recently added, then tells the
+    /// sender the stroke's id
+    fn add_paint_stroke(
+        &mut self,
+        conn_id: ConnectionId,
+        user_id: UserId,
+        layer_id: LayerId,
+        client_id: ClientStrokeId,
+        paint_stroke: PaintStroke,
+    ) {
+        let (layer_id, id) = match self.stroke_acks.get(&client_id) {
+            Some(ack) => ack,
+            None => {
+                let id = match self.add_element(
+                    conn_id,
+                    user_id,
+                    layer_id,
+                    Element::PaintStroke(paint_stroke),
+                ) {
+                    Some(id) => id,
+                    None => {
+                        self.metrics.strokes_rejected.fetch_add(1, Ordering::Relaxed);
+                        return;
+                    }
+                };
+                self.metrics.strokes_accepted.fetch_add(1, Ordering::Relaxed);
+                self.stroke_acks.insert(client_id, layer_id, id);
+                (layer_id, id)
+            }
+        };
+
+        if let Some(conn) = self.connections.get(&conn_id) {
+            let ack = ServerMessage::StrokeAck {
+                client_id,
+                id,
+                layer: layer_id,
+            };
+            self.send_msg(conn, &ack);
+        }
+    }
+
+    /// Replaces a text label, if it exists and was placed by the same user
+    fn edit_text_label(
+        &mut self,
+        conn_id: ConnectionId,
+        user_id: UserId,
+        layer_id: LayerId,
+        mut text_label: TextLabel,
+    ) {
+        let layer = match self.canvas.layer_mut(layer_id) {
+            Some(layer) => layer,
+            None => {
+                let message = format!("Nonexistant layer {}", layer_id);
+                self.send_error(conn_id, ErrorCode::NotFound, message);
+                return;
+            }
+        };
 
-                            for stroke in &visible_strokes {
-                                // Send paint stroke to everyone connected viewing the visible tiles
-                                let msg = ServerMessage::PaintStroke(layer_id as u8, (**stroke).clone());
-                                let zbincode_msg = netsketch_shared::to_zbincode(&msg);
-
-                                match zbincode_msg {
-                                    Ok(msg) => {
-                                        if let Err(err) =
-                                            conn.tx_conn.send(Ok(WsMessage::binary(msg)))
-                                        {
-                                            room_eprintln!(self, "Send error: {}", err.to_string());
-                                        }
-                                    }
-                                    Err(err) => {
-                                        room_eprintln!(self, "ZBincode error: {}", err.to_string());
-                                    }
-                                };
+        match layer.get_element(text_label.id).map(|x| &**x) {
+            Some(Element::TextLabel(existing)) if existing.user_id == user_id => (),
+            _ => {
+                let message = format!("User {} can't edit text label {}", user_id, text_label.id);
+                self.send_error(conn_id, ErrorCode::Forbidden, message);
+                return;
+            }
+        }
+
+        text_label.user_id = user_id;
+        if let Some((element, tile_offsets)) = layer.replace_element(Element::TextLabel(text_label))
+        {
+            self.send_to_viewers(&element.to_server_message(layer_id), &tile_offsets, None);
+        }
+    }
+
+    /// Sends a message to every connection viewing any of the tile offsets, except for
+    /// `exclude_conn_id`
+    fn send_to_viewers(
+        &mut self,
+        msg: &ServerMessage,
+        tile_offsets: &HashSet<Offset>,
+        exclude_conn_id: Option<ConnectionId>,
+    ) {
+        self.send_sequenced(msg, Some(tile_offsets), exclude_conn_id);
+    }
+
+    /// Numbers a message and sends it to the connections it concerns, keeping it for replay
+    fn send_sequenced(
+        &mut self,
+        msg: &ServerMessage,
+        tile_offsets: Option<&HashSet<Offset>>,
+        exclude_conn_id: Option<ConnectionId>,
+    ) {
+        match self.replay.push(msg, tile_offsets, exclude_conn_id) {
+            Ok(event) => {
+                // Only look at the connections viewing the message's tiles. The message is
+                // compressed once for all of them, though each websocket still copies it
+                let send = |conn_id: &ConnectionId, conn: &Connection| {
+                    if Some(*conn_id) != exclude_conn_id {
+                        conn.send_data(&event.msg, &event.data);
+                    }
+                };
+                match tile_offsets {
+                    Some(tile_offsets) => {
+                        for conn_id in self.subscribers.viewers(tile_offsets) {
+                            if let Some(conn) = self.connections.get(&conn_id) {
+                                send(&conn_id, conn);
                             }
                         }
                     }
+                    None => {
+                        for (conn_id, conn) in self.connections.iter() {
+                            send(conn_id, conn);
+                        }
+                    }
                 }
-                _ => (),
             }
+            Err(err) => {
+                self.metrics.encode_errors.fetch_add(1, Ordering::Relaxed);
+                room_log!(error, self, "ZBincode error: {}", err);
+            }
+        };
+    }
+
+    /// Sends a message to a single connection, returning its compressed size
+    fn send_msg(&self, conn: &Connection, msg: &ServerMessage) -> usize {
+        match netsketch_shared::to_zbincode(msg) {
+            Ok(data) => {
+                let len = data.len();
+                conn.send_data(msg, &data.into());
+                len
+            }
+            Err(err) => {
+                self.metrics.encode_errors.fetch_add(1, Ordering::Relaxed);
+                room_log!(error, self, "ZBincode error: {}", err);
+                0
+            }
+        }
+    }
+
+    /// Tells a connection that a message it sent was rejected
+    fn send_error(&self, conn_id: ConnectionId, code: ErrorCode, message: String) {
+        if let Some(conn) = self.connections.get(&conn_id) {
+            self.send_msg(conn, &ServerMessage::Error { code, message });
+        }
+    }
+
+    /// Sends a message to every connection in the room
+    fn broadcast_msg(&mut self, msg: &ServerMessage) {
+        self.send_sequenced(msg, None, None);
+    }
+
+    fn shutdown(&mut self) {
+        room_log!(info, self, "Closing {} connections", self.connections.len());
+        self.shutting_down = true;
+        for conn in self.connections.values() {
+            self.close_for_restart(conn);
         }
     }
 
-    pub async fn disconnect(&self, userid: UserId) {
+    fn close_for_restart(&self, conn: &Connection) {
+        self.send_msg(conn, &ServerMessage::Restarting);
+        conn.tx_conn.close(CLOSE_SERVICE_RESTART, "Server restarting");
+    }
+
+    fn disconnect(&mut self, conn_id: ConnectionId) {
         // Stream closed up, so remove from the user list
-        let mut conn_map = self.connections.write().await;
-        if let Some(conn) = conn_map.get(&userid) {
-            eprintln!("good bye user: {} {}", userid, conn.username);
-            conn_map.remove(&userid);
+        if let Some(conn) = self.connections.remove(&conn_id) {
+            self.metrics.connections.fetch_sub(1, Ordering::Relaxed);
+            room_log!(info, self, "good bye user: {} {}", conn.user_id, conn.username);
+            self.subscribers
+                .unsubscribe(conn_id, &conn.active_tile_offsets);
+
+            // Keep what's needed to resume the connection for a while
+            self.resumable
+                .retain(|_, x| x.disconnected_at.elapsed() < RESUME_TIMEOUT);
+            self.resumable.insert(
+                conn.resume_token,
+                ResumeState {
+                    conn_id,
+                    user_id: conn.user_id,
+                    username: conn.username,
+                    active_tile_offsets: conn.active_tile_offsets,
+                    disconnected_at: Instant::now(),
+                },
+            );
         }
     }
 }
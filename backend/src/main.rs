@@ -1,102 +1,480 @@
 #![deny(warnings)]
+use std::collections::HashMap;
+use std::convert::Infallible;
 use std::path::PathBuf;
 use std::sync::Arc;
+use std::sync::atomic::Ordering;
 use std::net::SocketAddr;
-use std::str::FromStr;
+use std::time::Duration;
 use std::vec::Vec;
-use futures::{FutureExt, StreamExt};
-use tokio::sync::mpsc;
+use futures::{FutureExt, SinkExt, StreamExt};
+use structopt::StructOpt;
 use warp::Filter;
+use warp::http::StatusCode;
 use warp::ws::WebSocket;
 
+use netsketch_shared::{ErrorCode, Role, SequenceNumber, UserId, Username};
 use netsketch_backend::*;
+use netsketch_backend::access::ServerKey;
+use netsketch_backend::assets::{AssetError, AssetStore, MAX_ASSET_SIZE};
+use netsketch_backend::config::Config;
+use netsketch_backend::outbound::{OutboundQueue, QueueLimits};
+use netsketch_backend::ratelimit::{ConnectionRateLimiter, IpRateLimiter, IpRequestLimiter, RateVerdict};
+use netsketch_backend::rooms::RoomRegistry;
+use netsketch_backend::snapshots::RoomStore;
+use netsketch_backend::users::{self, UserError, UserStore};
 
 
 
-const NUM_ROOMS: usize = 100;
+/// Websocket close code for connections closed for misbehaving
+const POLICY_VIOLATION: u16 = 1008;
+/// Time connections get to receive their last messages when shutting down
+const SHUTDOWN_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Collaborative painting server. Settings given as options override the config file
+#[derive(StructOpt)]
+struct Args {
+    /// TOML config file
+    #[structopt(short, long, parse(from_os_str))]
+    config: Option<PathBuf>,
+    /// Address to listen on, may be given several times
+    #[structopt(short, long)]
+    listen: Vec<SocketAddr>,
+    /// Directory the frontend is served from
+    #[structopt(parse(from_os_str))]
+    static_dir: Option<PathBuf>,
+    /// Directory uploaded images are stored in
+    #[structopt(long, parse(from_os_str))]
+    asset_dir: Option<PathBuf>,
+    /// File holding the key room access and session tokens are derived from
+    #[structopt(long, parse(from_os_str))]
+    key_path: Option<PathBuf>,
+    /// File registered users are stored in
+    #[structopt(long, parse(from_os_str))]
+    users_path: Option<PathBuf>,
+    /// Seconds an empty room stays loaded
+    #[structopt(long)]
+    room_idle_secs: Option<u64>,
+    /// Address metrics are served on
+    #[structopt(long)]
+    metrics_listen: Option<SocketAddr>,
+    /// Log filter in RUST_LOG syntax
+    #[structopt(long)]
+    log: Option<String>,
+}
+
+impl Args {
+    /// Reads the config file, if any, and applies the options on top of it
+    fn config(self) -> Result<Config, String> {
+        let mut config = match &self.config {
+            Some(path) => Config::load(path).map_err(|x| x.to_string())?,
+            None => Config::default(),
+        };
+        if !self.listen.is_empty() {
+            config.listen = self.listen;
+        }
+        if let Some(static_dir) = self.static_dir {
+            config.static_dir = static_dir;
+        }
+        if let Some(asset_dir) = self.asset_dir {
+            config.storage.asset_dir = asset_dir;
+        }
+        if let Some(key_path) = self.key_path {
+            config.storage.key_path = key_path;
+        }
+        if let Some(users_path) = self.users_path {
+            config.storage.users_path = users_path;
+        }
+        if let Some(room_idle_secs) = self.room_idle_secs {
+            config.rooms.idle_timeout = Duration::from_secs(room_idle_secs);
+        }
+        if let Some(metrics_listen) = self.metrics_listen {
+            config.metrics.listen = Some(metrics_listen);
+        }
+        if let Some(log) = self.log {
+            config.log.filter = Some(log);
+        }
+        Ok(config)
+    }
+}
 
 #[tokio::main]
 async fn main() {
-    pretty_env_logger::init();
-    let mut rooms : Vec<Arc<Room>> = Vec::new();
+    let config = match Args::from_args().config() {
+        Ok(config) => config,
+        Err(err) => {
+            eprintln!("{}", err);
+            std::process::exit(2);
+        }
+    };
 
-    for i in 0..NUM_ROOMS{
-        let mut room = Room::default();
-        room.room_id = i;
-        rooms.push(Arc::new(room));
-    }
+    let mut logger = pretty_env_logger::formatted_builder();
+    match std::env::var("RUST_LOG").ok().or_else(|| config.log.filter.clone()) {
+        Some(filter) => logger.parse_filters(&filter),
+        None => logger.filter_level(log::LevelFilter::Info),
+    };
+    logger.init();
+
+    let asset_dir = config.storage.asset_dir.clone();
+    let asset_store = Arc::new(AssetStore::new(&asset_dir, config.storage.max_asset_bytes).expect("Unable to open asset store"));
+    let server_key = Arc::new(ServerKey::load_or_create(&config.storage.key_path).expect("Unable to load server key"));
+    let user_store = Arc::new(UserStore::open(&config.storage.users_path).expect("Unable to open user store"));
+    let room_store = RoomStore::open(&config.storage.rooms_dir).expect("Unable to open room store");
 
-    let rooms = Arc::new(rooms);
+    let max_message_size = config.limits.max_message_size;
+
+    let rooms = Arc::new(RoomRegistry::new(
+        config.rooms.clone(),
+        room_store,
+        Some(asset_store.clone()),
+        server_key.clone(),
+        config.limits.clone(),
+    ));
+    // Each connection is rate limited, as are all connections from one address together if
+    // configured
+    let rate_limits = config.rate_limits.clone();
+    let ip_limiter = config.ip_rate_scale.map(|x| Arc::new(IpRateLimiter::new(config.rate_limits.scaled(x))));
+    let queue_limits = config.queue.clone();
+    let upload_limiter = Arc::new(IpRequestLimiter::new(config.http_rate_limits.upload_bytes));
+    let login_limiter = Arc::new(IpRequestLimiter::new(config.http_rate_limits.logins));
+
+    let unloader_rooms = rooms.clone();
+    tokio::task::spawn(async move { unloader_rooms.run_unloader().await });
+    let shutdown_rooms = rooms.clone();
 
     // Turn our "state" into a new Filter...
     let rooms = warp::any().map(move || rooms.clone());
+    let server_key = warp::any().map(move || server_key.clone());
+    let user_store = warp::any().map(move || user_store.clone());
+    // Logins and registrations from one address share a bucket, since both hash a password
+    let login_limiter = warp::any().map(move || login_limiter.clone());
+
+    // POST /register with username and password form -> create user, replying with a session token
+    let register = warp::path("register")
+        .and(warp::path::end())
+        .and(warp::post())
+        .and(warp::body::content_length_limit(4096))
+        .and(warp::body::form())
+        .and(warp::addr::remote())
+        .and(login_limiter.clone())
+        .and(user_store.clone())
+        .and(server_key.clone())
+        .and_then(|form: HashMap<String, String>, remote: Option<SocketAddr>, login_limiter: Arc<IpRequestLimiter>, user_store: Arc<UserStore>, server_key: Arc<ServerKey>| async move {
+            if let Some(remote) = remote {
+                if !login_limiter.try_take(remote.ip(), 1.0).await {
+                    return Ok(warp::reply::with_status("Too many attempts, slow down".to_string(), StatusCode::TOO_MANY_REQUESTS));
+                }
+            }
+            let username = form.get("username").map(|x| x.as_str()).unwrap_or("");
+            let password = form.get("password").map(|x| x.as_str()).unwrap_or("");
+            let reply = match user_store.register(username, password).await {
+                Ok(user_id) => warp::reply::with_status(server_key.session_token(user_id), StatusCode::CREATED),
+                Err(UserError::UsernameTaken) => warp::reply::with_status(UserError::UsernameTaken.to_string(), StatusCode::CONFLICT),
+                Err(UserError::Io(err)) => {
+                    log::error!("Error saving user: {}", err);
+                    warp::reply::with_status("Error saving user".to_string(), StatusCode::INTERNAL_SERVER_ERROR)
+                }
+                Err(err) => warp::reply::with_status(err.to_string(), StatusCode::BAD_REQUEST),
+            };
+            Ok::<_, Infallible>(reply)
+        });
+
+    // POST /login with username and password form -> session token
+    let login = warp::path("login")
+        .and(warp::path::end())
+        .and(warp::post())
+        .and(warp::body::content_length_limit(4096))
+        .and(warp::body::form())
+        .and(warp::addr::remote())
+        .and(login_limiter)
+        .and(user_store.clone())
+        .and(server_key.clone())
+        .and_then(|form: HashMap<String, String>, remote: Option<SocketAddr>, login_limiter: Arc<IpRequestLimiter>, user_store: Arc<UserStore>, server_key: Arc<ServerKey>| async move {
+            if let Some(remote) = remote {
+                if !login_limiter.try_take(remote.ip(), 1.0).await {
+                    return Ok(warp::reply::with_status("Too many attempts, slow down".to_string(), StatusCode::TOO_MANY_REQUESTS));
+                }
+            }
+            let username = form.get("username").map(|x| x.as_str()).unwrap_or("");
+            let password = form.get("password").map(|x| x.as_str()).unwrap_or("");
+            let reply = match user_store.login(username, password).await {
+                Some(user_id) => warp::reply::with_status(server_key.session_token(user_id), StatusCode::OK),
+                None => warp::reply::with_status("Invalid username or password".to_string(), StatusCode::UNAUTHORIZED),
+            };
+            Ok::<_, Infallible>(reply)
+        });
 
-    // GET /ws -> websocket upgrade
+    // GET /metrics -> counters and gauges of rooms, for Prometheus. Only served on the metrics
+    // address
+    let per_room = config.metrics.per_room;
+    let metrics = warp::path("metrics")
+        .and(warp::path::end())
+        .and(warp::get())
+        .and(rooms.clone())
+        .and_then(move |rooms: Arc<RoomRegistry>| async move {
+            let reply = warp::reply::with_header(rooms.render_metrics(per_room).await, "content-type", "text/plain; version=0.0.4");
+            Ok::<_, Infallible>(reply)
+        });
+
+    // GET /new -> create room, redirecting to its editor link
+    let new_room = warp::path("new")
+        .and(warp::path::end())
+        .and(rooms.clone())
+        .map(|rooms: Arc<RoomRegistry>| {
+            let (room_id, tokens) = rooms.create();
+            // Set header directly, since Uri drops the fragment the frontend reads the room from
+            let location = format!("/#{}?token={}", room_id, tokens.editor);
+            warp::reply::with_status(
+                warp::reply::with_header(warp::reply(), "location", location),
+                StatusCode::SEE_OTHER,
+            )
+        });
+
+    // GET /ws/{room}/{username}?token={token}[&session={session}][&resume={resume}&seq={seq}]
+    // -> websocket upgrade. Logged in users get their stored id and name, guests a fresh id and
+    // the name in the path. Reconnecting clients pass their resume token and last sequence number
     let ws = warp::path("ws")
         .and(warp::path::param())
+        .and(warp::path::param())
+        .and(warp::query::<HashMap<String, String>>())
         .and(rooms)
-        .and_then(|room_id: usize, rooms: Arc<Vec<Arc<Room>>>| async move{
-            match rooms.get(room_id) {
-                Some(x) => Ok(x.clone()),
+        .and(user_store)
+        .and(server_key.clone())
+        .and_then(|room_id: String, username: String, query: HashMap<String, String>, rooms: Arc<RoomRegistry>, user_store: Arc<UserStore>, server_key: Arc<ServerKey>| async move{
+            let (user_id, username) = match query.get("session") {
+                Some(session) => {
+                    let user_id = server_key.verify_session(session).ok_or_else(warp::reject::not_found)?;
+                    let username = user_store.username(user_id).await.ok_or_else(warp::reject::not_found)?;
+                    (user_id, username)
+                }
+                None => (users::next_guest_id(), username),
+            };
+            let resume = match (query.get("resume"), query.get("seq")) {
+                (Some(resume_token), Some(seq)) => Some((resume_token.clone(), seq.parse().map_err(|_| warp::reject::not_found())?)),
+                _ => None,
+            };
+            let token = query.get("token").map(|x| x.as_str()).unwrap_or("");
+            match rooms.join(&room_id, token).await {
+                Some((room, role)) => Ok((room, user_id, username, role, resume)),
                 None => Err(warp::reject::not_found())
             } 
         })
-        .and(warp::path::param())
+        .untuple_one()
+        .and(warp::addr::remote())
         .and(warp::ws())
-        .map(| room :Arc<Room>, username: String, ws: warp::ws::Ws|    {
-            ws.on_upgrade(move |socket| connected(socket, room.clone(), username))
+        .map(move | room :Arc<Room>, user_id: UserId, username: Username, role: Role, resume: Option<(String, SequenceNumber)>, remote: Option<SocketAddr>, ws: warp::ws::Ws|    {
+            let rate_limiter = ConnectionRateLimiter::new(&rate_limits, ip_limiter.clone().zip(remote.map(|x| x.ip())));
+            let traffic_limits = TrafficLimits { rate_limiter, queue_limits: queue_limits.clone() };
+            // Refuse oversized messages before they are buffered, the room bounds them once inflated
+            ws.max_message_size(max_message_size).on_upgrade(move |socket| connected(socket, room.clone(), user_id, username, role, resume, traffic_limits))
         });
 
-    let args: Vec<String> = std::env::args().collect();
+    // POST /assets?room={room}&token={editor token} -> store image, replying with its hash.
+    // Only editors may upload, which is checked before the body is read
+    let asset_store = warp::any().map(move || asset_store.clone());
+    let upload_limiter = warp::any().map(move || upload_limiter.clone());
+    let upload = warp::path("assets")
+        .and(warp::path::end())
+        .and(warp::post())
+        .and(warp::query::<HashMap<String, String>>())
+        .and(server_key)
+        .and_then(|query: HashMap<String, String>, server_key: Arc<ServerKey>| async move {
+            let room_id = query.get("room").map(|x| x.as_str()).unwrap_or("");
+            let token = query.get("token").map(|x| x.as_str()).unwrap_or("");
+            match server_key.room_tokens(room_id).role(token) {
+                Some(Role::Editor) => Ok(()),
+                _ => Err(warp::reject::not_found()),
+            }
+        })
+        .untuple_one()
+        .and(warp::addr::remote())
+        .and(warp::body::content_length_limit(MAX_ASSET_SIZE))
+        .and(warp::body::bytes())
+        .and(asset_store)
+        .and(upload_limiter)
+        .and_then(|remote: Option<SocketAddr>, data: bytes::Bytes, asset_store: Arc<AssetStore>, upload_limiter: Arc<IpRequestLimiter>| async move {
+            if let Some(remote) = remote {
+                if !upload_limiter.try_take(remote.ip(), data.len() as f64).await {
+                    return Ok(warp::reply::with_status("Too many uploads, slow down".to_string(), StatusCode::TOO_MANY_REQUESTS));
+                }
+            }
+            let reply = match asset_store.store(&data).await {
+                Ok(hash) => warp::reply::with_status(hash, StatusCode::CREATED),
+                Err(AssetError::QuotaExceeded) => warp::reply::with_status(AssetError::QuotaExceeded.to_string(), StatusCode::INSUFFICIENT_STORAGE),
+                Err(AssetError::Io(err)) => {
+                    log::error!("Error storing asset: {}", err);
+                    warp::reply::with_status("Error storing asset".to_string(), StatusCode::INTERNAL_SERVER_ERROR)
+                }
+                Err(err) => warp::reply::with_status(err.to_string(), StatusCode::BAD_REQUEST),
+            };
+            Ok::<_, Infallible>(reply)
+        });
 
-    let static_fs = warp::fs::dir(PathBuf::from(args[1].as_str()));
+    // GET /assets/{hash} -> stored image
+    let assets_fs = warp::path("assets").and(warp::fs::dir(asset_dir));
 
-    warp::serve(ws.or(static_fs)).run(SocketAddr::from_str("[::]:8081").unwrap()).await;
-    
-    //let ipv4_warp = warp::serve(chat.clone()).try_bind(SocketAddr::from_str("0.0.0.0:8081").unwrap());
-    //let ipv6_warp = warp::serve(chat.clone()).try_bind(SocketAddr::from_str("[::]:8081").unwrap());
-    //futures::future::join(ipv6_warp, ipv4_warp).await;
+    let static_fs = warp::fs::dir(config.static_dir.clone());
+
+    let routes = ws.or(new_room).or(register).or(login).or(upload).or(assets_fs).or(static_fs);
+    // Stop accepting connections on shutdown, then close the open ones
+    let signal = shutdown_signal().boxed().shared();
+    let mut servers = Vec::new();
+    for addr in &config.listen {
+        match warp::serve(routes.clone()).try_bind_with_graceful_shutdown(*addr, signal.clone()) {
+            Ok((addr, server)) => {
+                log::info!("Listening on {}", addr);
+                servers.push(server.boxed());
+            }
+            Err(err) => {
+                log::error!("Unable to listen on {}: {}", addr, err);
+                std::process::exit(1);
+            }
+        }
+    }
+    if let Some(addr) = config.metrics.listen {
+        match warp::serve(metrics).try_bind_with_graceful_shutdown(addr, signal.clone()) {
+            Ok((addr, server)) => {
+                log::info!("Serving metrics on {}", addr);
+                servers.push(server.boxed());
+            }
+            Err(err) => {
+                log::error!("Unable to serve metrics on {}: {}", addr, err);
+                std::process::exit(1);
+            }
+        }
+    }
+    let shutdown = async move {
+        signal.await;
+        log::info!("Shutting down");
+        shutdown_rooms.shutdown(SHUTDOWN_TIMEOUT).await;
+    };
+    futures::future::join(futures::future::join_all(servers), shutdown).await;
 
 }
 
 
 
-async fn connected(ws: WebSocket, room: Arc<Room>, username: String) {
+/// Resolves on Ctrl-C, or SIGTERM on Unix
+async fn shutdown_signal() {
+    #[cfg(unix)]
+    {
+        use tokio::signal::unix::{signal, SignalKind};
+        let mut terminate = signal(SignalKind::terminate()).expect("Unable to listen for SIGTERM");
+        tokio::select! {
+            _ = tokio::signal::ctrl_c() => (),
+            _ = terminate.recv() => (),
+        }
+    }
+    #[cfg(not(unix))]
+    {
+        let _ = tokio::signal::ctrl_c().await;
+    }
+}
+
+/// Limits on a connection's traffic in each direction
+struct TrafficLimits {
+    rate_limiter: ConnectionRateLimiter,
+    queue_limits: QueueLimits,
+}
+
+async fn connected(ws: WebSocket, room: Arc<Room>, user_id: UserId, username: Username, role: Role, resume: Option<(String, SequenceNumber)>, traffic_limits: TrafficLimits) {
+    let mut rate_limiter = traffic_limits.rate_limiter;
+    let queue = Arc::new(OutboundQueue::new(traffic_limits.queue_limits, room.metrics.clone()));
 
 
     // Split the socket into a sender and receive of messages.
-    let (ws_tx, mut ws_rx) = ws.split();
-
-    // Use an unbounded channel to handle buffering and flushing of messages
-    // to the websocket...
-    let (tx, rx) = mpsc::unbounded_channel();
-    tokio::task::spawn(rx.forward(ws_tx).map(|result| {
-        if let Err(e) = result {
-            eprintln!("websocket send error: {}", e);
+    let (mut ws_tx, mut ws_rx) = ws.split();
+
+    // Write queued messages to the websocket until the queue is closed. A send that takes longer
+    // than the queue's lag limit means the client stopped reading
+    let max_lag = queue.limits().max_lag;
+    let writer_queue = queue.clone();
+    let metrics = room.metrics.clone();
+    let mut writer = tokio::task::spawn(async move {
+        while let Some(msg) = writer_queue.pop().await {
+            let len = msg.as_bytes().len();
+            match tokio::time::timeout(max_lag, ws_tx.send(msg)).await {
+                Ok(Ok(())) => {
+                    metrics.bytes_out.fetch_add(len as u64, Ordering::Relaxed);
+                }
+                Ok(Err(e)) => {
+                    log::warn!("websocket send error: {}", e);
+                    break;
+                }
+                Err(_) => {
+                    log::warn!("websocket send timed out");
+                    break;
+                }
+            }
         }
-    }));
+        writer_queue.finish();
+    });
 
 
-    let userid = room.connect(tx, username).await;
+    let conn_id = room.connect(queue.clone(), user_id, username, role, resume).await;
 
 
     // Every time the user sends a message, broadcast it to
     // all other users...
-    while let Some(result) = ws_rx.next().await {
+    let mut writer_done = false;
+    loop {
+        // Stop reading once nothing more can be written, such as after closing a slow consumer
+        let result = tokio::select! {
+            result = ws_rx.next() => result,
+            _ = &mut writer => {
+                writer_done = true;
+                None
+            }
+        };
+        let result = match result {
+            Some(result) => result,
+            None => break,
+        };
         let msg = match result {
             Ok(msg) => msg,
             Err(e) => {
-                eprintln!("websocket error(uid={}): {}", userid, e);
+                log::warn!("websocket error(uid={}): {}", user_id, e);
                 break;
             }
         };
-        room.receive_msg(userid, msg).await;
+
+        // Check size before decoding, then what the message does
+        room.metrics.bytes_in.fetch_add(msg.as_bytes().len() as u64, Ordering::Relaxed);
+        let mut verdict = rate_limiter.check_bytes(msg.as_bytes().len()).await;
+        let data = match verdict {
+            RateVerdict::Allow if msg.is_binary() => room.decode_msg(conn_id, msg.as_bytes()).await,
+            _ => None,
+        };
+        if let Some(data) = &data {
+            verdict = rate_limiter.check_msg(data).await;
+            if verdict != RateVerdict::Allow {
+                room.metrics.reject(data);
+            }
+        }
+        match (verdict, data) {
+            (RateVerdict::Allow, Some(data)) => room.receive_msg(conn_id, data).await,
+            (RateVerdict::Allow, None) => (),
+            (RateVerdict::Throttle, _) => {
+                room.send_error(conn_id, ErrorCode::RateLimited, "Too many messages, slow down".to_string()).await;
+            }
+            (RateVerdict::Disconnect, _) => {
+                log::warn!("Disconnecting uid={} for exceeding rate limits", user_id);
+                room.send_error(conn_id, ErrorCode::RateLimited, "Too many messages, disconnecting".to_string()).await;
+                queue.close(POLICY_VIOLATION, "Rate limit exceeded");
+                break;
+            }
+        }
     }
 
 
 //    // ws_rx stream will keep processing as long as the user stays
 //    // connected. Once they disconnect, then...
-    room.disconnect(userid).await;
+    room.disconnect(conn_id).await;
+    queue.finish();
+    // Hold on to the room until the last messages are written, so shutting down waits for them
+    if !writer_done {
+        let _ = writer.await;
+    }
 
 }
 
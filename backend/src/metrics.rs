@@ -0,0 +1,295 @@
+//! Counters and gauges of each room, exported in the Prometheus text format
+
+use crate::RoomId;
+use netsketch_shared::prelude::*;
+use std::fmt::Write;
+use std::sync::atomic::{AtomicI64, AtomicU64, Ordering};
+use std::sync::Arc;
+
+/// Upper bounds in bytes of the buckets sizes are counted in
+pub const SIZE_BUCKETS: [u64; 8] = [
+    1 << 10,
+    4 << 10,
+    16 << 10,
+    64 << 10,
+    256 << 10,
+    1 << 20,
+    4 << 20,
+    16 << 20,
+];
+
+/// Distribution of sizes in bytes, counted in `SIZE_BUCKETS`
+#[derive(Default)]
+pub struct SizeHistogram {
+    /// Sizes up to each bound, and above the last
+    buckets: [AtomicU64; SIZE_BUCKETS.len() + 1],
+    sum: AtomicU64,
+}
+
+impl SizeHistogram {
+    pub fn observe(&self, size: usize) {
+        let size = size as u64;
+        let i = SIZE_BUCKETS
+            .iter()
+            .position(|x| size <= *x)
+            .unwrap_or(SIZE_BUCKETS.len());
+        self.buckets[i].fetch_add(1, Ordering::Relaxed);
+        self.sum.fetch_add(size, Ordering::Relaxed);
+    }
+}
+
+/// Activity of one room. Added to the registry's total of unloaded rooms when the room is
+/// unloaded
+#[derive(Default)]
+pub struct RoomMetrics {
+    pub connections: AtomicI64,
+    pub strokes_accepted: AtomicU64,
+    /// Paint strokes refused for permissions, limits or rate limits
+    pub strokes_rejected: AtomicU64,
+    /// Size of the websocket messages received from the room's connections
+    pub bytes_in: AtomicU64,
+    pub bytes_out: AtomicU64,
+    /// Client messages that couldn't be decoded
+    pub decode_errors: AtomicU64,
+    /// Server messages that couldn't be encoded
+    pub encode_errors: AtomicU64,
+    /// Messages waiting in the outbound queues of the room's connections
+    pub queued_messages: AtomicI64,
+    pub queued_bytes: AtomicI64,
+    /// Compressed size of everything sent in reply to a `SetViewPort`
+    pub viewport_bytes: SizeHistogram,
+}
+
+impl RoomMetrics {
+    /// Adds the counters of another room. Gauges are left out, as they are back to zero once a
+    /// room is unused
+    pub fn add_counters(&self, other: &RoomMetrics) {
+        let add = |x: &AtomicU64, y: &AtomicU64| {
+            x.fetch_add(y.load(Ordering::Relaxed), Ordering::Relaxed);
+        };
+        add(&self.strokes_accepted, &other.strokes_accepted);
+        add(&self.strokes_rejected, &other.strokes_rejected);
+        add(&self.bytes_in, &other.bytes_in);
+        add(&self.bytes_out, &other.bytes_out);
+        add(&self.decode_errors, &other.decode_errors);
+        add(&self.encode_errors, &other.encode_errors);
+        for (x, y) in self
+            .viewport_bytes
+            .buckets
+            .iter()
+            .zip(&other.viewport_bytes.buckets)
+        {
+            add(x, y);
+        }
+        add(&self.viewport_bytes.sum, &other.viewport_bytes.sum);
+    }
+
+    /// Counts a client message that was refused
+    pub fn reject(&self, msg: &ClientMessage) {
+        if let ClientMessage::PaintStroke(..) = msg {
+            self.strokes_rejected.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+}
+
+type Sample = fn(&RoomMetrics) -> i64;
+
+/// Name, type, help and value of each metric with one value per room
+const ROOM_METRICS: &[(&str, &str, &str, Sample)] = &[
+    (
+        "netsketch_connections",
+        "gauge",
+        "Open websocket connections",
+        |x| x.connections.load(Ordering::Relaxed),
+    ),
+    (
+        "netsketch_strokes_accepted_total",
+        "counter",
+        "Paint strokes added",
+        |x| x.strokes_accepted.load(Ordering::Relaxed) as i64,
+    ),
+    (
+        "netsketch_strokes_rejected_total",
+        "counter",
+        "Paint strokes refused for permissions, limits or rate limits",
+        |x| x.strokes_rejected.load(Ordering::Relaxed) as i64,
+    ),
+    (
+        "netsketch_received_bytes_total",
+        "counter",
+        "Size of websocket messages received",
+        |x| x.bytes_in.load(Ordering::Relaxed) as i64,
+    ),
+    (
+        "netsketch_sent_bytes_total",
+        "counter",
+        "Size of websocket messages sent",
+        |x| x.bytes_out.load(Ordering::Relaxed) as i64,
+    ),
+    (
+        "netsketch_decode_errors_total",
+        "counter",
+        "Client messages that couldn't be decoded",
+        |x| x.decode_errors.load(Ordering::Relaxed) as i64,
+    ),
+    (
+        "netsketch_encode_errors_total",
+        "counter",
+        "Server messages that couldn't be encoded",
+        |x| x.encode_errors.load(Ordering::Relaxed) as i64,
+    ),
+    (
+        "netsketch_queued_messages",
+        "gauge",
+        "Messages waiting to be written to connections",
+        |x| x.queued_messages.load(Ordering::Relaxed),
+    ),
+    (
+        "netsketch_queued_bytes",
+        "gauge",
+        "Size of messages waiting to be written to connections",
+        |x| x.queued_bytes.load(Ordering::Relaxed),
+    ),
+];
+
+/// Renders the metrics of rooms. Unless `per_room` is set, rooms are summed into one series,
+/// since room names are part of their URLs. Room names are alphanumeric, so they need no
+/// escaping
+pub fn render(loaded_rooms: usize, rooms: &[(RoomId, Arc<RoomMetrics>)], per_room: bool) -> String {
+    // Labels of each series, and the rooms summed into it
+    let series: Vec<(String, Vec<&RoomMetrics>)> = if per_room {
+        rooms
+            .iter()
+            .map(|(room_id, metrics)| (format!("room=\"{}\"", room_id), vec![&**metrics]))
+            .collect()
+    } else {
+        vec![(String::new(), rooms.iter().map(|x| &*x.1).collect())]
+    };
+
+    let mut out = String::new();
+    header(&mut out, "netsketch_rooms", "gauge", "Loaded rooms");
+    let _ = writeln!(out, "netsketch_rooms {}", loaded_rooms);
+
+    for (name, kind, help, sample) in ROOM_METRICS {
+        header(&mut out, name, kind, help);
+        for (labels, metrics) in &series {
+            let value: i64 = metrics.iter().map(|x| sample(x)).sum();
+            let _ = writeln!(out, "{}{} {}", name, braced(labels), value);
+        }
+    }
+
+    let name = "netsketch_viewport_reply_bytes";
+    header(
+        &mut out,
+        name,
+        "histogram",
+        "Compressed size of everything sent in reply to a viewport change",
+    );
+    for (labels, metrics) in &series {
+        let mut count = 0;
+        for (i, bound) in SIZE_BUCKETS.iter().map(Some).chain(Some(None)).enumerate() {
+            count += metrics
+                .iter()
+                .map(|x| x.viewport_bytes.buckets[i].load(Ordering::Relaxed))
+                .sum::<u64>();
+            let le = match bound {
+                Some(bound) => format!("le=\"{}\"", bound),
+                None => "le=\"+Inf\"".to_string(),
+            };
+            let bucket_labels = if labels.is_empty() {
+                le
+            } else {
+                format!("{},{}", labels, le)
+            };
+            let _ = writeln!(out, "{}_bucket{{{}}} {}", name, bucket_labels, count);
+        }
+        let sum: u64 = metrics
+            .iter()
+            .map(|x| x.viewport_bytes.sum.load(Ordering::Relaxed))
+            .sum();
+        let _ = writeln!(out, "{}_sum{} {}", name, braced(labels), sum);
+        let _ = writeln!(out, "{}_count{} {}", name, braced(labels), count);
+    }
+    out
+}
+
+/// Labels in braces, or nothing if there are none
+fn braced(labels: &str) -> String {
+    if labels.is_empty() {
+        String::new()
+    } else {
+        format!("{{{}}}", labels)
+    }
+}
+
+fn header(out: &mut String, name: &str, kind: &str, help: &str) {
+    let _ = writeln!(out, "# HELP {} {}", name, help);
+    let _ = writeln!(out, "# TYPE {} {}", name, kind);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn renders_room_metrics() {
+        let metrics = Arc::new(RoomMetrics::default());
+        metrics.connections.fetch_add(2, Ordering::Relaxed);
+        metrics.strokes_accepted.fetch_add(5, Ordering::Relaxed);
+        metrics.viewport_bytes.observe(100);
+        metrics.viewport_bytes.observe(2000);
+        metrics.viewport_bytes.observe(100 << 20);
+        let other = Arc::new(RoomMetrics::default());
+        other.strokes_accepted.fetch_add(1, Ordering::Relaxed);
+        other.viewport_bytes.observe(100);
+        let rooms = [("abc".to_string(), metrics), ("def".to_string(), other)];
+
+        let out = render(1, &rooms, true);
+        let lines: Vec<&str> = out.lines().collect();
+        for line in &[
+            "netsketch_rooms 1",
+            "netsketch_connections{room=\"abc\"} 2",
+            "netsketch_strokes_accepted_total{room=\"abc\"} 5",
+            "netsketch_strokes_accepted_total{room=\"def\"} 1",
+            "netsketch_strokes_rejected_total{room=\"abc\"} 0",
+            "netsketch_viewport_reply_bytes_bucket{room=\"abc\",le=\"1024\"} 1",
+            "netsketch_viewport_reply_bytes_bucket{room=\"abc\",le=\"4096\"} 2",
+            "netsketch_viewport_reply_bytes_bucket{room=\"abc\",le=\"16777216\"} 2",
+            "netsketch_viewport_reply_bytes_bucket{room=\"abc\",le=\"+Inf\"} 3",
+            "netsketch_viewport_reply_bytes_count{room=\"abc\"} 3",
+        ] {
+            assert!(lines.contains(line), "missing {}", line);
+        }
+        assert!(lines.contains(&"# TYPE netsketch_connections gauge"));
+
+        // Room names are left out unless asked for
+        let out = render(1, &rooms, false);
+        assert!(!out.contains("abc"));
+        let lines: Vec<&str> = out.lines().collect();
+        for line in &[
+            "netsketch_connections 2",
+            "netsketch_strokes_accepted_total 6",
+            "netsketch_viewport_reply_bytes_bucket{le=\"1024\"} 2",
+            "netsketch_viewport_reply_bytes_bucket{le=\"+Inf\"} 4",
+            "netsketch_viewport_reply_bytes_sum 104859800",
+            "netsketch_viewport_reply_bytes_count 4",
+        ] {
+            assert!(lines.contains(line), "missing {}", line);
+        }
+    }
+
+    #[test]
+    fn adds_counters() {
+        let total = RoomMetrics::default();
+        let metrics = RoomMetrics::default();
+        metrics.connections.fetch_add(1, Ordering::Relaxed);
+        metrics.strokes_accepted.fetch_add(5, Ordering::Relaxed);
+        metrics.viewport_bytes.observe(2000);
+        total.add_counters(&metrics);
+        total.add_counters(&metrics);
+        assert_eq!(total.connections.load(Ordering::Relaxed), 0);
+        assert_eq!(total.strokes_accepted.load(Ordering::Relaxed), 10);
+        assert_eq!(total.viewport_bytes.buckets[1].load(Ordering::Relaxed), 2);
+        assert_eq!(total.viewport_bytes.sum.load(Ordering::Relaxed), 4000);
+    }
+}
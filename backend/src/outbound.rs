@@ -0,0 +1,361 @@
+use crate::metrics::RoomMetrics;
+use crate::MessageSink;
+use bytes::Bytes;
+use netsketch_shared::prelude::*;
+use serde::Deserialize;
+use std::collections::VecDeque;
+use std::sync::atomic::Ordering;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tokio::sync::Notify;
+use warp::ws::Message as WsMessage;
+
+/// Websocket close code for connections closed because they couldn't keep up
+pub const CLOSE_SLOW_CONSUMER: u16 = 4001;
+
+/// Bounds on the messages waiting to be written to a connection
+#[derive(Deserialize, Debug, PartialEq, Clone)]
+#[serde(default, deny_unknown_fields)]
+pub struct QueueLimits {
+    pub max_messages: usize,
+    /// Maximum total size in bytes of queued messages
+    pub max_bytes: usize,
+    /// Longest a message may wait before the connection is considered too slow
+    #[serde(
+        rename = "max_lag_secs",
+        deserialize_with = "crate::config::deserialize_secs"
+    )]
+    pub max_lag: Duration,
+}
+
+impl Default for QueueLimits {
+    fn default() -> Self {
+        QueueLimits {
+            max_messages: 1024,
+            max_bytes: 8 << 20,
+            max_lag: Duration::from_secs(30),
+        }
+    }
+}
+
+/// State that a message replaces completely, so a queued message with the same key is redundant
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum SupersedeKey {
+    LayerTree,
+    Palette(PaletteId),
+    BrushPreset(BrushPresetId),
+    TextLabel(LayerId, ElementId),
+}
+
+/// How a message may be treated when a connection falls behind
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum MessageClass {
+    /// Must be delivered in order
+    Required,
+    /// Replaces any queued message with the same key
+    Supersedes(SupersedeKey),
+    /// Informational, dropped when the queue is full
+    Droppable,
+}
+
+impl MessageClass {
+    pub fn of(msg: &ServerMessage) -> Self {
+        match msg {
+            ServerMessage::Sequenced(_, msg) => MessageClass::of(msg),
+            ServerMessage::Error { .. } => MessageClass::Droppable,
+            ServerMessage::LayerTree(_) => MessageClass::Supersedes(SupersedeKey::LayerTree),
+            ServerMessage::Palette(palette) => {
+                MessageClass::Supersedes(SupersedeKey::Palette(palette.id))
+            }
+            ServerMessage::BrushPreset(brush_preset) => {
+                MessageClass::Supersedes(SupersedeKey::BrushPreset(brush_preset.id))
+            }
+            ServerMessage::TextLabel(layer_id, text_label) => {
+                MessageClass::Supersedes(SupersedeKey::TextLabel(*layer_id, text_label.id))
+            }
+            _ => MessageClass::Required,
+        }
+    }
+}
+
+/// Message waiting to be written
+enum Frame {
+    /// Compressed message. The buffer is shared with every other connection it's queued for
+    /// until it is written
+    Binary(Bytes),
+    Close(u16, &'static str),
+}
+
+impl Frame {
+    fn len(&self) -> usize {
+        match self {
+            Frame::Binary(data) => data.len(),
+            Frame::Close(_, reason) => reason.len(),
+        }
+    }
+
+    /// Copies the frame into a websocket message. warp's messages own a `Vec<u8>` and have no
+    /// constructor taking shared bytes, so each connection writing a message makes its own copy
+    fn into_message(self) -> WsMessage {
+        match self {
+            Frame::Binary(data) => WsMessage::binary(data.to_vec()),
+            Frame::Close(code, reason) => WsMessage::close_with(code, reason),
+        }
+    }
+}
+
+struct Queued {
+    frame: Frame,
+    class: MessageClass,
+    queued_at: Instant,
+}
+
+#[derive(Default)]
+struct QueueState {
+    items: VecDeque<Queued>,
+    bytes: usize,
+    /// No more messages are accepted, the writer stops once the queue is drained
+    closed: bool,
+}
+
+impl QueueState {
+    fn remove(&mut self, i: usize) {
+        if let Some(item) = self.items.remove(i) {
+            self.bytes -= item.frame.len();
+        }
+    }
+
+    fn push_back(&mut self, frame: Frame, class: MessageClass) {
+        self.bytes += frame.len();
+        self.items.push_back(Queued {
+            frame,
+            class,
+            queued_at: Instant::now(),
+        });
+    }
+}
+
+/// Bounded queue of messages waiting to be written to a connection's websocket. Redundant
+/// messages are coalesced, and a connection that falls too far behind is closed, so it can
+/// resume later instead of growing server memory without limit
+pub struct OutboundQueue {
+    limits: QueueLimits,
+    state: Mutex<QueueState>,
+    notify: Notify,
+    /// Metrics of the connection's room, which count what's queued
+    metrics: Arc<RoomMetrics>,
+}
+
+impl OutboundQueue {
+    pub fn new(limits: QueueLimits, metrics: Arc<RoomMetrics>) -> Self {
+        OutboundQueue {
+            limits,
+            state: Mutex::new(QueueState::default()),
+            notify: Notify::new(),
+            metrics,
+        }
+    }
+
+    pub fn limits(&self) -> &QueueLimits {
+        &self.limits
+    }
+
+    /// Queues a message, compressed as `data`. If the connection has fallen behind, it is closed
+    /// instead
+    pub fn push(&self, msg: &ServerMessage, data: Bytes) {
+        let class = MessageClass::of(msg);
+        self.with_state(|state| {
+            if state.closed {
+                return;
+            }
+
+            let lagging = match state.items.front() {
+                Some(oldest) => oldest.queued_at.elapsed() > self.limits.max_lag,
+                None => false,
+            };
+            if let MessageClass::Supersedes(key) = class {
+                if let Some(i) = state
+                    .items
+                    .iter()
+                    .position(|x| x.class == MessageClass::Supersedes(key))
+                {
+                    if !lagging && self.replace(state, i, msg, &data) {
+                        self.notify.notify();
+                        return;
+                    }
+                    state.remove(i);
+                }
+            }
+
+            let fits = |state: &QueueState| {
+                state.items.len() < self.limits.max_messages
+                    && state.bytes + data.len() <= self.limits.max_bytes
+            };
+            while !fits(state) {
+                match state
+                    .items
+                    .iter()
+                    .position(|x| x.class == MessageClass::Droppable)
+                {
+                    Some(i) => state.remove(i),
+                    None => break,
+                }
+            }
+
+            if lagging || !fits(state) {
+                if class == MessageClass::Droppable && !lagging {
+                    return;
+                }
+                Self::close_slow(state);
+            } else {
+                state.push_back(Frame::Binary(data), class);
+            }
+            self.notify.notify();
+        });
+    }
+
+    /// Replaces the queued message at `i` with a message superseding it, keeping its place so
+    /// messages queued after it still follow it. Returns false if the message doesn't fit.
+    ///
+    /// The replacement can end up ahead of messages with lower sequence numbers, so it is queued
+    /// without its sequence number, keeping those the client sees in order. A client resuming
+    /// from before it is sent it again, which is harmless since it replaces the same state
+    fn replace(&self, state: &mut QueueState, i: usize, msg: &ServerMessage, data: &Bytes) -> bool {
+        let data = match msg {
+            ServerMessage::Sequenced(_, msg) => match netsketch_shared::to_zbincode(&**msg) {
+                Ok(data) => Bytes::from(data),
+                Err(_) => return false,
+            },
+            _ => data.clone(),
+        };
+        let old_len = state.items[i].frame.len();
+        if state.bytes - old_len + data.len() > self.limits.max_bytes {
+            return false;
+        }
+        state.bytes = state.bytes - old_len + data.len();
+        state.items[i].frame = Frame::Binary(data);
+        true
+    }
+
+    /// Drops everything queued and closes the connection, telling the client why first
+    fn close_slow(state: &mut QueueState) {
+        state.items.clear();
+        state.bytes = 0;
+        let error = ServerMessage::Error {
+            code: ErrorCode::SlowConsumer,
+            message: "Connection too slow, reconnect to resume".to_string(),
+        };
+        if let Ok(data) = netsketch_shared::to_zbincode(&error) {
+            state.push_back(Frame::Binary(data.into()), MessageClass::Required);
+        }
+        state.push_back(
+            Frame::Close(CLOSE_SLOW_CONSUMER, "Slow consumer"),
+            MessageClass::Required,
+        );
+        state.closed = true;
+    }
+
+    /// Closes the connection with `code` after the messages already queued
+    pub fn close(&self, code: u16, reason: &'static str) {
+        self.with_state(|state| {
+            if !state.closed {
+                state.push_back(Frame::Close(code, reason), MessageClass::Required);
+                state.closed = true;
+            }
+        });
+        self.notify.notify();
+    }
+
+    /// Stops accepting messages, so the writer stops once the queue is drained
+    pub fn finish(&self) {
+        self.with_state(|state| state.closed = true);
+        self.notify.notify();
+    }
+
+    /// Waits for the next message to write. Returns None once the queue is closed and drained
+    pub async fn pop(&self) -> Option<WsMessage> {
+        loop {
+            let popped = self.with_state(|state| match state.items.pop_front() {
+                Some(item) => {
+                    state.bytes -= item.frame.len();
+                    Some(Some(item.frame.into_message()))
+                }
+                None if state.closed => Some(None),
+                None => None,
+            });
+            if let Some(msg) = popped {
+                return msg;
+            }
+            self.notify.notified().await;
+        }
+    }
+
+    /// Changes the queue's state, keeping the room's queue gauges up to date
+    fn with_state<R>(&self, f: impl FnOnce(&mut QueueState) -> R) -> R {
+        let mut state = self.state.lock().expect("outbound queue poisoned");
+        let (len, bytes) = (state.items.len() as i64, state.bytes as i64);
+        let result = f(&mut state);
+        self.metrics
+            .queued_messages
+            .fetch_add(state.items.len() as i64 - len, Ordering::Relaxed);
+        self.metrics
+            .queued_bytes
+            .fetch_add(state.bytes as i64 - bytes, Ordering::Relaxed);
+        result
+    }
+}
+
+impl Drop for OutboundQueue {
+    fn drop(&mut self) {
+        // Messages that were never written no longer count as queued
+        self.with_state(|state| {
+            state.items.clear();
+            state.bytes = 0;
+        });
+    }
+}
+
+impl MessageSink for OutboundQueue {
+    fn send(&self, msg: &ServerMessage, data: &Bytes) {
+        self.push(msg, data.clone());
+    }
+
+    fn close(&self, code: u16, reason: &'static str) {
+        OutboundQueue::close(self, code, reason);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn push(queue: &OutboundQueue, msg: ServerMessage) {
+        let data = netsketch_shared::to_zbincode(&msg).unwrap();
+        queue.push(&msg, data.into());
+    }
+
+    async fn pop(queue: &OutboundQueue) -> ServerMessage {
+        let msg = queue.pop().await.unwrap();
+        netsketch_shared::from_zbincode(msg.as_bytes()).unwrap()
+    }
+
+    #[tokio::test]
+    async fn superseding_message_keeps_its_place() {
+        let queue = OutboundQueue::new(QueueLimits::default(), Arc::default());
+        let sequenced = |seq, msg| ServerMessage::Sequenced(seq, Box::new(msg));
+        let mut tree = LayerTree::default();
+        push(&queue, sequenced(1, ServerMessage::LayerTree(tree.clone())));
+        push(&queue, sequenced(2, ServerMessage::RemoveElement(0, 1)));
+        tree.create_group();
+        push(&queue, sequenced(3, ServerMessage::LayerTree(tree.clone())));
+
+        // Sent without its number, so the numbers the client sees stay in order
+        assert_eq!(pop(&queue).await, ServerMessage::LayerTree(tree));
+        assert_eq!(
+            pop(&queue).await,
+            sequenced(2, ServerMessage::RemoveElement(0, 1))
+        );
+        queue.finish();
+        assert!(queue.pop().await.is_none());
+    }
+}
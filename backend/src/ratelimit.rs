@@ -0,0 +1,479 @@
+use netsketch_shared::prelude::*;
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::sync::Arc;
+use std::time::Instant;
+use tokio::sync::Mutex;
+
+/// Number of tracked addresses above which idle ones are forgotten
+const MAX_IDLE_IPS: usize = 1024;
+
+/// Sustained rate and burst allowance of one kind of traffic
+#[derive(Deserialize, Debug, PartialEq, Clone, Copy)]
+#[serde(deny_unknown_fields)]
+pub struct RatePolicy {
+    /// Tokens added per second
+    pub rate: f64,
+    /// Maximum tokens that can be saved up
+    pub burst: f64,
+}
+
+impl RatePolicy {
+    pub const fn new(rate: f64, burst: f64) -> Self {
+        RatePolicy { rate, burst }
+    }
+}
+
+/// Bucket refilling at a steady rate, which traffic takes tokens from
+#[derive(Debug, Clone)]
+pub struct TokenBucket {
+    policy: RatePolicy,
+    tokens: f64,
+    last_refill: Instant,
+}
+
+impl TokenBucket {
+    /// Creates a full bucket
+    pub fn new(policy: RatePolicy, now: Instant) -> Self {
+        TokenBucket {
+            policy,
+            tokens: policy.burst,
+            last_refill: now,
+        }
+    }
+
+    fn refill(&mut self, now: Instant) {
+        let elapsed = now
+            .saturating_duration_since(self.last_refill)
+            .as_secs_f64();
+        self.tokens = (self.tokens + elapsed * self.policy.rate).min(self.policy.burst);
+        self.last_refill = now;
+    }
+
+    /// Tokens available at `now`
+    pub fn tokens(&mut self, now: Instant) -> f64 {
+        self.refill(now);
+        self.tokens
+    }
+
+    /// Takes `amount` tokens if there are enough. Returns false, taking nothing, otherwise
+    pub fn try_take(&mut self, amount: f64, now: Instant) -> bool {
+        if self.tokens(now) < amount {
+            return false;
+        }
+        self.tokens -= amount;
+        true
+    }
+
+    /// Whether the bucket has refilled completely
+    pub fn is_full(&mut self, now: Instant) -> bool {
+        self.tokens(now) >= self.policy.burst
+    }
+}
+
+/// Limits on the traffic of a connection or address
+#[derive(Deserialize, Debug, PartialEq, Clone)]
+#[serde(default, deny_unknown_fields)]
+pub struct RateLimits {
+    /// Paint strokes and other changes to the room
+    pub strokes: RatePolicy,
+    /// Points in paint strokes, polygons and eraser paths
+    pub points: RatePolicy,
+    /// Bytes of compressed messages as received
+    pub bytes: RatePolicy,
+    pub chat_messages: RatePolicy,
+    pub viewport_changes: RatePolicy,
+    /// Messages dropped for exceeding the limits above. Once these run out the connection is
+    /// closed
+    pub violations: RatePolicy,
+}
+
+impl Default for RateLimits {
+    fn default() -> Self {
+        RateLimits {
+            strokes: RatePolicy::new(30.0, 60.0),
+            points: RatePolicy::new(3000.0, 20_000.0),
+            bytes: RatePolicy::new(256.0 * 1024.0, 1024.0 * 1024.0),
+            chat_messages: RatePolicy::new(2.0, 10.0),
+            viewport_changes: RatePolicy::new(20.0, 40.0),
+            violations: RatePolicy::new(1.0, 20.0),
+        }
+    }
+}
+
+impl RateLimits {
+    /// Limits for all connections from one address, allowing `factor` busy connections at once
+    pub fn scaled(&self, factor: f64) -> Self {
+        let scale =
+            |policy: RatePolicy| RatePolicy::new(policy.rate * factor, policy.burst * factor);
+        RateLimits {
+            strokes: scale(self.strokes),
+            points: scale(self.points),
+            bytes: scale(self.bytes),
+            chat_messages: scale(self.chat_messages),
+            viewport_changes: scale(self.viewport_changes),
+            violations: scale(self.violations),
+        }
+    }
+}
+
+/// Limits on HTTP requests from each address
+#[derive(Deserialize, Debug, PartialEq, Clone)]
+#[serde(default, deny_unknown_fields)]
+pub struct HttpRateLimits {
+    /// Bytes of uploaded assets
+    pub upload_bytes: RatePolicy,
+    /// Login and registration attempts, which each hash a password
+    pub logins: RatePolicy,
+}
+
+impl Default for HttpRateLimits {
+    fn default() -> Self {
+        HttpRateLimits {
+            upload_bytes: RatePolicy::new(64.0 * 1024.0, 32.0 * 1024.0 * 1024.0),
+            logins: RatePolicy::new(0.2, 10.0),
+        }
+    }
+}
+
+/// What to do with a message after checking it against the limits
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum RateVerdict {
+    Allow,
+    /// Drop the message and tell the client it is going too fast
+    Throttle,
+    /// Client kept exceeding the limits, close the connection
+    Disconnect,
+}
+
+/// Tokens a message takes from the buckets
+#[derive(Debug, PartialEq, Clone, Copy)]
+enum Charge {
+    /// Size of a message as received, before decoding it
+    Bytes(f64),
+    ChatMessage,
+    ViewportChange,
+    /// Change to the room carrying some points
+    Change {
+        points: f64,
+    },
+    Free,
+}
+
+impl Charge {
+    fn of_msg(msg: &ClientMessage) -> Self {
+        match msg {
+            ClientMessage::ChatMessage(_) => Charge::ChatMessage,
+            ClientMessage::SetViewPort(..) => Charge::ViewportChange,
+            msg if msg.is_mutating() => Charge::Change {
+                points: num_points(msg) as f64,
+            },
+            _ => Charge::Free,
+        }
+    }
+}
+
+/// Token buckets for each kind of limited traffic
+#[derive(Debug, Clone)]
+struct Buckets {
+    strokes: TokenBucket,
+    points: TokenBucket,
+    bytes: TokenBucket,
+    chat_messages: TokenBucket,
+    viewport_changes: TokenBucket,
+    violations: TokenBucket,
+}
+
+impl Buckets {
+    fn new(limits: &RateLimits, now: Instant) -> Self {
+        Buckets {
+            strokes: TokenBucket::new(limits.strokes, now),
+            points: TokenBucket::new(limits.points, now),
+            bytes: TokenBucket::new(limits.bytes, now),
+            chat_messages: TokenBucket::new(limits.chat_messages, now),
+            viewport_changes: TokenBucket::new(limits.viewport_changes, now),
+            violations: TokenBucket::new(limits.violations, now),
+        }
+    }
+
+    /// Whether there are enough tokens for a charge, without taking them
+    fn allows(&mut self, charge: Charge, now: Instant) -> bool {
+        match charge {
+            Charge::Bytes(len) => self.bytes.tokens(now) >= len,
+            Charge::ChatMessage => self.chat_messages.tokens(now) >= 1.0,
+            Charge::ViewportChange => self.viewport_changes.tokens(now) >= 1.0,
+            Charge::Change { points } => {
+                self.strokes.tokens(now) >= 1.0 && self.points.tokens(now) >= points
+            }
+            Charge::Free => true,
+        }
+    }
+
+    /// Takes the tokens of a charge that `allows` accepted
+    fn take(&mut self, charge: Charge, now: Instant) {
+        match charge {
+            Charge::Bytes(len) => {
+                self.bytes.try_take(len, now);
+            }
+            Charge::ChatMessage => {
+                self.chat_messages.try_take(1.0, now);
+            }
+            Charge::ViewportChange => {
+                self.viewport_changes.try_take(1.0, now);
+            }
+            Charge::Change { points } => {
+                self.strokes.try_take(1.0, now);
+                self.points.try_take(points, now);
+            }
+            Charge::Free => (),
+        }
+    }
+
+    /// Counts a violation, escalating to disconnecting once too many have happened
+    fn violation(&mut self, now: Instant) -> RateVerdict {
+        if self.violations.try_take(1.0, now) {
+            RateVerdict::Throttle
+        } else {
+            RateVerdict::Disconnect
+        }
+    }
+
+    fn is_idle(&mut self, now: Instant) -> bool {
+        self.strokes.is_full(now)
+            && self.points.is_full(now)
+            && self.bytes.is_full(now)
+            && self.chat_messages.is_full(now)
+            && self.viewport_changes.is_full(now)
+            && self.violations.is_full(now)
+    }
+}
+
+/// Number of points a message carries, which is most of the work of storing and sending it
+fn num_points(msg: &ClientMessage) -> usize {
+    match msg {
+        ClientMessage::PaintStroke(_, _, paint_stroke) => paint_stroke.points.len(),
+        ClientMessage::Shape(_, shape) => match &shape.kind {
+            ShapeKind::Polygon { points } => points.len(),
+            _ => 1,
+        },
+        ClientMessage::StrokeErase(_, stroke_erase) => stroke_erase.path.len(),
+        _ => 1,
+    }
+}
+
+/// Buckets shared by every connection from the same address
+pub struct IpRateLimiter {
+    limits: RateLimits,
+    buckets: Mutex<HashMap<IpAddr, Buckets>>,
+}
+
+impl IpRateLimiter {
+    pub fn new(limits: RateLimits) -> Self {
+        IpRateLimiter {
+            limits,
+            buckets: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Takes a charge from the address's buckets if they allow it. Returns false, taking
+    /// nothing, otherwise
+    async fn try_take(&self, ip: IpAddr, charge: Charge, now: Instant) -> bool {
+        let mut buckets = self.buckets.lock().await;
+        if !buckets.contains_key(&ip) && buckets.len() >= MAX_IDLE_IPS {
+            // Addresses whose buckets have refilled are indistinguishable from new ones
+            buckets.retain(|_, x| !x.is_idle(now));
+        }
+        let limits = &self.limits;
+        let buckets = buckets
+            .entry(ip)
+            .or_insert_with(|| Buckets::new(limits, now));
+        if !buckets.allows(charge, now) {
+            return false;
+        }
+        buckets.take(charge, now);
+        true
+    }
+}
+
+/// Rate limiter for one connection, also charging its address if per address limits are on.
+/// Messages are only charged if both allow them. Violations always count against the
+/// connection, so one client exceeding the address's limits doesn't disconnect the others
+pub struct ConnectionRateLimiter {
+    buckets: Buckets,
+    ip: Option<(Arc<IpRateLimiter>, IpAddr)>,
+}
+
+impl ConnectionRateLimiter {
+    pub fn new(limits: &RateLimits, ip: Option<(Arc<IpRateLimiter>, IpAddr)>) -> Self {
+        ConnectionRateLimiter {
+            buckets: Buckets::new(limits, Instant::now()),
+            ip,
+        }
+    }
+
+    /// Checks a received message's size, before spending any time decoding it
+    pub async fn check_bytes(&mut self, len: usize) -> RateVerdict {
+        self.check(Charge::Bytes(len as f64), Instant::now()).await
+    }
+
+    /// Checks a decoded message against the limits for its kind
+    pub async fn check_msg(&mut self, msg: &ClientMessage) -> RateVerdict {
+        self.check(Charge::of_msg(msg), Instant::now()).await
+    }
+
+    async fn check(&mut self, charge: Charge, now: Instant) -> RateVerdict {
+        if !self.buckets.allows(charge, now) {
+            return self.buckets.violation(now);
+        }
+        if let Some((ip_limiter, ip)) = &self.ip {
+            if !ip_limiter.try_take(*ip, charge, now).await {
+                return self.buckets.violation(now);
+            }
+        }
+        self.buckets.take(charge, now);
+        RateVerdict::Allow
+    }
+}
+
+/// One token bucket per address, for limiting a kind of HTTP request
+pub struct IpRequestLimiter {
+    policy: RatePolicy,
+    buckets: Mutex<HashMap<IpAddr, TokenBucket>>,
+}
+
+impl IpRequestLimiter {
+    pub fn new(policy: RatePolicy) -> Self {
+        IpRequestLimiter {
+            policy,
+            buckets: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Takes `amount` tokens from the address's bucket. Returns false, taking nothing, if there
+    /// aren't enough
+    pub async fn try_take(&self, ip: IpAddr, amount: f64) -> bool {
+        let now = Instant::now();
+        let mut buckets = self.buckets.lock().await;
+        if !buckets.contains_key(&ip) && buckets.len() >= MAX_IDLE_IPS {
+            buckets.retain(|_, x| !x.is_full(now));
+        }
+        let policy = self.policy;
+        buckets
+            .entry(ip)
+            .or_insert_with(|| TokenBucket::new(policy, now))
+            .try_take(amount, now)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    fn stroke(points: usize) -> ClientMessage {
+        ClientMessage::PaintStroke(
+            0,
+            ClientStrokeId::nil(),
+            PaintStroke {
+                points: vec![StrokePoint::default(); points],
+                ..PaintStroke::default()
+            },
+        )
+    }
+
+    #[test]
+    fn token_bucket_refills_up_to_burst() {
+        let start = Instant::now();
+        let mut bucket = TokenBucket::new(RatePolicy::new(2.0, 10.0), start);
+        assert!(bucket.try_take(8.0, start));
+        assert!(!bucket.try_take(3.0, start));
+        // Nothing is taken when there aren't enough
+        assert_eq!(bucket.tokens(start), 2.0);
+
+        assert_eq!(bucket.tokens(start + Duration::from_secs(1)), 4.0);
+        assert!(bucket.try_take(3.0, start + Duration::from_secs(1)));
+        assert!(bucket.is_full(start + Duration::from_secs(60)));
+        assert_eq!(bucket.tokens(start + Duration::from_secs(60)), 10.0);
+    }
+
+    #[test]
+    fn stroke_points_are_charged() {
+        let now = Instant::now();
+        let limits = RateLimits {
+            points: RatePolicy::new(0.0, 100.0),
+            ..RateLimits::default()
+        };
+        let mut buckets = Buckets::new(&limits, now);
+        assert_eq!(Charge::of_msg(&stroke(60)), Charge::Change { points: 60.0 });
+        assert_eq!(
+            Charge::of_msg(&ClientMessage::ChatMessage(String::new())),
+            Charge::ChatMessage
+        );
+
+        assert!(buckets.allows(Charge::of_msg(&stroke(60)), now));
+        buckets.take(Charge::of_msg(&stroke(60)), now);
+        assert!(!buckets.allows(Charge::of_msg(&stroke(60)), now));
+        assert!(buckets.allows(Charge::of_msg(&stroke(40)), now));
+        assert_eq!(buckets.strokes.tokens(now), limits.strokes.burst - 1.0);
+    }
+
+    #[tokio::test]
+    async fn violations_escalate_to_disconnect() {
+        let now = Instant::now();
+        let limits = RateLimits {
+            chat_messages: RatePolicy::new(0.0, 1.0),
+            violations: RatePolicy::new(1.0, 2.0),
+            ..RateLimits::default()
+        };
+        let mut limiter = ConnectionRateLimiter::new(&limits, None);
+        assert_eq!(
+            limiter.check(Charge::ChatMessage, now).await,
+            RateVerdict::Allow
+        );
+        for _ in 0..2 {
+            assert_eq!(
+                limiter.check(Charge::ChatMessage, now).await,
+                RateVerdict::Throttle
+            );
+        }
+        assert_eq!(
+            limiter.check(Charge::ChatMessage, now).await,
+            RateVerdict::Disconnect
+        );
+        // Violations are forgiven over time
+        let later = now + Duration::from_secs(1);
+        assert_eq!(
+            limiter.check(Charge::ChatMessage, later).await,
+            RateVerdict::Throttle
+        );
+    }
+
+    #[tokio::test]
+    async fn address_throttle_spends_nothing_from_connection() {
+        let now = Instant::now();
+        let limits = RateLimits {
+            strokes: RatePolicy::new(0.0, 10.0),
+            ..RateLimits::default()
+        };
+        let ip_limiter = Arc::new(IpRateLimiter::new(RateLimits {
+            strokes: RatePolicy::new(0.0, 1.0),
+            ..RateLimits::default()
+        }));
+        let ip = IpAddr::from([127, 0, 0, 1]);
+        let mut alice = ConnectionRateLimiter::new(&limits, Some((ip_limiter.clone(), ip)));
+        let mut bob = ConnectionRateLimiter::new(&limits, Some((ip_limiter, ip)));
+        let charge = Charge::of_msg(&stroke(1));
+
+        assert_eq!(alice.check(charge, now).await, RateVerdict::Allow);
+        assert_eq!(bob.check(charge, now).await, RateVerdict::Throttle);
+        assert_eq!(bob.buckets.strokes.tokens(now), 10.0);
+        assert_eq!(alice.buckets.strokes.tokens(now), 9.0);
+        // Only the connection that was throttled counts a violation
+        assert_eq!(
+            bob.buckets.violations.tokens(now),
+            limits.violations.burst - 1.0
+        );
+        assert!(alice.buckets.violations.is_full(now));
+    }
+}
@@ -0,0 +1,86 @@
+use crate::ConnectionId;
+use bytes::Bytes;
+use netsketch_shared::prelude::*;
+use std::collections::HashSet;
+use std::collections::VecDeque;
+
+/// Number of recent messages kept per room for clients that reconnect
+pub const REPLAY_BUFFER_LEN: usize = 1024;
+
+/// Message sent to a room, kept so it can be replayed
+pub struct ReplayEvent {
+    pub seq: SequenceNumber,
+    /// Tiles the message concerns, or None if it was sent to every connection
+    pub tile_offsets: Option<HashSet<Offset>>,
+    /// Connection the message wasn't sent to, because it originated there
+    pub exclude_conn_id: Option<ConnectionId>,
+    /// The message wrapped in `ServerMessage::Sequenced`
+    pub msg: ServerMessage,
+    /// Compressed `msg`, queued for every connection it's sent to without being compressed again
+    pub data: Bytes,
+}
+
+impl ReplayEvent {
+    /// Whether a connection viewing `active_tile_offsets` was sent this message
+    pub fn is_for(&self, conn_id: ConnectionId, active_tile_offsets: &HashSet<Offset>) -> bool {
+        Some(conn_id) != self.exclude_conn_id
+            && match &self.tile_offsets {
+                Some(tile_offsets) => tile_offsets.intersection(active_tile_offsets).count() != 0,
+                None => true,
+            }
+    }
+}
+
+/// Numbers the messages sent to a room and keeps the most recent ones
+#[derive(Default)]
+pub struct ReplayBuffer {
+    events: VecDeque<ReplayEvent>,
+    last_seq: SequenceNumber,
+}
+
+impl ReplayBuffer {
+    /// Sequence number of the last message, 0 if none have been sent
+    pub fn last_seq(&self) -> SequenceNumber {
+        self.last_seq
+    }
+
+    /// Assigns the next sequence number to a message and stores it, dropping the oldest message
+    /// if full. Returns the stored event, whose data is ready to send
+    pub fn push(
+        &mut self,
+        msg: &ServerMessage,
+        tile_offsets: Option<&HashSet<Offset>>,
+        exclude_conn_id: Option<ConnectionId>,
+    ) -> Result<&ReplayEvent, String> {
+        let seq = self.last_seq + 1;
+        let msg = ServerMessage::Sequenced(seq, Box::new(msg.clone()));
+        let data = netsketch_shared::to_zbincode(&msg)?;
+        self.last_seq = seq;
+
+        if self.events.len() >= REPLAY_BUFFER_LEN {
+            self.events.pop_front();
+        }
+        self.events.push_back(ReplayEvent {
+            seq,
+            tile_offsets: tile_offsets.cloned(),
+            exclude_conn_id,
+            msg,
+            data: data.into(),
+        });
+        Ok(self.events.back().expect("event just pushed"))
+    }
+
+    /// Returns the messages after `seq`, or None if some of them have already been dropped or
+    /// `seq` is in the future
+    pub fn since(&self, seq: SequenceNumber) -> Option<impl Iterator<Item = &ReplayEvent>> {
+        let oldest = self
+            .events
+            .front()
+            .map(|x| x.seq)
+            .unwrap_or(self.last_seq + 1);
+        if seq > self.last_seq || seq + 1 < oldest {
+            return None;
+        }
+        Some(self.events.iter().filter(move |x| x.seq > seq))
+    }
+}
@@ -0,0 +1,451 @@
+use crate::access::{RoomTokens, ServerKey};
+use crate::assets::AssetStore;
+use crate::config::RoomsConfig;
+use crate::metrics::{self, RoomMetrics};
+use crate::snapshots::RoomStore;
+use crate::Room;
+use crate::RoomId;
+use netsketch_shared::{Limits, Role};
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tokio::sync::{OwnedMutexGuard, RwLock};
+
+/// Maximum length in bytes of a room name
+pub const MAX_ROOM_NAME_LEN: usize = 64;
+
+/// Room name may contain ASCII letters, digits, '-' and '_', so it can be used as-is in URLs
+pub fn is_valid_room_name(name: &str) -> bool {
+    !name.is_empty()
+        && name.len() <= MAX_ROOM_NAME_LEN
+        && name
+            .chars()
+            .all(|x| x.is_ascii_alphanumeric() || x == '-' || x == '_')
+}
+
+struct RoomEntry {
+    room: Arc<Room>,
+    /// When the room was first seen unused by the sweeper, None while in use
+    idle_since: Option<Instant>,
+}
+
+/// Held while a room is being loaded, or saved and unloaded, so that joins to it wait while
+/// joins to other rooms don't
+struct RoomLock<'a> {
+    registry: &'a RoomRegistry,
+    name: String,
+    guard: Option<OwnedMutexGuard<()>>,
+}
+
+impl Drop for RoomLock<'_> {
+    fn drop(&mut self) {
+        self.guard = None;
+        let mut locks = self
+            .registry
+            .room_locks
+            .lock()
+            .expect("room locks poisoned");
+        if locks
+            .get(&self.name)
+            .is_some_and(|x| Arc::strong_count(x) == 1)
+        {
+            locks.remove(&self.name);
+        }
+    }
+}
+
+/// Registry of loaded rooms, keyed by name. Rooms are loaded from the room store on first join,
+/// and saved back and unloaded once they have gone unused for the configured idle timeout
+pub struct RoomRegistry {
+    /// Loaded rooms. Never held while loading or saving a room
+    rooms: RwLock<HashMap<String, RoomEntry>>,
+    /// Locks of the rooms being loaded or unloaded
+    room_locks: Mutex<HashMap<String, Arc<tokio::sync::Mutex<()>>>>,
+    /// Metrics of loaded rooms
+    room_metrics: Mutex<HashMap<RoomId, Arc<RoomMetrics>>>,
+    /// Counters of rooms unloaded so far, so totals don't go down as rooms are unloaded
+    unloaded_metrics: RoomMetrics,
+    config: RoomsConfig,
+    room_store: RoomStore,
+    asset_store: Option<Arc<AssetStore>>,
+    server_key: Arc<ServerKey>,
+    /// Limits given to every room
+    limits: Limits,
+    /// No more joins are accepted
+    shutting_down: AtomicBool,
+}
+
+impl RoomRegistry {
+    pub fn new(
+        config: RoomsConfig,
+        room_store: RoomStore,
+        asset_store: Option<Arc<AssetStore>>,
+        server_key: Arc<ServerKey>,
+        limits: Limits,
+    ) -> Self {
+        RoomRegistry {
+            rooms: RwLock::new(HashMap::new()),
+            room_locks: Mutex::new(HashMap::new()),
+            room_metrics: Mutex::new(HashMap::new()),
+            unloaded_metrics: RoomMetrics::default(),
+            config,
+            room_store,
+            asset_store,
+            server_key,
+            limits,
+            shutting_down: AtomicBool::new(false),
+        }
+    }
+
+    /// Picks a random name for a new room, returning it with the room's tokens
+    pub fn create(&self) -> (RoomId, RoomTokens) {
+        let name = format!("{:016x}", rand::random::<u64>());
+        let tokens = self.server_key.room_tokens(&name);
+        (name, tokens)
+    }
+
+    /// Returns the room with this name and the role granted by token, loading the room if it
+    /// isn't loaded. Returns None if the name or token is invalid, the server is shutting down,
+    /// or the saved room can't be read
+    pub async fn join(&self, name: &str, token: &str) -> Option<(Arc<Room>, Role)> {
+        if !is_valid_room_name(name) || self.shutting_down.load(Ordering::Relaxed) {
+            return None;
+        }
+        let tokens = self.server_key.room_tokens(name);
+        let role = tokens.role(token)?;
+        if let Some(entry) = self.rooms.read().await.get(name) {
+            return Some((entry.room.clone(), role));
+        }
+
+        let _lock = self.lock_room(name).await;
+        if self.shutting_down.load(Ordering::Relaxed) {
+            return None;
+        }
+        if let Some(entry) = self.rooms.read().await.get(name) {
+            return Some((entry.room.clone(), role));
+        }
+        log::info!("Loading room {}", name);
+        let saved = match self.room_store.load(name).await {
+            Ok(saved) => saved,
+            Err(err) => {
+                // Starting the room empty would overwrite what was saved on unload
+                log::error!("Unable to load room {}: {}", name, err);
+                return None;
+            }
+        };
+        let metrics = self
+            .room_metrics
+            .lock()
+            .expect("room metrics poisoned")
+            .entry(name.to_string())
+            .or_default()
+            .clone();
+        let room = Arc::new(Room::spawn(
+            name.to_string(),
+            tokens,
+            self.asset_store.clone(),
+            self.limits.clone(),
+            self.config.undo_depth,
+            metrics,
+            saved,
+        ));
+        self.rooms.write().await.insert(
+            name.to_string(),
+            RoomEntry {
+                room: room.clone(),
+                idle_since: None,
+            },
+        );
+        Some((room, role))
+    }
+
+    /// Waits until no other task is loading or unloading the room with this name
+    async fn lock_room(&self, name: &str) -> RoomLock<'_> {
+        let lock = self
+            .room_locks
+            .lock()
+            .expect("room locks poisoned")
+            .entry(name.to_string())
+            .or_default()
+            .clone();
+        RoomLock {
+            registry: self,
+            name: name.to_string(),
+            guard: Some(lock.lock_owned().await),
+        }
+    }
+
+    /// Saves a room's contents to the room store
+    async fn save(&self, room: &Room) -> Result<(), String> {
+        let data = room.encode().await?;
+        self.room_store
+            .save(&room.room_id, data)
+            .await
+            .map_err(|x| x.to_string())
+    }
+
+    /// Number of loaded rooms
+    pub async fn len(&self) -> usize {
+        self.rooms.read().await.len()
+    }
+
+    pub async fn is_empty(&self) -> bool {
+        self.rooms.read().await.is_empty()
+    }
+
+    /// Metrics of every room loaded so far, in the Prometheus text format. Series are labelled
+    /// with room names if `per_room` is set, with unloaded rooms summed under the empty name
+    pub async fn render_metrics(&self, per_room: bool) -> String {
+        let loaded_rooms = self.len().await;
+        let unloaded = Arc::new(RoomMetrics::default());
+        unloaded.add_counters(&self.unloaded_metrics);
+        let mut rooms: Vec<_> = self
+            .room_metrics
+            .lock()
+            .expect("room metrics poisoned")
+            .iter()
+            .map(|(name, metrics)| (name.clone(), metrics.clone()))
+            .chain(Some((String::new(), unloaded)))
+            .collect();
+        rooms.sort_by(|a, b| a.0.cmp(&b.0));
+        metrics::render(loaded_rooms, &rooms, per_room)
+    }
+
+    /// Saves and unloads rooms that have been unused for at least the idle timeout. A room is in
+    /// use while anything besides the registry holds a reference to it, which covers connected
+    /// users and joins in progress, so a room is never unloaded from under a client. Rooms that
+    /// can't be saved stay loaded
+    pub async fn unload_idle(&self) {
+        let now = Instant::now();
+        let idle_timeout = self.config.idle_timeout;
+        let mut idle = Vec::new();
+        for (name, entry) in self.rooms.write().await.iter_mut() {
+            if Arc::strong_count(&entry.room) > 1 {
+                entry.idle_since = None;
+                continue;
+            }
+            let idle_since = *entry.idle_since.get_or_insert(now);
+            if now.duration_since(idle_since) >= idle_timeout {
+                idle.push(name.clone());
+            }
+        }
+
+        for name in idle {
+            // Taken out of the registry while it is saved, so joins to it wait for the room lock
+            // and then load what was saved
+            let _lock = self.lock_room(&name).await;
+            let entry = {
+                let mut rooms = self.rooms.write().await;
+                match rooms.get(&name) {
+                    Some(entry) if Arc::strong_count(&entry.room) == 1 => rooms.remove(&name),
+                    _ => None,
+                }
+            };
+            let entry = match entry {
+                Some(entry) => entry,
+                None => continue,
+            };
+            match self.save(&entry.room).await {
+                Ok(()) => {
+                    log::info!("Unloading idle room {}", name);
+                    let metrics = self
+                        .room_metrics
+                        .lock()
+                        .expect("room metrics poisoned")
+                        .remove(&name);
+                    if let Some(metrics) = metrics {
+                        self.unloaded_metrics.add_counters(&metrics);
+                    }
+                }
+                Err(err) => {
+                    log::error!("Unable to save room {}: {}", name, err);
+                    self.rooms.write().await.insert(name, entry);
+                }
+            }
+        }
+    }
+
+    /// Stops accepting joins and closes every connection, telling it the server is restarting.
+    /// Once every connection has finished, or after `timeout`, saves every room and returns
+    pub async fn shutdown(&self, timeout: Duration) {
+        self.shutting_down.store(true, Ordering::Relaxed);
+        // Let rooms being loaded or saved finish, so they are closed or saved below
+        let room_locks: Vec<_> = self
+            .room_locks
+            .lock()
+            .expect("room locks poisoned")
+            .keys()
+            .cloned()
+            .collect();
+        for name in room_locks {
+            self.lock_room(&name).await;
+        }
+        let rooms: Vec<Arc<Room>> = self
+            .rooms
+            .read()
+            .await
+            .values()
+            .map(|x| x.room.clone())
+            .collect();
+        for room in rooms {
+            room.shutdown().await;
+        }
+
+        // Connections hold their room until their last message is written
+        let deadline = Instant::now() + timeout;
+        while Instant::now() < deadline {
+            let in_use = self
+                .rooms
+                .read()
+                .await
+                .values()
+                .any(|x| Arc::strong_count(&x.room) > 1);
+            if !in_use {
+                break;
+            }
+            tokio::time::delay_for(Duration::from_millis(100)).await;
+        }
+
+        for entry in self.rooms.read().await.values() {
+            if let Err(err) = self.save(&entry.room).await {
+                log::error!("Unable to save room {}: {}", entry.room.room_id, err);
+            }
+        }
+    }
+
+    /// Periodically unloads idle rooms. Never returns, so should be spawned as its own task
+    pub async fn run_unloader(&self) {
+        // Check often enough that rooms are unloaded within about 1.5 times the timeout
+        let period = (self.config.idle_timeout / 2).max(Duration::from_secs(1));
+        let mut interval = tokio::time::interval(period);
+        loop {
+            interval.tick().await;
+            self.unload_idle().await;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::harness::TestClient;
+    use netsketch_shared::prelude::*;
+
+    fn test_registry(room_dir: &std::path::Path) -> RoomRegistry {
+        RoomRegistry::new(
+            RoomsConfig {
+                idle_timeout: Duration::from_secs(0),
+                ..RoomsConfig::default()
+            },
+            RoomStore::open(room_dir).unwrap(),
+            None,
+            Arc::new(ServerKey::new(vec![1; 32])),
+            Limits::default(),
+        )
+    }
+
+    #[tokio::test]
+    async fn unloaded_rooms_keep_their_contents() {
+        let room_dir =
+            std::env::temp_dir().join(format!("netsketch-rooms-{}", rand::random::<u64>()));
+        let rooms = test_registry(&room_dir);
+        let (name, tokens) = rooms.create();
+
+        let (room, _) = rooms.join(&name, &tokens.editor).await.unwrap();
+        let alice = TestClient::join(&room, 1, Role::Editor).await;
+        alice
+            .send(ClientMessage::TextLabel(
+                0,
+                TextLabel {
+                    content: "hi".to_string(),
+                    ..TextLabel::default()
+                },
+            ))
+            .await;
+        alice
+            .send(ClientMessage::AddPalette(Palette {
+                name: "greys".to_string(),
+                ..Palette::default()
+            }))
+            .await;
+        alice.leave().await;
+        drop(room);
+        rooms.unload_idle().await;
+        assert!(rooms.is_empty().await);
+
+        // Loaded again from the room store, by another registry as after a restart
+        let rooms = test_registry(&room_dir);
+        let (room, _) = rooms.join(&name, &tokens.viewer).await.unwrap();
+        let bob = TestClient::join(&room, 2, Role::Viewer).await;
+        bob.send(ClientMessage::SetViewPort(
+            Offset { x: 0, y: 0 },
+            Offset { x: 99, y: 99 },
+        ))
+        .await;
+        let received = bob.received().await;
+        assert!(received
+            .iter()
+            .any(|x| matches!(x, ServerMessage::TextLabel(0, label) if label.content == "hi")));
+        assert!(received
+            .iter()
+            .any(|x| matches!(x, ServerMessage::Palette(palette) if palette.name == "greys")));
+        std::fs::remove_dir_all(&room_dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn joins_wait_only_for_their_room() {
+        let room_dir =
+            std::env::temp_dir().join(format!("netsketch-rooms-{}", rand::random::<u64>()));
+        let rooms = test_registry(&room_dir);
+        let (busy, busy_tokens) = rooms.create();
+        let (other, other_tokens) = rooms.create();
+
+        // As if the busy room was being loaded or saved
+        let lock = rooms.lock_room(&busy).await;
+        let timeout = Duration::from_millis(100);
+        let join = rooms.join(&other, &other_tokens.editor);
+        assert!(tokio::time::timeout(timeout, join).await.unwrap().is_some());
+        let join = rooms.join(&busy, &busy_tokens.editor);
+        assert!(tokio::time::timeout(timeout, join).await.is_err());
+
+        drop(lock);
+        assert!(rooms.join(&busy, &busy_tokens.editor).await.is_some());
+        assert!(rooms.room_locks.lock().unwrap().is_empty());
+        std::fs::remove_dir_all(&room_dir).ok();
+    }
+
+    #[tokio::test]
+    async fn unloaded_rooms_are_summed_in_metrics() {
+        let room_dir =
+            std::env::temp_dir().join(format!("netsketch-rooms-{}", rand::random::<u64>()));
+        let rooms = test_registry(&room_dir);
+        let (name, tokens) = rooms.create();
+
+        let (room, _) = rooms.join(&name, &tokens.editor).await.unwrap();
+        room.metrics
+            .strokes_accepted
+            .fetch_add(3, Ordering::Relaxed);
+        drop(room);
+        rooms.unload_idle().await;
+        assert!(rooms.is_empty().await);
+        assert!(rooms.room_metrics.lock().unwrap().is_empty());
+        let out = rooms.render_metrics(false).await;
+        assert!(out.contains("netsketch_rooms 0\n"));
+        assert!(out.contains("netsketch_strokes_accepted_total 3\n"));
+
+        let (room, _) = rooms.join(&name, &tokens.editor).await.unwrap();
+        room.metrics
+            .strokes_accepted
+            .fetch_add(1, Ordering::Relaxed);
+        let out = rooms.render_metrics(false).await;
+        assert!(out.contains("netsketch_strokes_accepted_total 4\n"));
+        let out = rooms.render_metrics(true).await;
+        assert!(out.contains("netsketch_strokes_accepted_total{room=\"\"} 3\n"));
+        assert!(out.contains(&format!(
+            "netsketch_strokes_accepted_total{{room=\"{}\"}} 1\n",
+            name
+        )));
+        std::fs::remove_dir_all(&room_dir).unwrap();
+    }
+}
@@ -0,0 +1,78 @@
+use netsketch_shared::{Canvas, StyleLibrary};
+use serde::{Deserialize, Serialize};
+use std::io;
+use std::path::{Path, PathBuf};
+
+/// Contents of a room that outlive it being loaded
+#[derive(Default, Deserialize)]
+pub struct SavedRoom {
+    pub canvas: Canvas,
+    pub styles: StyleLibrary,
+}
+
+/// Same layout as `SavedRoom`, so a room can be saved without copying its contents
+#[derive(Serialize)]
+struct SavedRoomRef<'a> {
+    canvas: &'a Canvas,
+    styles: &'a StyleLibrary,
+}
+
+/// Encodes a room's contents for `RoomStore::save`, or returns None if the room is empty
+pub fn encode_room(canvas: &Canvas, styles: &StyleLibrary) -> Result<Option<Vec<u8>>, String> {
+    let is_empty = canvas.tree().nodes().is_empty()
+        && styles.palettes().next().is_none()
+        && styles.brush_presets().next().is_none();
+    if is_empty {
+        return Ok(None);
+    }
+    netsketch_shared::to_zbincode(&SavedRoomRef { canvas, styles }).map(Some)
+}
+
+/// Directory rooms are saved to when unloaded, with one file per room named after it
+pub struct RoomStore {
+    root: PathBuf,
+}
+
+impl RoomStore {
+    /// Opens room store rooted at directory, creating it if nonexistant
+    pub fn open<P: AsRef<Path>>(root: P) -> io::Result<Self> {
+        std::fs::create_dir_all(root.as_ref())?;
+        Ok(RoomStore {
+            root: root.as_ref().to_path_buf(),
+        })
+    }
+
+    /// Room names are checked by `is_valid_room_name`, so they're safe to use as file names
+    fn path(&self, room_id: &str) -> PathBuf {
+        self.root.join(format!("{}.room", room_id))
+    }
+
+    /// Reads a saved room, or returns an empty one if it was never saved
+    pub async fn load(&self, room_id: &str) -> io::Result<SavedRoom> {
+        let data = match tokio::fs::read(self.path(room_id)).await {
+            Ok(data) => data,
+            Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(SavedRoom::default()),
+            Err(err) => return Err(err),
+        };
+        netsketch_shared::from_zbincode(&data)
+            .map_err(|x| io::Error::new(io::ErrorKind::InvalidData, x))
+    }
+
+    /// Saves a room encoded by `encode_room`. An empty room is saved by removing its file
+    pub async fn save(&self, room_id: &str, data: Option<Vec<u8>>) -> io::Result<()> {
+        let path = self.path(room_id);
+        let data = match data {
+            Some(data) => data,
+            None => {
+                return match tokio::fs::remove_file(&path).await {
+                    Err(err) if err.kind() != io::ErrorKind::NotFound => Err(err),
+                    _ => Ok(()),
+                }
+            }
+        };
+        // Write to temporary file first so a failed save never loses the previous one
+        let tmp_path = self.root.join(format!(".{}.tmp", room_id));
+        tokio::fs::write(&tmp_path, data).await?;
+        tokio::fs::rename(&tmp_path, &path).await
+    }
+}
@@ -0,0 +1,52 @@
+use crate::ConnectionId;
+use netsketch_shared::prelude::*;
+use std::collections::HashMap;
+use std::collections::HashSet;
+
+/// Index from each tile to the connections viewing it, so a change is only matched against the
+/// connections interested in its tiles instead of every connection in the room
+#[derive(Default)]
+pub struct TileSubscribers {
+    by_tile: HashMap<Offset, HashSet<ConnectionId>>,
+}
+
+impl TileSubscribers {
+    /// Moves a connection's subscriptions from the tiles in `old` to the tiles in `new`
+    pub fn update(&mut self, conn_id: ConnectionId, old: &HashSet<Offset>, new: &HashSet<Offset>) {
+        for tile_offset in old.difference(new) {
+            self.remove(conn_id, tile_offset);
+        }
+        for tile_offset in new.difference(old) {
+            self.by_tile
+                .entry(*tile_offset)
+                .or_default()
+                .insert(conn_id);
+        }
+    }
+
+    /// Removes a connection from every tile in `tile_offsets`
+    pub fn unsubscribe(&mut self, conn_id: ConnectionId, tile_offsets: &HashSet<Offset>) {
+        for tile_offset in tile_offsets {
+            self.remove(conn_id, tile_offset);
+        }
+    }
+
+    fn remove(&mut self, conn_id: ConnectionId, tile_offset: &Offset) {
+        if let Some(conn_ids) = self.by_tile.get_mut(tile_offset) {
+            conn_ids.remove(&conn_id);
+            if conn_ids.is_empty() {
+                self.by_tile.remove(tile_offset);
+            }
+        }
+    }
+
+    /// Connections viewing any of the tiles in `tile_offsets`
+    pub fn viewers(&self, tile_offsets: &HashSet<Offset>) -> HashSet<ConnectionId> {
+        tile_offsets
+            .iter()
+            .filter_map(|x| self.by_tile.get(x))
+            .flatten()
+            .copied()
+            .collect()
+    }
+}
@@ -0,0 +1,285 @@
+use crate::access::constant_time_eq;
+use hmac::Hmac;
+use netsketch_shared::{UserId, Username};
+use sha2::Sha256;
+use std::collections::HashMap;
+use std::io::{self, BufRead, Write};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use tokio::sync::RwLock;
+
+/// Maximum length in bytes of a username
+pub const MAX_USERNAME_LEN: usize = 32;
+/// Minimum length in bytes of a password
+pub const MIN_PASSWORD_LEN: usize = 8;
+/// PBKDF2 iterations used to hash passwords
+#[cfg(not(test))]
+const PBKDF2_ROUNDS: u32 = 100_000;
+/// Fewer in tests, which hash unoptimized
+#[cfg(test)]
+const PBKDF2_ROUNDS: u32 = 1000;
+const SALT_LEN: usize = 16;
+const HASH_LEN: usize = 32;
+
+/// Guests get ids from this value up, so they never collide with registered users. Kept below
+/// 2^32 so ids fit in a wasm usize
+pub const FIRST_GUEST_ID: UserId = 1 << 31;
+static NEXT_GUEST_ID: AtomicUsize = AtomicUsize::new(FIRST_GUEST_ID);
+
+/// Returns a fresh id for a connection that isn't logged in
+pub fn next_guest_id() -> UserId {
+    NEXT_GUEST_ID.fetch_add(1, Ordering::Relaxed)
+}
+
+pub fn is_guest(user_id: UserId) -> bool {
+    user_id >= FIRST_GUEST_ID
+}
+
+/// Username may contain ASCII letters, digits, '-' and '_'
+pub fn is_valid_username(username: &str) -> bool {
+    !username.is_empty()
+        && username.len() <= MAX_USERNAME_LEN
+        && username
+            .chars()
+            .all(|x| x.is_ascii_alphanumeric() || x == '-' || x == '_')
+}
+
+#[derive(Debug)]
+pub enum UserError {
+    InvalidUsername,
+    PasswordTooShort,
+    UsernameTaken,
+    Io(io::Error),
+}
+
+impl std::fmt::Display for UserError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            UserError::InvalidUsername => write!(f, "Invalid username"),
+            UserError::PasswordTooShort => {
+                write!(f, "Password shorter than {} bytes", MIN_PASSWORD_LEN)
+            }
+            UserError::UsernameTaken => write!(f, "Username taken"),
+            UserError::Io(err) => write!(f, "{}", err),
+        }
+    }
+}
+
+struct UserRecord {
+    user_id: UserId,
+    salt: Vec<u8>,
+    hash: Vec<u8>,
+}
+
+struct Users {
+    by_name: HashMap<Username, UserRecord>,
+    names: HashMap<UserId, Username>,
+    next_user_id: UserId,
+}
+
+/// Registered users with salted PBKDF2 password hashes, stored in a text file with one
+/// `user_id username salt hash` line per user
+pub struct UserStore {
+    path: PathBuf,
+    users: RwLock<Users>,
+}
+
+fn to_hex(data: &[u8]) -> String {
+    data.iter().map(|x| format!("{:02x}", x)).collect()
+}
+
+fn from_hex(hex: &str) -> Option<Vec<u8>> {
+    hex.as_bytes()
+        .chunks(2)
+        .map(|x| match x {
+            [_, _] => u8::from_str_radix(std::str::from_utf8(x).ok()?, 16).ok(),
+            _ => None,
+        })
+        .collect()
+}
+
+fn hash_password(password: &str, salt: &[u8]) -> Vec<u8> {
+    let mut hash = vec![0; HASH_LEN];
+    pbkdf2::pbkdf2::<Hmac<Sha256>>(password.as_bytes(), salt, PBKDF2_ROUNDS, &mut hash);
+    hash
+}
+
+/// Hashes on the blocking thread pool, since PBKDF2 is slow on purpose
+async fn hash_password_blocking(password: String, salt: Vec<u8>) -> Vec<u8> {
+    tokio::task::spawn_blocking(move || hash_password(&password, &salt))
+        .await
+        .expect("Password hashing panicked")
+}
+
+impl UserStore {
+    /// Opens user store file, which is created on first registration if nonexistant
+    pub fn open<P: AsRef<Path>>(path: P) -> io::Result<Self> {
+        let path = path.as_ref().to_path_buf();
+        let mut users = Users {
+            by_name: HashMap::new(),
+            names: HashMap::new(),
+            next_user_id: 1,
+        };
+
+        if path.exists() {
+            let file = std::fs::File::open(&path)?;
+            for (i, line) in io::BufReader::new(file).lines().enumerate() {
+                let line = line?;
+                let fields: Vec<&str> = line.split_whitespace().collect();
+                let record = match fields.as_slice() {
+                    [user_id, username, salt, hash] => user_id
+                        .parse()
+                        .ok()
+                        .and_then(|user_id| {
+                            Some(UserRecord {
+                                user_id,
+                                salt: from_hex(salt)?,
+                                hash: from_hex(hash)?,
+                            })
+                        })
+                        .map(|record| (username.to_string(), record)),
+                    [] => continue,
+                    _ => None,
+                };
+                let (username, record) = record.ok_or_else(|| {
+                    io::Error::new(
+                        io::ErrorKind::InvalidData,
+                        format!("Invalid user record on line {}", i + 1),
+                    )
+                })?;
+                users.next_user_id = users.next_user_id.max(record.user_id + 1);
+                users.names.insert(record.user_id, username.clone());
+                users.by_name.insert(username, record);
+            }
+        }
+
+        Ok(UserStore {
+            path,
+            users: RwLock::new(users),
+        })
+    }
+
+    /// Creates a user, returning its id
+    pub async fn register(&self, username: &str, password: &str) -> Result<UserId, UserError> {
+        if !is_valid_username(username) {
+            return Err(UserError::InvalidUsername);
+        }
+        if password.len() < MIN_PASSWORD_LEN {
+            return Err(UserError::PasswordTooShort);
+        }
+        if self.users.read().await.by_name.contains_key(username) {
+            return Err(UserError::UsernameTaken);
+        }
+
+        let salt: Vec<u8> = (0..SALT_LEN).map(|_| rand::random()).collect();
+        let hash = hash_password_blocking(password.to_string(), salt.clone()).await;
+
+        // Check again, since another registration may have finished while hashing
+        let mut users = self.users.write().await;
+        if users.by_name.contains_key(username) {
+            return Err(UserError::UsernameTaken);
+        }
+        let user_id = users.next_user_id;
+        let line = format!(
+            "{} {} {} {}\n",
+            user_id,
+            username,
+            to_hex(&salt),
+            to_hex(&hash)
+        );
+        // Appended on the blocking thread pool. The lock is still held, so lines are written
+        // in the order of their ids
+        let path = self.path.clone();
+        tokio::task::spawn_blocking(move || {
+            std::fs::OpenOptions::new()
+                .create(true)
+                .append(true)
+                .open(path)
+                .and_then(|mut file| file.write_all(line.as_bytes()))
+        })
+        .await
+        .expect("Appending user record panicked")
+        .map_err(UserError::Io)?;
+
+        users.next_user_id += 1;
+        users.names.insert(user_id, username.to_string());
+        users.by_name.insert(
+            username.to_string(),
+            UserRecord {
+                user_id,
+                salt,
+                hash,
+            },
+        );
+        Ok(user_id)
+    }
+
+    /// Checks password, returning the user's id if correct
+    pub async fn login(&self, username: &str, password: &str) -> Option<UserId> {
+        let record = self
+            .users
+            .read()
+            .await
+            .by_name
+            .get(username)
+            .map(|x| (x.user_id, x.salt.clone(), x.hash.clone()));
+        match record {
+            Some((user_id, salt, expected)) => {
+                let hash = hash_password_blocking(password.to_string(), salt).await;
+                if constant_time_eq(&hash, &expected) {
+                    Some(user_id)
+                } else {
+                    None
+                }
+            }
+            None => {
+                // Hash anyway, so the time taken doesn't reveal whether the user exists
+                hash_password_blocking(password.to_string(), vec![0; SALT_LEN]).await;
+                None
+            }
+        }
+    }
+
+    pub async fn username(&self, user_id: UserId) -> Option<Username> {
+        self.users.read().await.names.get(&user_id).cloned()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn registered_users_can_log_in() {
+        let path = std::env::temp_dir().join(format!("netsketch-users-{}", rand::random::<u64>()));
+        let users = UserStore::open(&path).unwrap();
+        let alice = users.register("alice", "password1").await.unwrap();
+        let bob = users.register("bob", "password2").await.unwrap();
+        assert_ne!(alice, bob);
+        assert!(!is_guest(alice));
+        assert_eq!(users.username(bob).await.as_deref(), Some("bob"));
+
+        assert!(matches!(
+            users.register("alice", "password3").await,
+            Err(UserError::UsernameTaken)
+        ));
+        assert!(matches!(
+            users.register("a b", "password3").await,
+            Err(UserError::InvalidUsername)
+        ));
+        assert!(matches!(
+            users.register("carol", "short").await,
+            Err(UserError::PasswordTooShort)
+        ));
+
+        assert_eq!(users.login("alice", "password1").await, Some(alice));
+        assert_eq!(users.login("alice", "password2").await, None);
+        assert_eq!(users.login("carol", "password1").await, None);
+
+        // Users are read back after a restart
+        let users = UserStore::open(&path).unwrap();
+        assert_eq!(users.login("bob", "password2").await, Some(bob));
+        assert_eq!(users.register("carol", "password3").await.unwrap(), bob + 1);
+        std::fs::remove_file(&path).unwrap();
+    }
+}
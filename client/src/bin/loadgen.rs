@@ -0,0 +1,486 @@
+//! Simulates many users panning and drawing in one room, reporting how quickly the server keeps
+//! up. Usage:
+//!
+//! netsketch-loadgen [--server ws://localhost:8081] [--room ROOM --token TOKEN] [--users 10]
+//!     [--duration 30] [--stroke-rate 1] [--pan-rate 0.2] [--points 20] [--layer 0]
+//!
+//! Rates are per user per second. Without a room, one is created. Every simulated user
+//! connects from the same address, so the server's `ip_rate_scale` should be left unset
+
+use netsketch_client::{ClientError, JoinOptions};
+use netsketch_shared::prelude::*;
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+use std::collections::HashMap;
+use std::io::{Read, Write};
+use std::str::FromStr;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+/// Size of the area users pan around in, small enough for their viewports to overlap
+const WORLD_SIZE: i32 = 4000;
+const VIEWPORT_WIDTH: i32 = 1280;
+const VIEWPORT_HEIGHT: i32 = 720;
+
+struct Options {
+    server: String,
+    room: Option<(String, String)>,
+    users: usize,
+    duration: Duration,
+    stroke_rate: f64,
+    pan_rate: f64,
+    points: usize,
+    /// Layer strokes are drawn on
+    layer: LayerId,
+}
+
+fn parse<T: FromStr>(arg: &str, value: &str) -> Result<T, String> {
+    value
+        .parse()
+        .map_err(|_| format!("Invalid value for {}: {}", arg, value))
+}
+
+fn parse_args() -> Result<Options, String> {
+    let mut options = Options {
+        server: "ws://localhost:8081".to_string(),
+        room: None,
+        users: 10,
+        duration: Duration::from_secs(30),
+        stroke_rate: 1.0,
+        pan_rate: 0.2,
+        points: 20,
+        layer: 0,
+    };
+    let (mut room, mut token) = (None, None);
+
+    let mut args = std::env::args().skip(1);
+    while let Some(arg) = args.next() {
+        let value = args
+            .next()
+            .ok_or_else(|| format!("Missing value for {}", arg))?;
+        match arg.as_str() {
+            "--server" => options.server = value,
+            "--room" => room = Some(value),
+            "--token" => token = Some(value),
+            "--users" => options.users = parse(&arg, &value)?,
+            "--duration" => options.duration = Duration::from_secs(parse(&arg, &value)?),
+            "--stroke-rate" => options.stroke_rate = parse(&arg, &value)?,
+            "--pan-rate" => options.pan_rate = parse(&arg, &value)?,
+            "--points" => options.points = parse(&arg, &value)?,
+            "--layer" => options.layer = parse(&arg, &value)?,
+            _ => return Err(format!("Unknown argument {}", arg)),
+        }
+    }
+
+    options.room = match (room, token) {
+        (Some(room), Some(token)) => Some((room, token)),
+        (None, None) => None,
+        _ => return Err("--room and --token must be given together".to_string()),
+    };
+    if options.users == 0 || options.points < 2 {
+        return Err("Need at least one user and two points per stroke".to_string());
+    }
+    Ok(options)
+}
+
+/// Creates a room with `GET /new`, returning its id and editor token from the redirect
+fn create_room(server: &str) -> Result<(String, String), String> {
+    let url = url::Url::parse(server).map_err(|x| x.to_string())?;
+    let host = url.host_str().ok_or("Server URL has no host")?;
+    let port = url
+        .port_or_known_default()
+        .ok_or("Server URL has no port")?;
+
+    let mut stream = std::net::TcpStream::connect((host, port)).map_err(|x| x.to_string())?;
+    write!(
+        stream,
+        "GET /new HTTP/1.1\r\nHost: {}\r\nConnection: close\r\n\r\n",
+        host
+    )
+    .map_err(|x| x.to_string())?;
+    let mut response = String::new();
+    stream
+        .read_to_string(&mut response)
+        .map_err(|x| x.to_string())?;
+
+    // Location is /#{room}?token={token}
+    let location = response
+        .lines()
+        .find_map(|x| {
+            let (name, value) = x.split_at(x.find(':')?);
+            if name.eq_ignore_ascii_case("location") {
+                Some(value[1..].trim())
+            } else {
+                None
+            }
+        })
+        .ok_or("No room in response to /new")?;
+    let location = location.trim_start_matches("/#");
+    let query_start = location
+        .find("?token=")
+        .ok_or("No token in /new location")?;
+    Ok((
+        location[..query_start].to_string(),
+        location[query_start + "?token=".len()..].to_string(),
+    ))
+}
+
+/// Identifies a paint stroke by its author and end points, so receivers can find when it was
+/// sent
+type StrokeKey = (UserId, Offset, Offset);
+
+fn stroke_key(user_id: UserId, paint_stroke: &PaintStroke) -> Option<StrokeKey> {
+    let point = |x: &StrokePoint| Offset { x: x.x, y: x.y };
+    Some((
+        user_id,
+        point(paint_stroke.points.first()?),
+        point(paint_stroke.points.last()?),
+    ))
+}
+
+/// Count and total size of one kind of message
+#[derive(Default, Clone, Copy)]
+struct Traffic {
+    messages: usize,
+    bytes: usize,
+}
+
+impl Traffic {
+    fn add(&mut self, bytes: usize) {
+        self.messages += 1;
+        self.bytes += bytes;
+    }
+}
+
+#[derive(Default)]
+struct Stats {
+    /// Time from sending a stroke to its acknowledgement
+    ack_latencies: Vec<Duration>,
+    /// Time from sending a stroke to another user receiving it
+    fan_out_delays: Vec<Duration>,
+    sent: HashMap<&'static str, Traffic>,
+    received: HashMap<&'static str, Traffic>,
+    errors: HashMap<String, usize>,
+    /// Users that couldn't join or were disconnected early
+    failed_users: usize,
+}
+
+/// Strokes in flight, shared by every simulated user
+#[derive(Default)]
+struct Shared {
+    stats: Stats,
+    sent_strokes: HashMap<StrokeKey, Instant>,
+    pending_acks: HashMap<ClientStrokeId, Instant>,
+}
+
+fn client_message_kind(msg: &ClientMessage) -> &'static str {
+    match msg {
+        ClientMessage::PaintStroke(..) => "PaintStroke",
+        ClientMessage::SetViewPort(..) => "SetViewPort",
+        _ => "Other",
+    }
+}
+
+fn server_message_kind(msg: &ServerMessage) -> &'static str {
+    match msg {
+        ServerMessage::Sequenced(_, msg) => match **msg {
+            ServerMessage::PaintStroke(..) => "PaintStroke (broadcast)",
+            ServerMessage::LayerTree(..) => "LayerTree (broadcast)",
+            _ => "Other (broadcast)",
+        },
+        ServerMessage::PaintStroke(..) => "PaintStroke (viewport)",
+        ServerMessage::LayerTree(..) => "LayerTree",
+        ServerMessage::StrokeAck { .. } => "StrokeAck",
+        ServerMessage::Error { .. } => "Error",
+        _ => "Other",
+    }
+}
+
+fn random_viewport_origin(rng: &mut impl Rng) -> Offset {
+    Offset {
+        x: rng.gen_range(0, WORLD_SIZE - VIEWPORT_WIDTH),
+        y: rng.gen_range(0, WORLD_SIZE - VIEWPORT_HEIGHT),
+    }
+}
+
+/// Random walk of `num_points` points inside the viewport
+fn random_stroke(rng: &mut impl Rng, origin: Offset, num_points: usize) -> PaintStroke {
+    let mut x = origin.x + rng.gen_range(0, VIEWPORT_WIDTH);
+    let mut y = origin.y + rng.gen_range(0, VIEWPORT_HEIGHT);
+    let points = (0..num_points)
+        .map(|_| {
+            x = (x + rng.gen_range(-20, 21))
+                .max(origin.x)
+                .min(origin.x + VIEWPORT_WIDTH);
+            y = (y + rng.gen_range(-20, 21))
+                .max(origin.y)
+                .min(origin.y + VIEWPORT_HEIGHT);
+            StrokePoint {
+                p: rng.gen_range(0.2, 1.0),
+                x,
+                y,
+            }
+        })
+        .collect();
+    PaintStroke {
+        id: 0,
+        user_id: 0,
+        brush: Brush {
+            color: Color {
+                r: rng.gen(),
+                g: rng.gen(),
+                b: rng.gen(),
+                a: 255,
+            },
+            width: rng.gen_range(1.0, 20.0),
+            ..Brush::default()
+        },
+        points,
+    }
+}
+
+/// Interval between events happening `rate` times a second, with the first one delayed
+/// randomly so users don't act in lockstep
+fn interval(rng: &mut impl Rng, rate: f64) -> Option<tokio::time::Interval> {
+    if rate <= 0.0 {
+        return None;
+    }
+    let period = Duration::from_secs_f64(1.0 / rate);
+    let start = tokio::time::Instant::now() + period.mul_f64(rng.gen());
+    Some(tokio::time::interval_at(start, period))
+}
+
+/// Ticks an optional interval, never finishing if there is none
+async fn tick(interval: &mut Option<tokio::time::Interval>) {
+    match interval {
+        Some(interval) => {
+            interval.tick().await;
+        }
+        None => futures::future::pending().await,
+    }
+}
+
+async fn run_user(
+    options: Arc<Options>,
+    room_id: String,
+    join_options: JoinOptions,
+    index: usize,
+    shared: Arc<Mutex<Shared>>,
+) -> Result<(), ClientError> {
+    let username = format!("loadgen{}", index);
+    let (mut client, mut messages) =
+        netsketch_client::join(&options.server, &room_id, &username, &join_options).await?;
+    let user_id = client.welcome().user_id;
+
+    // Record what's received until the connection closes
+    let reader_shared = shared.clone();
+    let reader = tokio::task::spawn(async move {
+        while let Some(msg) = messages.next_sized().await {
+            let (msg, len) = match msg {
+                Ok(msg) => msg,
+                Err(_) => break,
+            };
+            let received_at = Instant::now();
+            let mut shared = reader_shared.lock().expect("stats poisoned");
+            let shared = &mut *shared;
+            shared
+                .stats
+                .received
+                .entry(server_message_kind(&msg))
+                .or_default()
+                .add(len);
+            match msg {
+                ServerMessage::Sequenced(_, msg) => {
+                    if let ServerMessage::PaintStroke(_, paint_stroke) = *msg {
+                        let sent_at = stroke_key(paint_stroke.user_id, &paint_stroke)
+                            .and_then(|x| shared.sent_strokes.get(&x));
+                        if let Some(sent_at) = sent_at {
+                            shared.stats.fan_out_delays.push(received_at - *sent_at);
+                        }
+                    }
+                }
+                ServerMessage::StrokeAck { client_id, .. } => {
+                    if let Some(sent_at) = shared.pending_acks.remove(&client_id) {
+                        shared.stats.ack_latencies.push(received_at - sent_at);
+                    }
+                }
+                ServerMessage::Error { code, .. } => {
+                    *shared
+                        .stats
+                        .errors
+                        .entry(format!("{:?}", code))
+                        .or_default() += 1;
+                }
+                _ => (),
+            }
+        }
+    });
+
+    let mut rng = StdRng::from_entropy();
+    let mut origin = random_viewport_origin(&mut rng);
+    let mut strokes = interval(&mut rng, options.stroke_rate);
+    let mut pans = interval(&mut rng, options.pan_rate);
+    let deadline = tokio::time::delay_for(options.duration);
+    tokio::pin!(deadline);
+
+    let mut set_viewport = true;
+    loop {
+        let msg = if set_viewport {
+            set_viewport = false;
+            ClientMessage::SetViewPort(
+                origin,
+                Offset {
+                    x: origin.x + VIEWPORT_WIDTH,
+                    y: origin.y + VIEWPORT_HEIGHT,
+                },
+            )
+        } else {
+            tokio::select! {
+                _ = &mut deadline => break,
+                _ = tick(&mut strokes) => {
+                    ClientMessage::PaintStroke(
+                        options.layer,
+                        ClientStrokeId::new_v4(),
+                        random_stroke(&mut rng, origin, options.points),
+                    )
+                }
+                _ = tick(&mut pans) => {
+                    origin = random_viewport_origin(&mut rng);
+                    set_viewport = true;
+                    continue;
+                }
+            }
+        };
+
+        if let ClientMessage::PaintStroke(_, client_id, paint_stroke) = &msg {
+            let mut shared = shared.lock().expect("stats poisoned");
+            let now = Instant::now();
+            shared.pending_acks.insert(*client_id, now);
+            if let Some(key) = stroke_key(user_id, paint_stroke) {
+                shared.sent_strokes.insert(key, now);
+            }
+        }
+        let len = client.send(&msg).await?;
+        shared
+            .lock()
+            .expect("stats poisoned")
+            .stats
+            .sent
+            .entry(client_message_kind(&msg))
+            .or_default()
+            .add(len);
+    }
+
+    client.close().await?;
+    let _ = reader.await;
+    Ok(())
+}
+
+/// Formats the 50th, 90th, 99th percentile and maximum of the durations
+fn percentiles(durations: &mut [Duration]) -> String {
+    if durations.is_empty() {
+        return "no samples".to_string();
+    }
+    durations.sort();
+    let at = |q: f64| {
+        let i = ((durations.len() - 1) as f64 * q).round() as usize;
+        durations[i].as_secs_f64() * 1000.0
+    };
+    format!(
+        "p50 {:.1}ms  p90 {:.1}ms  p99 {:.1}ms  max {:.1}ms  ({} samples)",
+        at(0.5),
+        at(0.9),
+        at(0.99),
+        at(1.0),
+        durations.len()
+    )
+}
+
+fn print_traffic(direction: &str, traffic: &HashMap<&'static str, Traffic>, secs: f64) {
+    println!("{}:", direction);
+    let mut kinds: Vec<_> = traffic.iter().collect();
+    kinds.sort_by_key(|(kind, _)| *kind);
+    for (kind, traffic) in kinds {
+        println!(
+            "  {:<24} {:>8} msgs  {:>8.1} msgs/s  {:>8.1} bytes/msg",
+            kind,
+            traffic.messages,
+            traffic.messages as f64 / secs,
+            traffic.bytes as f64 / traffic.messages as f64
+        );
+    }
+}
+
+#[tokio::main]
+async fn main() {
+    let options = match parse_args() {
+        Ok(options) => Arc::new(options),
+        Err(err) => {
+            eprintln!("{}", err);
+            std::process::exit(2);
+        }
+    };
+
+    let (room_id, token) = match &options.room {
+        Some(room) => room.clone(),
+        None => match create_room(&options.server) {
+            Ok(room) => room,
+            Err(err) => {
+                eprintln!("Unable to create room: {}", err);
+                std::process::exit(1);
+            }
+        },
+    };
+    println!(
+        "Simulating {} users in room {} for {}s",
+        options.users,
+        room_id,
+        options.duration.as_secs()
+    );
+
+    let shared = Arc::new(Mutex::new(Shared::default()));
+    let join_options = JoinOptions {
+        token,
+        ..JoinOptions::default()
+    };
+    let started = Instant::now();
+    let users: Vec<_> = (0..options.users)
+        .map(|index| {
+            let user = run_user(
+                options.clone(),
+                room_id.clone(),
+                join_options.clone(),
+                index,
+                shared.clone(),
+            );
+            let shared = shared.clone();
+            tokio::task::spawn(async move {
+                if let Err(err) = user.await {
+                    eprintln!("User {}: {}", index, err);
+                    shared.lock().expect("stats poisoned").stats.failed_users += 1;
+                }
+            })
+        })
+        .collect();
+    futures::future::join_all(users).await;
+    let secs = started.elapsed().as_secs_f64();
+
+    let mut shared = shared.lock().expect("stats poisoned");
+    let stats = &mut shared.stats;
+    println!(
+        "Stroke acknowledgement: {}",
+        percentiles(&mut stats.ack_latencies)
+    );
+    println!(
+        "Fan-out delay:          {}",
+        percentiles(&mut stats.fan_out_delays)
+    );
+    print_traffic("Sent", &stats.sent, secs);
+    print_traffic("Received", &stats.received, secs);
+    for (code, count) in &stats.errors {
+        println!("Server errors {}: {}", code, count);
+    }
+    if stats.failed_users != 0 {
+        println!("Failed users: {}", stats.failed_users);
+    }
+}
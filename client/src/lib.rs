@@ -0,0 +1,287 @@
+//! Async client for netsketch rooms, for scripting bots, importers and integration tests. Needs
+//! to run on a tokio runtime
+
+use futures::{Sink, SinkExt, Stream, StreamExt};
+use netsketch_shared::prelude::*;
+use std::collections::VecDeque;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use tokio_tungstenite::tungstenite::Error as WsError;
+use tokio_tungstenite::tungstenite::Message as WsMessage;
+use url::Url;
+
+type WsSink = Pin<Box<dyn Sink<WsMessage, Error = WsError> + Send>>;
+type WsStream = Pin<Box<dyn Stream<Item = Result<WsMessage, WsError>> + Send>>;
+
+#[derive(Debug)]
+pub enum ClientError {
+    Url(url::ParseError),
+    WebSocket(WsError),
+    /// Message couldn't be encoded or decoded
+    Codec(String),
+    /// Server closed the connection before welcoming us
+    Closed,
+}
+
+impl std::fmt::Display for ClientError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            ClientError::Url(err) => write!(f, "Invalid URL: {}", err),
+            ClientError::WebSocket(err) => write!(f, "{}", err),
+            ClientError::Codec(err) => write!(f, "ZBincode error: {}", err),
+            ClientError::Closed => write!(f, "Connection closed"),
+        }
+    }
+}
+
+impl std::error::Error for ClientError {}
+
+impl From<url::ParseError> for ClientError {
+    fn from(err: url::ParseError) -> Self {
+        ClientError::Url(err)
+    }
+}
+
+impl From<WsError> for ClientError {
+    fn from(err: WsError) -> Self {
+        ClientError::WebSocket(err)
+    }
+}
+
+/// How to join a room
+#[derive(Debug, Default, Clone)]
+pub struct JoinOptions {
+    /// Editor or viewer token of the room
+    pub token: String,
+    /// Session token of a logged in user. Guests join with the username given to `join`
+    pub session: Option<String>,
+    /// Resume token and last sequence number of a previous connection to pick up where it left
+    /// off
+    pub resume: Option<(String, SequenceNumber)>,
+}
+
+/// Builds the websocket URL of a room on a server such as `ws://localhost:8081`
+pub fn room_url(
+    server: &str,
+    room_id: &str,
+    username: &str,
+    options: &JoinOptions,
+) -> Result<Url, ClientError> {
+    let mut url = Url::parse(server)?;
+    url.path_segments_mut()
+        .map_err(|_| url::ParseError::RelativeUrlWithCannotBeABaseBase)?
+        .pop_if_empty()
+        .extend(&["ws", room_id, username]);
+    {
+        let mut query = url.query_pairs_mut();
+        query.append_pair("token", &options.token);
+        if let Some(session) = &options.session {
+            query.append_pair("session", session);
+        }
+        if let Some((resume_token, seq)) = &options.resume {
+            query.append_pair("resume", resume_token);
+            query.append_pair("seq", &seq.to_string());
+        }
+    }
+    Ok(url)
+}
+
+/// Joins a room, waiting for the server's welcome. Returns a client to send messages with, and
+/// the stream of messages from the server, which can be used from separate tasks
+pub async fn join(
+    server: &str,
+    room_id: &str,
+    username: &str,
+    options: &JoinOptions,
+) -> Result<(Client, Messages), ClientError> {
+    let url = room_url(server, room_id, username, options)?;
+    let (socket, _) = tokio_tungstenite::connect_async(url).await?;
+    let (tx, rx) = socket.split();
+    let mut messages = Messages {
+        rx: Box::pin(rx),
+        pending: VecDeque::new(),
+    };
+
+    // Keep anything sent before the welcome, such as a resync, for the caller
+    let mut early = VecDeque::new();
+    let welcome = loop {
+        match messages.next_sized().await {
+            Some(Ok((ServerMessage::Welcome(welcome), _))) => break welcome,
+            Some(Ok(msg)) => early.push_back(msg),
+            Some(Err(err)) => return Err(err),
+            None => return Err(ClientError::Closed),
+        }
+    };
+    messages.pending = early;
+
+    let client = Client {
+        tx: Box::pin(tx),
+        welcome,
+    };
+    Ok((client, messages))
+}
+
+/// Sending half of a connection to a room
+pub struct Client {
+    tx: WsSink,
+    welcome: Welcome,
+}
+
+impl Client {
+    /// Who the server says we are, and what we may do
+    pub fn welcome(&self) -> &Welcome {
+        &self.welcome
+    }
+
+    /// Sends a message, returning the size of its frame
+    pub async fn send(&mut self, msg: &ClientMessage) -> Result<usize, ClientError> {
+        let data = netsketch_shared::to_zbincode(msg).map_err(ClientError::Codec)?;
+        let len = data.len();
+        self.tx.send(WsMessage::Binary(data)).await?;
+        Ok(len)
+    }
+
+    /// Sets the area we're looking at. The server replies with the layer tree and every element
+    /// in the area, then sends changes to it
+    pub async fn set_viewport(
+        &mut self,
+        upper_left: Offset,
+        lower_right: Offset,
+    ) -> Result<(), ClientError> {
+        self.send(&ClientMessage::SetViewPort(upper_left, lower_right))
+            .await?;
+        Ok(())
+    }
+
+    /// Sends a new paint stroke. Returns the client id it was sent with, which the server's
+    /// `ServerMessage::StrokeAck` carries along with the stroke's id
+    pub async fn send_stroke(
+        &mut self,
+        layer_id: LayerId,
+        paint_stroke: PaintStroke,
+    ) -> Result<ClientStrokeId, ClientError> {
+        let client_id = ClientStrokeId::new_v4();
+        self.send(&ClientMessage::PaintStroke(
+            layer_id,
+            client_id,
+            paint_stroke,
+        ))
+        .await?;
+        Ok(client_id)
+    }
+
+    /// Closes the connection. The stream of messages ends once the server has closed its side
+    pub async fn close(mut self) -> Result<(), ClientError> {
+        self.tx.close().await?;
+        Ok(())
+    }
+}
+
+/// Messages from the server, ending when the connection is closed
+pub struct Messages {
+    rx: WsStream,
+    /// Messages received before the welcome, with the sizes of their frames
+    pending: VecDeque<(ServerMessage, usize)>,
+}
+
+impl Messages {
+    /// Receives the next message along with the size of the frame it arrived in
+    pub async fn next_sized(&mut self) -> Option<Result<(ServerMessage, usize), ClientError>> {
+        futures::future::poll_fn(|cx| self.poll_next_sized(cx)).await
+    }
+
+    fn poll_next_sized(
+        &mut self,
+        cx: &mut Context,
+    ) -> Poll<Option<Result<(ServerMessage, usize), ClientError>>> {
+        if let Some(msg) = self.pending.pop_front() {
+            return Poll::Ready(Some(Ok(msg)));
+        }
+        loop {
+            match self.rx.as_mut().poll_next(cx) {
+                Poll::Ready(Some(Ok(WsMessage::Binary(data)))) => {
+                    let msg = netsketch_shared::from_zbincode(&data)
+                        .map(|msg| (msg, data.len()))
+                        .map_err(ClientError::Codec);
+                    return Poll::Ready(Some(msg));
+                }
+                Poll::Ready(Some(Ok(WsMessage::Close(_)))) | Poll::Ready(None) => {
+                    return Poll::Ready(None);
+                }
+                // Pings are answered by the websocket itself
+                Poll::Ready(Some(Ok(_))) => (),
+                Poll::Ready(Some(Err(err))) => return Poll::Ready(Some(Err(err.into()))),
+                Poll::Pending => return Poll::Pending,
+            }
+        }
+    }
+}
+
+impl Stream for Messages {
+    type Item = Result<ServerMessage, ClientError>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<Option<Self::Item>> {
+        self.poll_next_sized(cx)
+            .map(|x| x.map(|x| x.map(|(msg, _)| msg)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn builds_room_urls() {
+        let url = room_url(
+            "ws://localhost:8081",
+            "abc",
+            "alice",
+            &JoinOptions {
+                token: "t&k".to_string(),
+                ..JoinOptions::default()
+            },
+        )
+        .unwrap();
+        assert_eq!(url.as_str(), "ws://localhost:8081/ws/abc/alice?token=t%26k");
+
+        // Segments are escaped, and a trailing slash doesn't add an empty one
+        let url = room_url(
+            "wss://example.com/sketch/",
+            "abc",
+            "a b/c",
+            &JoinOptions {
+                token: "tok".to_string(),
+                session: Some("s=1".to_string()),
+                resume: Some(("r+1".to_string(), 42)),
+            },
+        )
+        .unwrap();
+        assert_eq!(
+            url.as_str(),
+            "wss://example.com/sketch/ws/abc/a%20b%2Fc?token=tok&session=s%3D1&resume=r%2B1&seq=42"
+        );
+
+        assert!(room_url("not a url", "abc", "alice", &JoinOptions::default()).is_err());
+    }
+
+    #[tokio::test]
+    async fn returns_early_messages_first() {
+        let live = ServerMessage::RemoveElement(0, 2);
+        let data = netsketch_shared::to_zbincode(&live).unwrap();
+        let len = data.len();
+        let frames = vec![
+            Ok(WsMessage::Ping(Vec::new())),
+            Ok(WsMessage::Binary(data)),
+            Ok(WsMessage::Close(None)),
+        ];
+        let early = ServerMessage::RemoveElement(0, 1);
+        let mut messages = Messages {
+            rx: Box::pin(futures::stream::iter(frames)),
+            pending: vec![(early.clone(), 7)].into(),
+        };
+
+        assert_eq!(messages.next_sized().await.unwrap().unwrap(), (early, 7));
+        assert_eq!(messages.next_sized().await.unwrap().unwrap(), (live, len));
+        assert!(messages.next().await.is_none());
+    }
+}
@@ -1,20 +1,33 @@
 use css_in_rust::style::Style;
 use netsketch_shared::*;
+use std::cell::RefCell;
+use std::collections::BTreeMap;
+use std::collections::HashMap;
 use std::time::Duration;
-use wasm_bindgen::JsCast;
-use web_sys::{Element, CanvasRenderingContext2d, HtmlCanvasElement};
-use yew::format::Binary;
+use wasm_bindgen::closure::Closure;
+use wasm_bindgen::{JsCast, JsValue};
+use web_sys::{Element, CanvasRenderingContext2d, HtmlCanvasElement, HtmlImageElement};
+use yew::format::{Binary, Text};
 use yew::prelude::*;
+use yew::services::fetch::{FetchService, FetchTask, Request, Response};
+use yew::services::render::{RenderService, RenderTask};
 use yew::services::resize::{ResizeService, ResizeTask};
 use yew::services::timeout::{TimeoutService, TimeoutTask};
 use yew::services::websocket::{WebSocketService, WebSocketStatus, WebSocketTask};
 use yew::services::ConsoleService;
 
+/// Width of strokes drawn with the erase tool
+const ERASER_WIDTH: f32 = 20.0;
+
 pub struct DrawCanvas {
     /// Yew ComponentLink
     link: ComponentLink<Self>,
-    /// Reference to <canvas> node
+    /// Reference to the node holding the <canvas> layers are composited onto
     canvases_node_ref: NodeRef,
+    /// Offscreen canvas of each layer, by layer id
+    layer_canvases: RefCell<Vec<HtmlCanvasElement>>,
+    /// Pending composite of the layers, once per animation frame
+    composite_task: RefCell<Option<RenderTask>>,
     /// Style
     style: Style,
 
@@ -22,8 +35,12 @@ pub struct DrawCanvas {
     resize: Option<ResizeTask>,
     /// Reference to timeout task
     timeout: Option<TimeoutTask>,
+    /// Pending reconnect after the websocket closes
+    reconnect: Option<TimeoutTask>,
     /// Websocket connection
     websocket: Option<WebSocketTask>,
+    /// Pending login request
+    login_task: Option<FetchTask>,
 
     /// viewport offset
     viewport_offset: Offset,
@@ -41,13 +58,53 @@ pub struct DrawCanvas {
     cur_paint_stroke: PaintStroke,
 
     /// Active layer
-    active_layer: LayerId
+    active_layer: LayerId,
+
+    /// Brush used by the brush tool
+    brush: Brush,
+
+    /// Current object eraser path, in world coordinates
+    stroke_erase_path: Vec<Point>,
+
+    /// Ordering, nesting and visibility of layers
+    layer_tree: LayerTree,
+
+    /// Elements received from server, per layer, kept to redraw layers when elements change
+    elements: Vec<BTreeMap<ElementId, netsketch_shared::Element>>,
+
+    /// Paint strokes sent by this client that the server hasn't acknowledged yet, in the order
+    /// they were drawn. The server doesn't echo them back, so they're kept to redraw and resend
+    pending_strokes: Vec<(ClientStrokeId, LayerId, PaintStroke)>,
+
+    /// Image assets loaded from server, by hash
+    images: HashMap<AssetHash, HtmlImageElement>,
+
+    /// Palettes shared by everyone in the room
+    palettes: BTreeMap<PaletteId, Palette>,
+
+    /// Brush presets shared by everyone in the room
+    brush_presets: BTreeMap<BrushPresetId, BrushPreset>,
+
+    /// Access level granted by the server
+    role: Role,
+
+    /// Read-only link to the room, given to editors to share
+    viewer_link: Option<String>,
+
+    /// Token to resume the connection with after it drops
+    resume_token: Option<String>,
+
+    /// Sequence number of the last room change received
+    last_seq: Option<SequenceNumber>,
 }
 
 pub enum Tool {
     Pan,
     Brush,
     Erase,
+    Text,
+    Fill,
+    StrokeErase,
 }
 
 pub enum Msg {
@@ -60,8 +117,19 @@ pub enum Msg {
     Resize,
     UpdateCanvas(Offset, Offset),
     ToolChange(Tool),
+    RedrawLayer(LayerId),
+    Composite,
+    SelectColor(Color),
+    SelectBrushPreset(BrushPresetId),
+    SaveBrushPreset,
+    Login,
+    LoggedIn(String),
+    Reconnect,
 }
 
+/// Local storage key of the session token
+const SESSION_KEY: &str = "netsketch_session";
+
 impl DrawCanvas {
     fn draw_stroke(&self, layer_id: LayerId, paint_stroke: &PaintStroke) {
         for i in 1..paint_stroke.points.len() {
@@ -73,8 +141,432 @@ impl DrawCanvas {
             );
         }
     }
-    fn draw_line(&self, layer_id: LayerId, _: &Brush, prev_points: &[StrokePoint], cur_point: &StrokePoint) {
+    /// Draws an element received from the server, which is in world coordinates
+    fn draw_element(&self, layer_id: LayerId, element: &netsketch_shared::Element) {
+        if let Some(draw_context) = self.get_draw_context(layer_id) {
+            let _result = draw_context.set_transform(
+                1.0,
+                0.0,
+                0.0,
+                1.0,
+                -self.viewport_offset.x as f64,
+                -self.viewport_offset.y as f64,
+            );
+            match element {
+                netsketch_shared::Element::PaintStroke(paint_stroke) => {
+                    self.draw_stroke(layer_id, paint_stroke)
+                }
+                netsketch_shared::Element::Shape(shape) => self.draw_shape(layer_id, shape),
+                netsketch_shared::Element::TextLabel(text_label) => {
+                    self.draw_text(layer_id, text_label)
+                }
+                netsketch_shared::Element::Image(image) => self.draw_image(layer_id, image),
+                netsketch_shared::Element::Fill(fill) => self.draw_fill(layer_id, fill),
+            }
+            let _result = draw_context.set_transform(1.0, 0.0, 0.0, 1.0, 0.0, 0.0);
+            self.request_composite();
+        }
+    }
+    /// Stores an element received from the server and draws it. If it replaces an existing
+    /// element, the whole layer is redrawn
+    fn store_element(&mut self, layer_id: LayerId, element: netsketch_shared::Element) {
+        if self.elements.len() <= layer_id as usize {
+            self.elements
+                .resize(layer_id as usize + 1, BTreeMap::new());
+        }
+        let replaced = self.elements[layer_id as usize]
+            .insert(element.id(), element.clone())
+            .is_some();
+        if replaced {
+            self.redraw_layer(layer_id);
+        } else {
+            self.draw_element(layer_id, &element);
+        }
+    }
+    /// Requests everything in the current viewport from the server
+    fn request_viewport(&self) {
+        if let Some(canvas_parent) = self.canvases_node_ref.cast::<Element>() {
+            let upper_left = self.viewport_offset;
+            let lower_right = self.viewport_offset
+                + Offset {
+                    x: canvas_parent.client_width(),
+                    y: canvas_parent.client_height(),
+                };
+            self.link
+                .send_message(Msg::UpdateCanvas(upper_left, lower_right));
+        }
+    }
+    /// Clears a layer and draws all known elements on it again
+    fn redraw_layer(&self, layer_id: LayerId) {
         let canvas = match self.get_canvas(layer_id) {
+            Some(canvas) => canvas,
+            None => return,
+        };
+        if let Some(draw_context) = self.get_draw_context(layer_id) {
+            draw_context.clear_rect(0.0, 0.0, canvas.width() as f64, canvas.height() as f64);
+            self.request_composite();
+        }
+        if let Some(elements) = self.elements.get(layer_id as usize) {
+            for element in elements.values() {
+                self.draw_element(layer_id, element);
+            }
+        }
+        for (_, stroke_layer_id, paint_stroke) in &self.pending_strokes {
+            if *stroke_layer_id == layer_id {
+                self.draw_element(
+                    layer_id,
+                    &netsketch_shared::Element::PaintStroke(paint_stroke.clone()),
+                );
+            }
+        }
+    }
+    fn draw_text(&self, layer_id: LayerId, text_label: &TextLabel) {
+        let draw_context = match self.get_draw_context(layer_id) {
+            Some(draw_context) => draw_context,
+            None => {
+                ConsoleService::error("Error getting drawing context");
+                return;
+            }
+        };
+        draw_context.save();
+        let _result = draw_context
+            .translate(text_label.position.x as f64, text_label.position.y as f64);
+        let _result = draw_context.rotate(text_label.rotation as f64);
+        draw_context.set_font(&format!("{}px sans-serif", text_label.font_size));
+        draw_context.set_text_baseline("top");
+        draw_context.set_fill_style(&JsValue::from_str(&color_to_css(&text_label.color)));
+        for (i, line) in text_label.content.lines().enumerate() {
+            let _result =
+                draw_context.fill_text(line, 0.0, i as f64 * text_label.font_size as f64);
+        }
+        draw_context.restore();
+    }
+    fn draw_image(&self, layer_id: LayerId, image: &PlacedImage) {
+        let draw_context = match self.get_draw_context(layer_id) {
+            Some(draw_context) => draw_context,
+            None => {
+                ConsoleService::error("Error getting drawing context");
+                return;
+            }
+        };
+        // Images still loading are drawn once their onload handler redraws the layer
+        let html_image = match self.images.get(&image.asset) {
+            Some(html_image) if html_image.complete() => html_image,
+            _ => return,
+        };
+        draw_context.save();
+        let _result = draw_context.translate(image.position.x as f64, image.position.y as f64);
+        let _result = draw_context.rotate(image.rotation as f64);
+        let _result = draw_context.draw_image_with_html_image_element_and_dw_and_dh(
+            html_image,
+            0.0,
+            0.0,
+            image.width as f64 * image.scale as f64,
+            image.height as f64 * image.scale as f64,
+        );
+        draw_context.restore();
+    }
+    fn draw_fill(&self, layer_id: LayerId, fill: &netsketch_shared::Fill) {
+        let draw_context = match self.get_draw_context(layer_id) {
+            Some(draw_context) => draw_context,
+            None => {
+                ConsoleService::error("Error getting drawing context");
+                return;
+            }
+        };
+        // Draw spans as a single path so translucent fills aren't blended twice at the seams
+        draw_context.begin_path();
+        for span in &fill.spans {
+            draw_context.rect(
+                span.x_start as f64,
+                span.y as f64,
+                (span.x_end - span.x_start) as f64,
+                1.0,
+            );
+        }
+        draw_context.set_fill_style(&JsValue::from_str(&color_to_css(&fill.color)));
+        draw_context.fill();
+    }
+    /// Starts loading an image asset if it isn't already, redrawing the layer once loaded
+    fn load_image(&mut self, layer_id: LayerId, asset: &AssetHash) {
+        if self.images.contains_key(asset) {
+            return;
+        }
+        let html_image = match HtmlImageElement::new() {
+            Ok(html_image) => html_image,
+            Err(_) => {
+                ConsoleService::error("Error creating image");
+                return;
+            }
+        };
+        let link = self.link.clone();
+        let onload = Closure::once_into_js(move || link.send_message(Msg::RedrawLayer(layer_id)));
+        html_image.set_onload(Some(onload.unchecked_ref()));
+        html_image.set_src(&format!("/assets/{}", asset));
+        self.images.insert(asset.clone(), html_image);
+    }
+    /// Asks for credentials and requests a session token from the server
+    fn login(&mut self) {
+        let window = match web_sys::window() {
+            Some(window) => window,
+            None => return,
+        };
+        let username = match window.prompt_with_message("Username") {
+            Ok(Some(username)) if !username.is_empty() => username,
+            _ => return,
+        };
+        let password = match window.prompt_with_message("Password") {
+            Ok(Some(password)) => password,
+            _ => return,
+        };
+
+        let body = format!(
+            "username={}&password={}",
+            url_encode(&username),
+            url_encode(&password)
+        );
+        let request = match Request::post("/login")
+            .header("Content-Type", "application/x-www-form-urlencoded")
+            .body(Ok(body))
+        {
+            Ok(request) => request,
+            Err(err) => {
+                ConsoleService::error(&err.to_string());
+                return;
+            }
+        };
+        let callback = self.link.callback(|response: Response<Text>| {
+            let ok = response.status().is_success();
+            match response.into_body() {
+                Ok(body) if ok => Msg::LoggedIn(body),
+                Ok(body) => Msg::ErrMsg(body),
+                Err(err) => Msg::ErrMsg(err.to_string()),
+            }
+        });
+        match FetchService::fetch(request, callback) {
+            Ok(task) => self.login_task = Some(task),
+            Err(err) => ConsoleService::error(&err.to_string()),
+        }
+    }
+    fn view_tools(&self) -> Html {
+        html! {
+            <div>
+                <button onclick=self.link.callback(|_|Msg::ToolChange(Tool::Pan))>{"Pan"}</button>
+                <button onclick=self.link.callback(|_|Msg::ToolChange(Tool::Brush))>{"Brush"}</button>
+                <button onclick=self.link.callback(|_|Msg::ToolChange(Tool::Erase))>{"Erase"}</button>
+                <button onclick=self.link.callback(|_|Msg::ToolChange(Tool::StrokeErase))>{"Erase strokes"}</button>
+                <button onclick=self.link.callback(|_|Msg::ToolChange(Tool::Text))>{"Text"}</button>
+                <button onclick=self.link.callback(|_|Msg::ToolChange(Tool::Fill))>{"Fill"}</button>
+            </div>
+        }
+    }
+    fn view_palettes(&self) -> Html {
+        html! {
+            <div>
+                { for self.palettes.values().flat_map(|palette| palette.colors.iter()).map(|color| {
+                    let color = *color;
+                    html! {
+                        <button
+                            style=format!("background-color: {}", color_to_css(&color))
+                            onclick=self.link.callback(move |_| Msg::SelectColor(color))
+                        >{"\u{a0}"}</button>
+                    }
+                }) }
+            </div>
+        }
+    }
+    fn view_brush_presets(&self) -> Html {
+        html! {
+            <div>
+                { for self.brush_presets.values().map(|brush_preset| {
+                    let brush_preset_id = brush_preset.id;
+                    html! {
+                        <button onclick=self.link.callback(move |_| Msg::SelectBrushPreset(brush_preset_id))>
+                            { &brush_preset.name }
+                        </button>
+                    }
+                }) }
+                <button onclick=self.link.callback(|_|Msg::SaveBrushPreset)>{"Save brush"}</button>
+            </div>
+        }
+    }
+    fn view_viewer_link(&self) -> Html {
+        match &self.viewer_link {
+            Some(viewer_link) => html! {
+                <div>{"Read-only link: "}<a href=viewer_link.clone()>{ viewer_link }</a></div>
+            },
+            None => html! {},
+        }
+    }
+    /// Asks for a name and shares the current brush with the room as a preset
+    fn save_brush_preset(&mut self) {
+        let window = match web_sys::window() {
+            Some(window) => window,
+            None => return,
+        };
+        let name = match window.prompt_with_message("Preset name") {
+            Ok(Some(name)) if !name.is_empty() => name,
+            _ => return,
+        };
+        let msg = ClientMessage::AddBrushPreset(BrushPreset {
+            id: 0,
+            name,
+            brush: self.brush.clone(),
+        });
+        self.send_msg(&msg);
+    }
+    /// Places a new text label at a screen position, or edits the label already there
+    fn place_text(&mut self, position: Point) {
+        let position = position + self.viewport_offset;
+        let window = match web_sys::window() {
+            Some(window) => window,
+            None => return,
+        };
+
+        // Look for an existing label under the pointer to edit
+        let existing = self.elements.get(self.active_layer as usize).and_then(|elements| {
+            elements.values().rev().find_map(|element| match element {
+                netsketch_shared::Element::TextLabel(text_label) => {
+                    let (upper_left, lower_right) = text_label.bounding_box();
+                    if position.x >= upper_left.x
+                        && position.x <= lower_right.x
+                        && position.y >= upper_left.y
+                        && position.y <= lower_right.y
+                    {
+                        Some(text_label.clone())
+                    } else {
+                        None
+                    }
+                }
+                _ => None,
+            })
+        });
+
+        let default = existing
+            .as_ref()
+            .map(|x| x.content.clone())
+            .unwrap_or_default();
+        let content = match window.prompt_with_message_and_default("Text", &default) {
+            Ok(Some(content)) if !content.is_empty() => content,
+            _ => return,
+        };
+
+        let msg = match existing {
+            Some(text_label) => ClientMessage::EditTextLabel(
+                self.active_layer,
+                TextLabel {
+                    content,
+                    ..text_label
+                },
+            ),
+            None => ClientMessage::TextLabel(
+                self.active_layer,
+                TextLabel {
+                    position,
+                    content,
+                    ..TextLabel::default()
+                },
+            ),
+        };
+        self.send_msg(&msg);
+    }
+    fn send_msg(&mut self, msg: &ClientMessage) {
+        if let Some(ws) = self.websocket.as_mut() {
+            match netsketch_shared::to_zbincode(msg) {
+                Ok(data) => {
+                    ws.send_binary(Ok(data));
+                }
+                Err(err) => ConsoleService::error(&err.to_string()),
+            };
+        }
+    }
+    fn draw_shape(&self, layer_id: LayerId, shape: &Shape) {
+        let draw_context = match self.get_draw_context(layer_id) {
+            Some(draw_context) => draw_context,
+            None => {
+                ConsoleService::error("Error getting drawing context");
+                return;
+            }
+        };
+
+        draw_context.begin_path();
+        match &shape.kind {
+            ShapeKind::Line { from, to } => {
+                draw_context.move_to(from.x as f64, from.y as f64);
+                draw_context.line_to(to.x as f64, to.y as f64);
+            }
+            ShapeKind::Rectangle {
+                upper_left,
+                lower_right,
+            } => {
+                draw_context.rect(
+                    upper_left.x as f64,
+                    upper_left.y as f64,
+                    (lower_right.x - upper_left.x) as f64,
+                    (lower_right.y - upper_left.y) as f64,
+                );
+            }
+            ShapeKind::Ellipse {
+                center,
+                radius_x,
+                radius_y,
+            } => {
+                let _result = draw_context.ellipse(
+                    center.x as f64,
+                    center.y as f64,
+                    radius_x.abs() as f64,
+                    radius_y.abs() as f64,
+                    0.0,
+                    0.0,
+                    2.0 * std::f64::consts::PI,
+                );
+            }
+            ShapeKind::Polygon { points } => {
+                for (i, point) in points.iter().enumerate() {
+                    if i == 0 {
+                        draw_context.move_to(point.x as f64, point.y as f64);
+                    } else {
+                        draw_context.line_to(point.x as f64, point.y as f64);
+                    }
+                }
+                draw_context.close_path();
+            }
+            ShapeKind::Arrow {
+                from,
+                to,
+                head_size,
+            } => {
+                draw_context.move_to(from.x as f64, from.y as f64);
+                draw_context.line_to(to.x as f64, to.y as f64);
+
+                // Draw arrowhead as two lines angled back from the tip
+                let angle = ((to.y - from.y) as f64).atan2((to.x - from.x) as f64);
+                for side in &[-1.0, 1.0] {
+                    let head_angle = angle + side * std::f64::consts::FRAC_PI_6;
+                    draw_context.move_to(to.x as f64, to.y as f64);
+                    draw_context.line_to(
+                        to.x as f64 - *head_size as f64 * head_angle.cos(),
+                        to.y as f64 - *head_size as f64 * head_angle.sin(),
+                    );
+                }
+            }
+        }
+
+        let closed = !matches!(shape.kind, ShapeKind::Line { .. } | ShapeKind::Arrow { .. });
+        if let (true, Some(fill)) = (closed, &shape.fill) {
+            draw_context.set_fill_style(&JsValue::from_str(&color_to_css(&fill.color)));
+            draw_context.fill();
+        }
+        if let Some(stroke) = &shape.stroke {
+            draw_context.set_stroke_style(&JsValue::from_str(&color_to_css(&stroke.color)));
+            draw_context.set_line_width(stroke.width as f64);
+            draw_context.set_line_join("round");
+            draw_context.stroke();
+        }
+    }
+    fn draw_line(&self, layer_id: LayerId, brush: &Brush, prev_points: &[StrokePoint], cur_point: &StrokePoint) {
+        // Pointer positions are relative to the visible canvas, which layers are the size of
+        let canvas = match self.view_canvas() {
             Some(canvas) => canvas,
             None => {
                 ConsoleService::error("Error getting canvas");
@@ -113,11 +605,29 @@ impl DrawCanvas {
 
         draw_context.begin_path();
         draw_context.set_line_join("round");
-        draw_context.set_line_width((2.0 * (from_point.p + to_point.p) / 2.0) as f64);
+        draw_context.set_line_cap("round");
+        // Brush width at the segment's average pressure, so a full pressure line is as wide as
+        // the server rasterizes it
+        draw_context.set_line_width((brush.width * (from_point.p + to_point.p) / 2.0) as f64);
         draw_context.move_to(from_point.x as f64, from_point.y as f64);
         draw_context.line_to(to_point.x as f64, to_point.y as f64);
-        draw_context.stroke();
+        if brush.replace {
+            // Clear pixels underneath first using an opaque color, since destination-out only
+            // removes as much as the source alpha. Then paint brush color if it isn't transparent
+            draw_context.set_stroke_style(&JsValue::from_str("black"));
+            let _result = draw_context.set_global_composite_operation("destination-out");
+            draw_context.stroke();
+            let _result = draw_context.set_global_composite_operation("source-over");
+            if brush.color.a > 0 {
+                draw_context.set_stroke_style(&JsValue::from_str(&color_to_css(&brush.color)));
+                draw_context.stroke();
+            }
+        } else {
+            draw_context.set_stroke_style(&JsValue::from_str(&color_to_css(&brush.color)));
+            draw_context.stroke();
+        }
         draw_context.close_path();
+        self.request_composite();
     }
     fn ws_connect(&mut self) {
         let callback = self.link.callback(|data: Binary| {
@@ -137,7 +647,12 @@ impl DrawCanvas {
             .link
             .callback(|data: WebSocketStatus| Msg::WsAction(data));
 
-        if let Ok(wsaddr) = get_wsaddr() {
+        // Resume where the last connection left off, if there was one
+        let resume = match (&self.resume_token, self.last_seq) {
+            (Some(resume_token), Some(last_seq)) => Some((resume_token.as_str(), last_seq)),
+            _ => None,
+        };
+        if let Ok(wsaddr) = get_wsaddr(resume) {
             self.websocket = Some(
                 WebSocketService::connect_binary(&wsaddr, callback, notification)
                     .expect("Unable to connect to websocket"),
@@ -146,22 +661,135 @@ impl DrawCanvas {
             ConsoleService::error("Unable to determine websocket host");
         }
     }
-    fn get_canvas(&self, layer_id: LayerId) -> Option<Box<HtmlCanvasElement>>{
+    /// Gets the visible canvas layers are composited onto
+    fn view_canvas(&self) -> Option<HtmlCanvasElement> {
         let node = self.canvases_node_ref.get()?;
         let element = node.dyn_into::<Element>().ok()?;
-        let layers = element.children();
-    
-        let canvas = layers.item(layer_id as u32)?;
+        let canvas = element.children().item(0)?;
+        canvas.dyn_into::<HtmlCanvasElement>().ok()
+    }
+    /// Creates a transparent offscreen canvas the size of the visible canvas
+    fn create_offscreen_canvas(&self) -> Option<HtmlCanvasElement> {
+        let view_canvas = self.view_canvas()?;
+        let document = web_sys::window()?.document()?;
+        let canvas = document.create_element("canvas").ok()?;
         let canvas = canvas.dyn_into::<HtmlCanvasElement>().ok()?;
-        Some(Box::new(canvas))
+        canvas.set_width(view_canvas.width());
+        canvas.set_height(view_canvas.height());
+        Some(canvas)
+    }
+    /// Gets the offscreen canvas of a layer, creating it if needed
+    fn get_canvas(&self, layer_id: LayerId) -> Option<Box<HtmlCanvasElement>>{
+        let mut layer_canvases = self.layer_canvases.borrow_mut();
+        while layer_canvases.len() <= layer_id as usize {
+            layer_canvases.push(self.create_offscreen_canvas()?);
+        }
+        Some(Box::new(layer_canvases[layer_id as usize].clone()))
     }
     fn get_draw_context(&self, layer_id: LayerId) -> Option<Box<CanvasRenderingContext2d>>{
         let canvas = self.get_canvas(layer_id)?;
-        let draw_context = canvas.get_context("2d").ok()?;
-        let draw_context = draw_context?.dyn_into::<CanvasRenderingContext2d>().ok()?;
-        Some(Box::new(draw_context))
+        Some(Box::new(canvas_context(&canvas)?))
+    }
+    /// Composites the layers onto the visible canvas on the next animation frame, if it isn't
+    /// already going to be
+    fn request_composite(&self) {
+        let mut composite_task = self.composite_task.borrow_mut();
+        if composite_task.is_none() {
+            let cb = self.link.callback(|_| Msg::Composite);
+            *composite_task = Some(RenderService::request_animation_frame(cb));
+        }
+    }
+    /// Draws the layers onto the visible canvas in the order given by the layer tree
+    fn composite(&self) {
+        let view_canvas = match self.view_canvas() {
+            Some(view_canvas) => view_canvas,
+            None => return,
+        };
+        let draw_context = match canvas_context(&view_canvas) {
+            Some(draw_context) => draw_context,
+            None => return,
+        };
+        // The layer being drawn on is shown before the server adds it to the tree, on top as
+        // the server will add it
+        let mut layer_tree = self.layer_tree.clone();
+        layer_tree.insert_layer(self.active_layer);
+        let image = layer_tree.composite(&mut CanvasCompositor { draw_canvas: self });
+
+        draw_context.clear_rect(0.0, 0.0, view_canvas.width() as f64, view_canvas.height() as f64);
+        if let Some(image) = image {
+            let _result = draw_context.draw_image_with_html_canvas_element(&image, 0.0, 0.0);
+        }
+    }
+}
+
+/// Composites layers with offscreen canvases
+struct CanvasCompositor<'a> {
+    draw_canvas: &'a DrawCanvas,
+}
+
+impl Compositor for CanvasCompositor<'_> {
+    type Buffer = Option<HtmlCanvasElement>;
+
+    fn create_buffer(&mut self) -> Self::Buffer {
+        self.draw_canvas.create_offscreen_canvas()
+    }
+
+    fn draw_layer(
+        &mut self,
+        onto: &mut Self::Buffer,
+        layer_id: LayerId,
+        opacity: f32,
+        clip_to: Option<LayerId>,
+    ) {
+        let layer_canvas = match self.draw_canvas.get_canvas(layer_id) {
+            Some(layer_canvas) => layer_canvas,
+            None => return,
+        };
+        let layer_canvas = match clip_to {
+            Some(clip_to) => {
+                // Keep only the layer's pixels where the clip base has some, scaled by its alpha
+                let clipped = match (self.create_buffer(), self.draw_canvas.get_canvas(clip_to)) {
+                    (Some(clipped), Some(base_canvas)) => match canvas_context(&clipped) {
+                        Some(draw_context) => {
+                            let _result = draw_context
+                                .draw_image_with_html_canvas_element(&layer_canvas, 0.0, 0.0);
+                            let _result =
+                                draw_context.set_global_composite_operation("destination-in");
+                            let _result = draw_context
+                                .draw_image_with_html_canvas_element(&base_canvas, 0.0, 0.0);
+                            clipped
+                        }
+                        None => return,
+                    },
+                    _ => return,
+                };
+                Box::new(clipped)
+            }
+            None => layer_canvas,
+        };
+        draw_with_opacity(onto, &layer_canvas, opacity);
+    }
+
+    fn draw_buffer(&mut self, onto: &mut Self::Buffer, buffer: Self::Buffer, opacity: f32) {
+        if let Some(buffer) = buffer {
+            draw_with_opacity(onto, &buffer, opacity);
+        }
     }
 }
+
+/// Draws a canvas with source-over onto another, at an opacity
+fn draw_with_opacity(onto: &Option<HtmlCanvasElement>, canvas: &HtmlCanvasElement, opacity: f32) {
+    if let Some(draw_context) = onto.as_ref().and_then(canvas_context) {
+        draw_context.set_global_alpha(opacity as f64);
+        let _result = draw_context.draw_image_with_html_canvas_element(canvas, 0.0, 0.0);
+        draw_context.set_global_alpha(1.0);
+    }
+}
+
+fn canvas_context(canvas: &HtmlCanvasElement) -> Option<CanvasRenderingContext2d> {
+    let draw_context = canvas.get_context("2d").ok()?;
+    draw_context?.dyn_into::<CanvasRenderingContext2d>().ok()
+}
 impl Component for DrawCanvas {
     type Message = Msg;
     type Properties = ();
@@ -169,11 +797,15 @@ impl Component for DrawCanvas {
         Self {
             link,
             canvases_node_ref: NodeRef::default(),
+            layer_canvases: RefCell::new(Vec::new()),
+            composite_task: RefCell::new(None),
             style: get_style(),
 
             resize: None,
             timeout: None,
             websocket: None,
+            reconnect: None,
+            login_task: None,
 
             viewport_offset: Offset::default(),
 
@@ -185,7 +817,31 @@ impl Component for DrawCanvas {
 
             cur_paint_stroke: PaintStroke::default(),
 
-            active_layer: 0
+            active_layer: 0,
+
+            brush: Brush::default(),
+
+            stroke_erase_path: Vec::new(),
+
+            layer_tree: LayerTree::default(),
+
+            elements: Vec::new(),
+
+            pending_strokes: Vec::new(),
+
+            images: HashMap::new(),
+
+            palettes: BTreeMap::new(),
+
+            brush_presets: BTreeMap::new(),
+
+            role: Role::Viewer,
+
+            viewer_link: None,
+
+            resume_token: None,
+
+            last_seq: None,
         }
     }
 
@@ -206,6 +862,12 @@ impl Component for DrawCanvas {
                 self.pointer_down = true;
                 match self.tool {
                     Tool::Brush | Tool::Erase => {
+                        // Erase strokes are ordinary paint strokes with a replacing transparent
+                        // brush
+                        self.cur_paint_stroke.brush = match self.tool {
+                            Tool::Erase => Brush::eraser(ERASER_WIDTH),
+                            _ => self.brush.clone(),
+                        };
                         let cur_point = StrokePoint {
                             p: event.pressure(),
                             x: event.offset_x(),
@@ -219,6 +881,15 @@ impl Component for DrawCanvas {
                             y: event.offset_y(),
                         }
                     }
+                    Tool::StrokeErase => {
+                        self.stroke_erase_path = vec![
+                            Point {
+                                x: event.offset_x(),
+                                y: event.offset_y(),
+                            } + self.viewport_offset,
+                        ];
+                    }
+                    Tool::Text | Tool::Fill => {}
                 }
             }
             Msg::PointerMove(event) => {
@@ -246,6 +917,15 @@ impl Component for DrawCanvas {
 
                             ConsoleService::log(&format!("{:?}",self.viewport_offset));
                         }
+                        Tool::StrokeErase => {
+                            self.stroke_erase_path.push(
+                                Point {
+                                    x: event.offset_x(),
+                                    y: event.offset_y(),
+                                } + self.viewport_offset,
+                            );
+                        }
+                        Tool::Text | Tool::Fill => {}
                     }
                 }
             }
@@ -276,57 +956,193 @@ impl Component for DrawCanvas {
                             points: Vec::new(),
                         };
                         //Send paint stroke to server
-                        if let Some(ws) = self.websocket.as_mut() {
-                            let zbincode_msg =
-                                netsketch_shared::to_zbincode(&ClientMessage::PaintStroke(
-                                    0,
-                                    std::mem::replace(&mut self.cur_paint_stroke, new_stroke),
-                                ));
-                            match zbincode_msg {
-                                Ok(data) => {
-                                    ws.send_binary(Ok(data));
-                                }
-                                Err(err) => ConsoleService::error(&err.to_string()),
-                            };
-                        } else {
-                            self.cur_paint_stroke = new_stroke;
-                        }
+                        let paint_stroke =
+                            std::mem::replace(&mut self.cur_paint_stroke, new_stroke);
+                        let client_id = ClientStrokeId::new_v4();
+                        self.send_msg(&ClientMessage::PaintStroke(
+                            self.active_layer,
+                            client_id,
+                            paint_stroke.clone(),
+                        ));
+                        self.pending_strokes
+                            .push((client_id, self.active_layer, paint_stroke));
+                    }
+                    Tool::Text => {
+                        self.place_text(Point {
+                            x: event.offset_x(),
+                            y: event.offset_y(),
+                        });
+                    }
+                    Tool::StrokeErase => {
+                        let path = std::mem::take(&mut self.stroke_erase_path);
+                        self.send_msg(&ClientMessage::StrokeErase(
+                            self.active_layer,
+                            StrokeErase {
+                                path,
+                                width: ERASER_WIDTH,
+                                mode: StrokeEraseMode::Split,
+                            },
+                        ));
+                    }
+                    Tool::Fill => {
+                        let seed = Point {
+                            x: event.offset_x(),
+                            y: event.offset_y(),
+                        } + self.viewport_offset;
+                        self.send_msg(&ClientMessage::FloodFill(
+                            self.active_layer,
+                            FloodFill {
+                                seed,
+                                tolerance: 32,
+                                color: self.brush.color,
+                            },
+                        ));
                     }
                     _ => {}
                 }
             }
             Msg::WsReady(server_message) => match server_message {
+                ServerMessage::Sequenced(seq, server_message) => {
+                    self.last_seq = Some(seq);
+                    return self.update(Msg::WsReady(*server_message));
+                }
+                ServerMessage::Resync => {
+                    // Start over from the current state of the room. Pending strokes are kept,
+                    // to be resent after the welcome
+                    self.last_seq = None;
+                    for elements in &mut self.elements {
+                        elements.clear();
+                    }
+                    for layer_id in 0..self.elements.len() {
+                        self.redraw_layer(layer_id as LayerId);
+                    }
+                    self.palettes.clear();
+                    self.brush_presets.clear();
+                    self.request_viewport();
+                }
+                ServerMessage::Restarting => {
+                    // Unacknowledged strokes are resent once reconnected
+                    ConsoleService::info("Server restarting, reconnecting");
+                }
+                ServerMessage::Welcome(welcome) => {
+                    // Keep counting from the last change seen when resuming
+                    if self.last_seq.is_none() {
+                        self.last_seq = Some(welcome.seq);
+                    }
+                    self.resume_token = Some(welcome.resume_token);
+
+                    // Resend strokes that may not have arrived. Ones that did are only
+                    // acknowledged again
+                    let pending: Vec<ClientMessage> = self
+                        .pending_strokes
+                        .iter()
+                        .map(|(client_id, layer_id, paint_stroke)| {
+                            ClientMessage::PaintStroke(*layer_id, *client_id, paint_stroke.clone())
+                        })
+                        .collect();
+                    for msg in &pending {
+                        self.send_msg(msg);
+                    }
+                    self.role = welcome.role;
+                    if self.role == Role::Viewer {
+                        self.tool = Tool::Pan;
+                    }
+                    self.viewer_link = welcome.viewer_token.and_then(|x| get_viewer_link(&x).ok());
+                    return true;
+                }
+                ServerMessage::Error { code, message } => {
+                    ConsoleService::error(&format!("Server error {:?}: {}", code, message));
+                }
+                ServerMessage::StrokeAck {
+                    client_id,
+                    id,
+                    layer,
+                } => {
+                    // Keep the stroke as a regular element now that its id is known
+                    if let Some(i) = self.pending_strokes.iter().position(|x| x.0 == client_id) {
+                        let (_, _, mut paint_stroke) = self.pending_strokes.remove(i);
+                        paint_stroke.id = id;
+                        if self.elements.len() <= layer as usize {
+                            self.elements.resize(layer as usize + 1, BTreeMap::new());
+                        }
+                        self.elements[layer as usize]
+                            .insert(id, netsketch_shared::Element::PaintStroke(paint_stroke));
+                    }
+                }
                 ServerMessage::PaintStroke(layer, paint_stroke) => {
-                   // paint_stroke.shift(&-self.viewport_offset);
-                    
-                    if let Some(draw_context) = self.get_draw_context(layer){
-                        let _result = draw_context.set_transform(
-                            1.0,
-                            0.0,
-                            0.0,
-                            1.0,
-                            -self.viewport_offset.x as f64,
-                            -self.viewport_offset.y as f64,
-                        );
-                        self.draw_stroke(layer, &paint_stroke);
-                        let _result = draw_context.set_transform(
-                            1.0,
-                            0.0,
-                            0.0,
-                            1.0,
-                            0.0,
-                            0.0,
-                        );
+                    self.store_element(layer, netsketch_shared::Element::PaintStroke(paint_stroke));
+                }
+                ServerMessage::Shape(layer, shape) => {
+                    self.store_element(layer, netsketch_shared::Element::Shape(shape));
+                }
+                ServerMessage::TextLabel(layer, text_label) => {
+                    self.store_element(layer, netsketch_shared::Element::TextLabel(text_label));
+                }
+                ServerMessage::Image(layer, image) => {
+                    self.load_image(layer, &image.asset);
+                    self.store_element(layer, netsketch_shared::Element::Image(image));
+                }
+                ServerMessage::Fill(layer, fill) => {
+                    self.store_element(layer, netsketch_shared::Element::Fill(fill));
+                }
+                ServerMessage::RemoveElement(layer, element_id) => {
+                    if let Some(elements) = self.elements.get_mut(layer as usize) {
+                        elements.remove(&element_id);
+                    }
+                    self.redraw_layer(layer);
+                }
+                ServerMessage::ReplaceStroke(layer, element_id, pieces) => {
+                    if let Some(elements) = self.elements.get_mut(layer as usize) {
+                        elements.remove(&element_id);
+                        for piece in pieces {
+                            elements.insert(piece.id, netsketch_shared::Element::PaintStroke(piece));
+                        }
                     }
+                    self.redraw_layer(layer);
+                }
+                ServerMessage::Palette(palette) => {
+                    self.palettes.insert(palette.id, palette);
+                    return true;
+                }
+                ServerMessage::RemovePalette(palette_id) => {
+                    self.palettes.remove(&palette_id);
+                    return true;
+                }
+                ServerMessage::BrushPreset(brush_preset) => {
+                    self.brush_presets.insert(brush_preset.id, brush_preset);
+                    return true;
+                }
+                ServerMessage::RemoveBrushPreset(brush_preset_id) => {
+                    self.brush_presets.remove(&brush_preset_id);
+                    return true;
+                }
+                ServerMessage::LayerTree(layer_tree) => {
+                    self.layer_tree = layer_tree;
+                    self.request_composite();
                 }
                 _ => (),
             },
             Msg::WsAction(status) => match status {
                 WebSocketStatus::Opened => {
-                    self.link.send_message(Msg::Resize);
+                    // A resumed connection already has its viewport
+                    if self.resume_token.is_none() {
+                        self.link.send_message(Msg::Resize);
+                    }
+                }
+                WebSocketStatus::Closed | WebSocketStatus::Error => {
+                    // Forget session if the first connection failed, in case it expired, so the
+                    // next attempt joins as a guest
+                    if self.resume_token.is_none() {
+                        if let Some(window) = web_sys::window() {
+                            if let Ok(Some(storage)) = window.local_storage() {
+                                let _result = storage.remove_item(SESSION_KEY);
+                            }
+                        }
+                    }
+                    self.websocket = None;
+                    let cb = self.link.callback(|_| Msg::Reconnect);
+                    self.reconnect = Some(TimeoutService::spawn(Duration::from_secs(1), cb));
                 }
-                //TODO If closed, reconnect
-                _ => (),
             },
             Msg::ErrMsg(errstring) => {
                 ConsoleService::error(&errstring);
@@ -345,6 +1161,12 @@ impl Component for DrawCanvas {
                         }
 
                     }
+                    // Resizing clears the layers, they're redrawn as the viewport is sent again
+                    for canvas in self.layer_canvases.borrow().iter() {
+                        canvas.set_width(width as u32);
+                        canvas.set_height(height as u32);
+                    }
+                    self.request_composite();
                     self.viewport_offset = Offset {
                         x: -width / 2,
                         y: -height / 2,
@@ -379,6 +1201,41 @@ impl Component for DrawCanvas {
             Msg::ToolChange(tool) => {
                 self.tool = tool;
             }
+            Msg::RedrawLayer(layer_id) => {
+                self.redraw_layer(layer_id);
+            }
+            Msg::Composite => {
+                self.composite_task.borrow_mut().take();
+                self.composite();
+            }
+            Msg::SelectColor(color) => {
+                self.brush.color = color;
+            }
+            Msg::SelectBrushPreset(brush_preset_id) => {
+                if let Some(brush_preset) = self.brush_presets.get(&brush_preset_id) {
+                    self.brush = brush_preset.brush.clone();
+                }
+            }
+            Msg::SaveBrushPreset => {
+                self.save_brush_preset();
+            }
+            Msg::Login => {
+                self.login();
+            }
+            Msg::Reconnect => {
+                self.reconnect = None;
+                self.ws_connect();
+            }
+            Msg::LoggedIn(session) => {
+                // Reconnect as the logged in user
+                self.login_task = None;
+                if let Some(window) = web_sys::window() {
+                    if let Ok(Some(storage)) = window.local_storage() {
+                        let _result = storage.set_item(SESSION_KEY, &session);
+                    }
+                    let _result = window.location().reload();
+                }
+            }
         };
         false
     }
@@ -391,13 +1248,27 @@ impl Component for DrawCanvas {
     }
 
     fn view(&self) -> Html {
-        html! {
-            <div class=self.style.clone()>
+        // Viewers only get the canvas to look around
+        let toolbar = match self.role {
+            Role::Viewer => html! {
                 <div>
-                    <button onclick=self.link.callback(|_|Msg::ToolChange(Tool::Pan))>{"Pan"}</button>
-                    <button onclick=self.link.callback(|_|Msg::ToolChange(Tool::Brush))>{"Brush"}</button>
-                    <button onclick=self.link.callback(|_|Msg::ToolChange(Tool::Erase))>{"Erase"}</button>
+                    {"View only"}
+                    <button onclick=self.link.callback(|_|Msg::Login)>{"Log in"}</button>
                 </div>
+            },
+            Role::Editor => html! {
+                <div>
+                    <button onclick=self.link.callback(|_|Msg::Login)>{"Log in"}</button>
+                    { self.view_viewer_link() }
+                    { self.view_tools() }
+                    { self.view_palettes() }
+                    { self.view_brush_presets() }
+                </div>
+            },
+        };
+        html! {
+            <div class=self.style.clone()>
+                { toolbar }
                 <div
                     onpointerdown=self.link.callback(|event: PointerEvent| Msg::PointerDown(event))
                     onpointermove=self.link.callback(|event: PointerEvent| Msg::PointerMove(event))
@@ -445,7 +1316,42 @@ fn get_style() -> Style {
     }
 }
 
-fn get_wsaddr() -> Result<String, String> {
+fn color_to_css(color: &Color) -> String {
+    format!(
+        "rgba({}, {}, {}, {})",
+        color.r,
+        color.g,
+        color.b,
+        color.a as f64 / 255.0
+    )
+}
+
+/// Builds a link to the current room using a viewer token
+fn get_viewer_link(viewer_token: &str) -> Result<String, String> {
+    let location = web_sys::window().ok_or("Error getting window")?.location();
+    let origin = location.origin().map_err(|_| "Error getting origin")?;
+    let hash = location.hash().map_err(|_| "Error getting hash")?;
+    let room = hash
+        .get(1..)
+        .and_then(|x| x.split(&['/', '?'][..]).next())
+        .ok_or("Error getting room")?;
+    Ok(format!("{}/#{}?token={}", origin, room, viewer_token))
+}
+
+/// Percent-encodes everything except unreserved characters
+fn url_encode(value: &str) -> String {
+    value
+        .bytes()
+        .map(|x| match x {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'_' | b'.' | b'~' => {
+                (x as char).to_string()
+            }
+            _ => format!("%{:02X}", x),
+        })
+        .collect()
+}
+
+fn get_wsaddr(resume: Option<(&str, SequenceNumber)>) -> Result<String, String> {
     // Extract location components to get websocket target
     let location = web_sys::window().ok_or("Error getting window")?.location();
     let proto = location.protocol().map_err(|_| "Error getting protocol")?;
@@ -453,12 +1359,50 @@ fn get_wsaddr() -> Result<String, String> {
     let host = location.host().map_err(|_| "Error getting host")?;
     let hash = location.hash().map_err(|_| "Error getting hash")?;
 
-    // Get everything after hash, or if this fails, default to room 0/random number username
-    let default_hashval = format!("0/{}", rand::random::<u16>());
-    let hashval = hash.get(1..).unwrap_or(&default_hashval);
+    // Hash is room[/username][?token=...]. Without a room, have the server create one
+    let hashval = hash.get(1..).unwrap_or("");
+    if hashval.is_empty() {
+        location.set_href("/new").map_err(|_| "Error creating room")?;
+        return Err("Creating new room".to_string());
+    }
+    let (path, query) = match hashval.find('?') {
+        Some(i) => hashval.split_at(i),
+        None => (hashval, ""),
+    };
+    // Default to random number username
+    let path = if path.contains('/') {
+        path.to_string()
+    } else {
+        format!("{}/{}", path, rand::random::<u16>())
+    };
+
+    // Identify as the logged in user, if any
+    let session = web_sys::window()
+        .and_then(|window| window.local_storage().ok().flatten())
+        .and_then(|storage| storage.get_item(SESSION_KEY).ok().flatten());
+    let query = match session {
+        Some(session) if query.is_empty() => format!("?session={}", url_encode(&session)),
+        Some(session) => format!("{}&session={}", query, url_encode(&session)),
+        None => query.to_string(),
+    };
+
+    // Pick up missed changes when reconnecting
+    let query = match resume {
+        Some((resume_token, seq)) => {
+            let separator = if query.is_empty() { "?" } else { "&" };
+            format!(
+                "{}{}resume={}&seq={}",
+                query,
+                separator,
+                url_encode(resume_token),
+                seq
+            )
+        }
+        None => query,
+    };
 
     // Generate websocket target
-    Ok(format!("{}//{}/ws/{}", wsproto, host, hashval))
+    Ok(format!("{}//{}/ws/{}{}", wsproto, host, path, query))
 }
 
 
@@ -0,0 +1,14 @@
+#![no_main]
+use libfuzzer_sys::fuzz_target;
+use netsketch_shared::{ClientMessage, Limits};
+
+// Decodes arbitrary websocket input the way the backend does, which must fail cleanly without
+// allocating much more than the limit, then runs validation on whatever decoded
+fuzz_target!(|data: &[u8]| {
+    let limits = Limits::default();
+    if let Ok(msg) =
+        netsketch_shared::from_zbincode_bounded::<ClientMessage>(data, limits.max_inflated_size)
+    {
+        let _ = limits.validate(&msg);
+    }
+});
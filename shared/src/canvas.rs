@@ -0,0 +1,393 @@
+use crate::Layer;
+use crate::LayerId;
+use crate::MAX_GROUPS;
+use crate::MAX_LAYERS;
+use serde::{Deserialize, Serialize};
+
+pub type GroupId = u8;
+
+/// Identifies either a layer or a group within the layer tree
+#[derive(Serialize, Deserialize, PartialEq, Eq, Hash, Debug, Clone, Copy)]
+pub enum NodeId {
+    Layer(LayerId),
+    Group(GroupId),
+}
+
+/// Visibility properties common to layers and groups
+#[derive(Serialize, Deserialize, Debug, PartialEq, Clone, Copy)]
+pub struct NodeProperties {
+    pub visible: bool,
+    /// Opacity from 0.0 (transparent) to 1.0 (opaque)
+    pub opacity: f32,
+}
+
+impl Default for NodeProperties {
+    fn default() -> Self {
+        NodeProperties {
+            visible: true,
+            opacity: 1.0,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, PartialEq, Clone)]
+pub enum CanvasNode {
+    Layer {
+        id: LayerId,
+        properties: NodeProperties,
+        /// Set to true to clip this layer to the alpha of the nearest unclipped sibling layer
+        /// below it
+        clip: bool,
+    },
+    Group {
+        id: GroupId,
+        properties: NodeProperties,
+        /// Child nodes, ordered bottom to top
+        children: Vec<CanvasNode>,
+    },
+}
+
+impl CanvasNode {
+    pub fn node_id(&self) -> NodeId {
+        match self {
+            CanvasNode::Layer { id, .. } => NodeId::Layer(*id),
+            CanvasNode::Group { id, .. } => NodeId::Group(*id),
+        }
+    }
+}
+
+/// Single step of the compositing program produced by [`LayerTree::composite_order`].
+///
+/// Renderers keep a stack of buffers, starting with one transparent buffer for the whole canvas.
+/// Steps are executed in order, and the buffer left at the end is the final image.
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum CompositeOp {
+    /// Push a new transparent buffer onto the stack for a group's children
+    PushGroup { id: GroupId, opacity: f32 },
+    /// Draw a layer with source-over onto the buffer at the top of the stack. If `clip_to` is
+    /// set, the layer is first masked by the alpha of that layer's pixels (before its own opacity
+    /// is applied)
+    Layer {
+        id: LayerId,
+        opacity: f32,
+        clip_to: Option<LayerId>,
+    },
+    /// Pop the top buffer and draw it with source-over onto the buffer beneath it, using the
+    /// opacity from the matching `PushGroup`
+    PopGroup,
+}
+
+/// Renderer executing the compositing program of a [`LayerTree`], see [`LayerTree::composite`]
+pub trait Compositor {
+    /// Image covering the whole canvas
+    type Buffer;
+    /// Creates a transparent buffer
+    fn create_buffer(&mut self) -> Self::Buffer;
+    /// Draws a layer with source-over onto a buffer, first masking it by the alpha of `clip_to`
+    /// if set
+    fn draw_layer(
+        &mut self,
+        onto: &mut Self::Buffer,
+        layer_id: LayerId,
+        opacity: f32,
+        clip_to: Option<LayerId>,
+    );
+    /// Draws a group's buffer with source-over onto the buffer beneath it
+    fn draw_buffer(&mut self, onto: &mut Self::Buffer, buffer: Self::Buffer, opacity: f32);
+}
+
+/// Ordering and nesting of layers into groups. Shared verbatim between server and clients
+#[derive(Default, Serialize, Deserialize, Debug, PartialEq, Clone)]
+pub struct LayerTree {
+    /// Top level nodes, ordered bottom to top
+    nodes: Vec<CanvasNode>,
+    next_group_id: GroupId,
+}
+
+impl LayerTree {
+    pub fn nodes(&self) -> &[CanvasNode] {
+        &self.nodes
+    }
+
+    pub fn contains(&self, node_id: NodeId) -> bool {
+        self.find(node_id).is_some()
+    }
+
+    /// Finds a node anywhere in the tree
+    pub fn find(&self, node_id: NodeId) -> Option<&CanvasNode> {
+        fn find_in(nodes: &[CanvasNode], node_id: NodeId) -> Option<&CanvasNode> {
+            for node in nodes {
+                if node.node_id() == node_id {
+                    return Some(node);
+                }
+                if let CanvasNode::Group { children, .. } = node {
+                    if let Some(node) = find_in(children, node_id) {
+                        return Some(node);
+                    }
+                }
+            }
+            None
+        }
+        find_in(&self.nodes, node_id)
+    }
+
+    fn find_mut(&mut self, node_id: NodeId) -> Option<&mut CanvasNode> {
+        fn find_in(nodes: &mut [CanvasNode], node_id: NodeId) -> Option<&mut CanvasNode> {
+            for node in nodes {
+                if node.node_id() == node_id {
+                    return Some(node);
+                }
+                if let CanvasNode::Group { children, .. } = node {
+                    if let Some(node) = find_in(children, node_id) {
+                        return Some(node);
+                    }
+                }
+            }
+            None
+        }
+        find_in(&mut self.nodes, node_id)
+    }
+
+    /// Adds a layer to the top of the root of the tree if it isn't already present. Returns true
+    /// if the tree changed
+    pub fn insert_layer(&mut self, layer_id: LayerId) -> bool {
+        if self.contains(NodeId::Layer(layer_id)) {
+            return false;
+        }
+        self.nodes.push(CanvasNode::Layer {
+            id: layer_id,
+            properties: NodeProperties::default(),
+            clip: false,
+        });
+        true
+    }
+
+    /// Creates an empty group on top of the root of the tree. Returns None if MAX_GROUPS has
+    /// been reached
+    pub fn create_group(&mut self) -> Option<GroupId> {
+        if self.next_group_id >= MAX_GROUPS {
+            return None;
+        }
+        let id = self.next_group_id;
+        self.next_group_id += 1;
+        self.nodes.push(CanvasNode::Group {
+            id,
+            properties: NodeProperties::default(),
+            children: Vec::new(),
+        });
+        Some(id)
+    }
+
+    /// Sets visibility and opacity of a layer or group. Returns true if the node exists
+    pub fn set_properties(&mut self, node_id: NodeId, new_properties: NodeProperties) -> bool {
+        let new_properties = NodeProperties {
+            visible: new_properties.visible,
+            opacity: if new_properties.opacity.is_nan() {
+                1.0
+            } else {
+                new_properties.opacity.clamp(0.0, 1.0)
+            },
+        };
+        match self.find_mut(node_id) {
+            Some(CanvasNode::Layer { properties, .. })
+            | Some(CanvasNode::Group { properties, .. }) => {
+                *properties = new_properties;
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Sets whether a layer clips to the layer below it. Returns true if the layer exists
+    pub fn set_clip(&mut self, layer_id: LayerId, new_clip: bool) -> bool {
+        match self.find_mut(NodeId::Layer(layer_id)) {
+            Some(CanvasNode::Layer { clip, .. }) => {
+                *clip = new_clip;
+                true
+            }
+            _ => false,
+        }
+    }
+
+    /// Moves a node into `parent` (or the root if None) at `index`, counted from the bottom.
+    /// Indices past the end place the node on top. Returns false if either node doesn't exist
+    /// or if a group would be moved into itself
+    pub fn move_node(&mut self, node_id: NodeId, parent: Option<GroupId>, index: usize) -> bool {
+        if let Some(parent) = parent {
+            // Check that the parent isn't the node itself or one of its descendants
+            let node = match self.find(node_id) {
+                Some(node) => node,
+                None => return false,
+            };
+            let mut subtree = LayerTree::default();
+            subtree.nodes.push(node.clone());
+            if subtree.contains(NodeId::Group(parent)) || !self.contains(NodeId::Group(parent)) {
+                return false;
+            }
+        }
+
+        fn take(nodes: &mut Vec<CanvasNode>, node_id: NodeId) -> Option<CanvasNode> {
+            if let Some(i) = nodes.iter().position(|x| x.node_id() == node_id) {
+                return Some(nodes.remove(i));
+            }
+            for node in nodes.iter_mut() {
+                if let CanvasNode::Group { children, .. } = node {
+                    if let Some(node) = take(children, node_id) {
+                        return Some(node);
+                    }
+                }
+            }
+            None
+        }
+        let node = match take(&mut self.nodes, node_id) {
+            Some(node) => node,
+            None => return false,
+        };
+
+        let siblings = match parent {
+            Some(parent) => match self.find_mut(NodeId::Group(parent)) {
+                Some(CanvasNode::Group { children, .. }) => children,
+                _ => unreachable!("parent existence checked above"),
+            },
+            None => &mut self.nodes,
+        };
+        siblings.insert(index.min(siblings.len()), node);
+        true
+    }
+
+    /// Generates the compositing program for this tree, bottom to top. Every renderer must
+    /// produce its image by executing these steps in order.
+    ///
+    /// Rules:
+    /// * Hidden layers and groups, and everything inside hidden groups, are skipped
+    /// * A clipped layer is masked by the nearest unclipped layer below it among its siblings.
+    ///   If that layer is hidden, the clipped layer is hidden too. If there is no such layer, or
+    ///   a group is in between, the clipped layer is drawn unclipped
+    /// * Group opacity applies to the group's composited contents, not to each child
+    pub fn composite_order(&self) -> Vec<CompositeOp> {
+        fn composite_nodes(nodes: &[CanvasNode], ops: &mut Vec<CompositeOp>) {
+            // Outer None: no clip base. Inner None: clip base hidden
+            let mut clip_base: Option<Option<LayerId>> = None;
+            for node in nodes {
+                match node {
+                    CanvasNode::Layer {
+                        id,
+                        properties,
+                        clip,
+                    } => {
+                        let clip_to = match (*clip, clip_base) {
+                            (true, Some(Some(base))) => Some(base),
+                            (true, Some(None)) => continue,
+                            (true, None) => None,
+                            (false, _) => {
+                                clip_base = Some(if properties.visible { Some(*id) } else { None });
+                                None
+                            }
+                        };
+                        if properties.visible {
+                            ops.push(CompositeOp::Layer {
+                                id: *id,
+                                opacity: properties.opacity,
+                                clip_to,
+                            });
+                        }
+                    }
+                    CanvasNode::Group {
+                        id,
+                        properties,
+                        children,
+                    } => {
+                        clip_base = None;
+                        if properties.visible {
+                            ops.push(CompositeOp::PushGroup {
+                                id: *id,
+                                opacity: properties.opacity,
+                            });
+                            composite_nodes(children, ops);
+                            ops.push(CompositeOp::PopGroup);
+                        }
+                    }
+                }
+            }
+        }
+        let mut ops = Vec::new();
+        composite_nodes(&self.nodes, &mut ops);
+        ops
+    }
+
+    /// Executes the compositing program from `composite_order` with a renderer, returning the
+    /// final image
+    pub fn composite<C: Compositor>(&self, compositor: &mut C) -> C::Buffer {
+        // Buffers with the opacity they're drawn with once popped
+        let mut stack = vec![(compositor.create_buffer(), 1.0)];
+        for op in self.composite_order() {
+            match op {
+                CompositeOp::PushGroup { opacity, .. } => {
+                    stack.push((compositor.create_buffer(), opacity));
+                }
+                CompositeOp::Layer {
+                    id,
+                    opacity,
+                    clip_to,
+                } => {
+                    let (onto, _) = stack.last_mut().expect("groups are balanced");
+                    compositor.draw_layer(onto, id, opacity, clip_to);
+                }
+                CompositeOp::PopGroup => {
+                    let (buffer, opacity) = stack.pop().expect("groups are balanced");
+                    let (onto, _) = stack.last_mut().expect("groups are balanced");
+                    compositor.draw_buffer(onto, buffer, opacity);
+                }
+            }
+        }
+        stack.pop().expect("groups are balanced").0
+    }
+}
+
+/// Full room canvas: paint data for every layer plus the tree arranging them
+#[derive(Default, Serialize, Deserialize, Debug, Clone)]
+pub struct Canvas {
+    layers: Vec<Layer>,
+    tree: LayerTree,
+}
+
+impl Canvas {
+    pub fn tree(&self) -> &LayerTree {
+        &self.tree
+    }
+
+    pub fn tree_mut(&mut self) -> &mut LayerTree {
+        &mut self.tree
+    }
+
+    /// Iterates through layers in id order
+    pub fn layers(&self) -> impl Iterator<Item = (LayerId, &Layer)> {
+        self.layers
+            .iter()
+            .enumerate()
+            .map(|(layer_id, layer)| (layer_id as LayerId, layer))
+    }
+
+    pub fn layer(&self, layer_id: LayerId) -> Option<&Layer> {
+        self.layers.get(layer_id as usize)
+    }
+
+    pub fn layer_mut(&mut self, layer_id: LayerId) -> Option<&mut Layer> {
+        self.layers.get_mut(layer_id as usize)
+    }
+
+    /// If nonexistant layer, create it and everything in between, adding new layers to the top
+    /// of the tree. Returns true if the tree changed, or None if the layer id is out of bounds
+    pub fn ensure_layer(&mut self, layer_id: LayerId) -> Option<bool> {
+        if layer_id >= MAX_LAYERS {
+            return None;
+        }
+        let mut tree_changed = false;
+        while self.layers.len() <= layer_id as usize {
+            let new_id = self.layers.len() as LayerId;
+            self.layers.push(Layer::default());
+            tree_changed |= self.tree.insert_layer(new_id);
+        }
+        Some(tree_changed)
+    }
+}
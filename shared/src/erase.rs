@@ -0,0 +1,290 @@
+use crate::tile_ops;
+use crate::Element;
+use crate::ElementId;
+use crate::Layer;
+use crate::Offset;
+use crate::PaintStroke;
+use crate::Point;
+use crate::StrokePoint;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeSet;
+use std::collections::HashSet;
+use std::sync::Arc;
+
+#[derive(Serialize, Deserialize, Debug, PartialEq, Clone, Copy)]
+pub enum StrokeEraseMode {
+    /// Delete every stroke touched by the eraser
+    Delete,
+    /// Remove only the parts of strokes under the eraser, keeping the remaining pieces
+    Split,
+}
+
+/// Object eraser request, which removes whole or partial paint strokes instead of pixels
+#[derive(Serialize, Deserialize, Debug, PartialEq, Clone)]
+pub struct StrokeErase {
+    /// Eraser path in world coordinates
+    pub path: Vec<Point>,
+    pub width: f32,
+    pub mode: StrokeEraseMode,
+}
+
+/// Paint stroke affected by an object eraser
+#[derive(Debug, PartialEq, Clone)]
+pub struct ErasedStroke {
+    /// Id of the removed stroke
+    pub id: ElementId,
+    /// Remaining pieces, added to the layer with new ids. Empty if the stroke was deleted
+    pub pieces: Vec<Arc<Element>>,
+    /// Tiles covered by the removed stroke or any of its pieces
+    pub tile_offsets: HashSet<Offset>,
+}
+
+/// Squared distance between segments a0-a1 and b0-b1
+fn segment_distance_sq(a0: Point, a1: Point, b0: Point, b1: Point) -> f32 {
+    fn point_segment_distance_sq(p: Point, s0: Point, s1: Point) -> f32 {
+        let (px, py) = (p.x as f32, p.y as f32);
+        let (fx, fy) = (s0.x as f32, s0.y as f32);
+        let (dx, dy) = ((s1.x - s0.x) as f32, (s1.y - s0.y) as f32);
+        let len_sq = dx * dx + dy * dy;
+        let t = if len_sq == 0.0 {
+            0.0
+        } else {
+            (((px - fx) * dx + (py - fy) * dy) / len_sq).clamp(0.0, 1.0)
+        };
+        let (nx, ny) = (fx + t * dx - px, fy + t * dy - py);
+        nx * nx + ny * ny
+    }
+    fn cross(o: Point, a: Point, b: Point) -> i64 {
+        (a.x - o.x) as i64 * (b.y - o.y) as i64 - (a.y - o.y) as i64 * (b.x - o.x) as i64
+    }
+
+    // Properly crossing segments touch
+    let d1 = cross(b0, b1, a0);
+    let d2 = cross(b0, b1, a1);
+    let d3 = cross(a0, a1, b0);
+    let d4 = cross(a0, a1, b1);
+    if ((d1 > 0 && d2 < 0) || (d1 < 0 && d2 > 0)) && ((d3 > 0 && d4 < 0) || (d3 < 0 && d4 > 0)) {
+        return 0.0;
+    }
+
+    point_segment_distance_sq(a0, b0, b1)
+        .min(point_segment_distance_sq(a1, b0, b1))
+        .min(point_segment_distance_sq(b0, a0, a1))
+        .min(point_segment_distance_sq(b1, a0, a1))
+}
+
+/// Box around a set of points
+#[derive(Debug, Clone, Copy)]
+struct Bounds {
+    min: Point,
+    max: Point,
+}
+
+impl Bounds {
+    /// Returns the box around the points, or None if there are none
+    fn around(points: impl Iterator<Item = Point>) -> Option<Self> {
+        points.fold(None, |bounds, point| {
+            let bounds = bounds.unwrap_or(Bounds {
+                min: point,
+                max: point,
+            });
+            Some(Bounds {
+                min: Point {
+                    x: bounds.min.x.min(point.x),
+                    y: bounds.min.y.min(point.y),
+                },
+                max: Point {
+                    x: bounds.max.x.max(point.x),
+                    y: bounds.max.y.max(point.y),
+                },
+            })
+        })
+    }
+
+    fn of_segment(a: Point, b: Point) -> Self {
+        Bounds {
+            min: Point {
+                x: a.x.min(b.x),
+                y: a.y.min(b.y),
+            },
+            max: Point {
+                x: a.x.max(b.x),
+                y: a.y.max(b.y),
+            },
+        }
+    }
+
+    /// Checks whether the boxes come within `distance` of each other
+    fn near(&self, other: &Bounds, distance: f32) -> bool {
+        let gap = |min_a: i32, max_a: i32, min_b: i32, max_b: i32| {
+            (min_b as i64 - max_a as i64).max(min_a as i64 - max_b as i64) as f32
+        };
+        gap(self.min.x, self.max.x, other.min.x, other.max.x) <= distance
+            && gap(self.min.y, self.max.y, other.min.y, other.max.y) <= distance
+    }
+}
+
+/// Eraser path split into segments, which are worked out once per erase
+struct Eraser<'a> {
+    erase: &'a StrokeErase,
+    /// Segments of the path, with their bounding boxes. A single point path is a zero length
+    /// segment
+    segments: Vec<(Point, Point, Bounds)>,
+    /// Bounding box of the whole path, None if the path is empty
+    bounds: Option<Bounds>,
+}
+
+impl<'a> Eraser<'a> {
+    fn new(erase: &'a StrokeErase) -> Self {
+        let path = &erase.path;
+        let segments = match path.len() {
+            0 => Vec::new(),
+            1 => vec![(path[0], path[0])],
+            _ => path.windows(2).map(|x| (x[0], x[1])).collect(),
+        };
+        Eraser {
+            erase,
+            segments: segments
+                .into_iter()
+                .map(|(a, b)| (a, b, Bounds::of_segment(a, b)))
+                .collect(),
+            bounds: Bounds::around(path.iter().copied()),
+        }
+    }
+
+    /// Checks whether the stroke segment from a to b comes within `radius` of the eraser path
+    fn touches(&self, a: Point, b: Point, radius: f32) -> bool {
+        let bounds = Bounds::of_segment(a, b);
+        self.segments.iter().any(|(e0, e1, eraser_bounds)| {
+            eraser_bounds.near(&bounds, radius)
+                && segment_distance_sq(a, b, *e0, *e1) <= radius * radius
+        })
+    }
+
+    /// Tiles the eraser passes over
+    fn tile_offsets(&self) -> HashSet<Offset> {
+        let radius = ((self.erase.width + 1.0) / 2.0) as i32;
+        let radius = Offset {
+            x: radius,
+            y: radius,
+        };
+        let mut tile_offsets = HashSet::new();
+        for (_, _, bounds) in &self.segments {
+            tile_offsets.extend(tile_ops::compute_bounded_tile_offsets(
+                &(bounds.min - radius),
+                &(bounds.max + radius),
+            ));
+        }
+        tile_offsets
+    }
+
+    /// Computes what remains of a stroke after erasing. Returns None if the eraser doesn't touch
+    /// the stroke, otherwise the remaining pieces, which is empty in delete mode
+    fn erase_stroke(&self, paint_stroke: &PaintStroke) -> Option<Vec<PaintStroke>> {
+        let radius = (self.erase.width.max(0.0) + paint_stroke.brush.width.max(0.0)) / 2.0;
+        let to_point = |x: &StrokePoint| Point { x: x.x, y: x.y };
+        let points = &paint_stroke.points;
+
+        // Skip strokes nowhere near the eraser without looking at their segments
+        let stroke_bounds = Bounds::around(points.iter().map(to_point))?;
+        if !self.bounds?.near(&stroke_bounds, radius) {
+            return None;
+        }
+
+        // A point is kept if its dot isn't touched, and a segment is kept if neither it nor its
+        // endpoints are touched. Runs of kept segments become pieces
+        let point_erased: Vec<bool> = points
+            .iter()
+            .map(|x| self.touches(to_point(x), to_point(x), radius))
+            .collect();
+        let segment_erased: Vec<bool> = points
+            .windows(2)
+            .map(|x| self.touches(to_point(&x[0]), to_point(&x[1]), radius))
+            .collect();
+        if !point_erased.iter().chain(segment_erased.iter()).any(|x| *x) {
+            return None;
+        }
+        if self.erase.mode == StrokeEraseMode::Delete {
+            return Some(Vec::new());
+        }
+
+        let mut pieces = Vec::new();
+        let mut cur_points: Vec<StrokePoint> = Vec::new();
+        for (i, point) in points.iter().enumerate() {
+            let connected = i > 0 && !segment_erased[i - 1];
+            if !connected && !cur_points.is_empty() {
+                pieces.push(std::mem::take(&mut cur_points));
+            }
+            if !point_erased[i] {
+                cur_points.push(*point);
+            }
+        }
+        if !cur_points.is_empty() {
+            pieces.push(cur_points);
+        }
+
+        Some(
+            pieces
+                .into_iter()
+                .map(|points| PaintStroke {
+                    id: 0,
+                    user_id: paint_stroke.user_id,
+                    brush: paint_stroke.brush.clone(),
+                    points,
+                })
+                .collect(),
+        )
+    }
+}
+
+impl StrokeErase {
+    /// Computes what remains of a stroke after erasing. Returns None if the eraser doesn't touch
+    /// the stroke, otherwise the remaining pieces, which is empty in delete mode
+    pub fn erase_stroke(&self, paint_stroke: &PaintStroke) -> Option<Vec<PaintStroke>> {
+        Eraser::new(self).erase_stroke(paint_stroke)
+    }
+}
+
+impl Layer {
+    /// Applies an object eraser to every paint stroke it touches. Touched strokes are removed,
+    /// and in split mode their remaining pieces are added back with new ids, placing them above
+    /// existing elements
+    pub fn erase_strokes(&mut self, erase: &StrokeErase) -> Vec<ErasedStroke> {
+        let eraser = Eraser::new(erase);
+        let mut candidates = BTreeSet::new();
+        for tile_offset in &eraser.tile_offsets() {
+            candidates.append(&mut self.get_tile_elements(tile_offset));
+        }
+
+        let mut erased = Vec::new();
+        for element in candidates {
+            let paint_stroke = match &*element {
+                Element::PaintStroke(paint_stroke) => paint_stroke,
+                _ => continue,
+            };
+            let pieces = match eraser.erase_stroke(paint_stroke) {
+                Some(pieces) => pieces,
+                None => continue,
+            };
+
+            let mut tile_offsets = match self.remove_element(paint_stroke.id) {
+                Some((_, tile_offsets)) => tile_offsets,
+                None => continue,
+            };
+            let pieces = pieces
+                .into_iter()
+                .map(|piece| {
+                    let (piece, piece_tile_offsets) = self.add_element(Element::PaintStroke(piece));
+                    tile_offsets.extend(piece_tile_offsets);
+                    piece
+                })
+                .collect();
+            erased.push(ErasedStroke {
+                id: paint_stroke.id,
+                pieces,
+                tile_offsets,
+            });
+        }
+        erased
+    }
+}
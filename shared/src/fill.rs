@@ -0,0 +1,210 @@
+use crate::tile_ops;
+use crate::Color;
+use crate::Element;
+use crate::ElementId;
+use crate::Layer;
+use crate::Offset;
+use crate::Point;
+use crate::Raster;
+use crate::UserId;
+use crate::TILE_SIZE;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeSet;
+use std::sync::Arc;
+
+/// Number of tiles in each direction around the seed tile that a flood fill can reach. Fills
+/// that would leak further are cut off at this boundary
+pub const FILL_RADIUS_TILES: i32 = 5;
+
+/// Bucket fill request sent by clients
+#[derive(Serialize, Deserialize, Debug, PartialEq, Clone)]
+pub struct FloodFill {
+    /// Point to start filling from, in world coordinates
+    pub seed: Point,
+    /// Maximum difference of any color channel from the seed pixel for a pixel to be filled
+    pub tolerance: u8,
+    pub color: Color,
+}
+
+/// Horizontal run of filled pixels on row `y`, from `x_start` up to but not including `x_end`
+#[derive(Serialize, Deserialize, Debug, PartialEq, Eq, Clone, Copy)]
+pub struct FillSpan {
+    pub y: i32,
+    pub x_start: i32,
+    pub x_end: i32,
+}
+
+/// Region filled by a flood fill, computed once by the server so every client agrees on its
+/// edges
+#[derive(Serialize, Deserialize, Debug, PartialEq, Clone)]
+pub struct Fill {
+    pub id: ElementId,
+    pub user_id: UserId,
+    pub color: Color,
+    /// Filled pixels in world coordinates, ordered by row then column
+    pub spans: Vec<FillSpan>,
+}
+
+impl Fill {
+    /// Returns upper left and lower right corners of the box containing the filled pixels
+    pub fn bounding_box(&self) -> (Offset, Offset) {
+        let mut spans = self.spans.iter();
+        let first = match spans.next() {
+            Some(first) => first,
+            None => return (Offset::default(), Offset::default()),
+        };
+        spans.fold(
+            (
+                Offset {
+                    x: first.x_start,
+                    y: first.y,
+                },
+                Offset {
+                    x: first.x_end - 1,
+                    y: first.y,
+                },
+            ),
+            |(upper_left, lower_right), span| {
+                (
+                    Offset {
+                        x: upper_left.x.min(span.x_start),
+                        y: upper_left.y.min(span.y),
+                    },
+                    Offset {
+                        x: lower_right.x.max(span.x_end - 1),
+                        y: lower_right.y.max(span.y),
+                    },
+                )
+            },
+        )
+    }
+
+    pub fn shift(&mut self, offset: &Offset) {
+        for span in &mut self.spans {
+            span.y += offset.y;
+            span.x_start += offset.x;
+            span.x_end += offset.x;
+        }
+    }
+}
+
+fn similar(a: Color, b: Color, tolerance: u8) -> bool {
+    let diff = |x: u8, y: u8| (x as i16 - y as i16).abs() <= tolerance as i16;
+    diff(a.r, b.r) && diff(a.g, b.g) && diff(a.b, b.b) && diff(a.a, b.a)
+}
+
+/// Finds pixels connected to `seed` (4-way) whose colors are within `tolerance` of the seed
+/// pixel. Returns no spans if the seed is outside the raster
+pub fn flood_fill(raster: &Raster, seed: Point, tolerance: u8) -> Vec<FillSpan> {
+    let seed_color = match raster.get(seed) {
+        Some(seed_color) => seed_color,
+        None => return Vec::new(),
+    };
+    let origin = raster.origin();
+    let width = raster.width();
+    let mut visited = vec![false; (width * raster.height()) as usize];
+    let index = |point: Point| ((point.y - origin.y) * width + (point.x - origin.x)) as usize;
+    let matches = |point: Point| match raster.get(point) {
+        Some(color) => similar(color, seed_color, tolerance),
+        None => false,
+    };
+
+    let mut spans = Vec::new();
+    let mut stack = vec![seed];
+    while let Some(point) = stack.pop() {
+        if visited[index(point)] || !matches(point) {
+            continue;
+        }
+
+        // Extend run left and right from point
+        let mut x_start = point.x;
+        while x_start > origin.x
+            && !visited[index(Point {
+                x: x_start - 1,
+                y: point.y,
+            })]
+            && matches(Point {
+                x: x_start - 1,
+                y: point.y,
+            })
+        {
+            x_start -= 1;
+        }
+        let mut x_end = point.x + 1;
+        while x_end < origin.x + width
+            && !visited[index(Point {
+                x: x_end,
+                y: point.y,
+            })]
+            && matches(Point {
+                x: x_end,
+                y: point.y,
+            })
+        {
+            x_end += 1;
+        }
+        for x in x_start..x_end {
+            visited[index(Point { x, y: point.y })] = true;
+        }
+        spans.push(FillSpan {
+            y: point.y,
+            x_start,
+            x_end,
+        });
+
+        // Queue the start of every matching run in the rows above and below
+        for y in &[point.y - 1, point.y + 1] {
+            let mut in_run = false;
+            for x in x_start..x_end {
+                let neighbor = Point { x, y: *y };
+                let fillable =
+                    raster.contains(neighbor) && !visited[index(neighbor)] && matches(neighbor);
+                if fillable && !in_run {
+                    stack.push(neighbor);
+                }
+                in_run = fillable;
+            }
+        }
+    }
+
+    spans.sort_by_key(|x| (x.y, x.x_start));
+    spans
+}
+
+/// Upper left corner and size of the square of tiles around the seed that a fill rasterizes
+fn fill_region(seed: Point) -> (Offset, i32) {
+    let seed_tile = tile_ops::point_to_tile_offset(seed.x, seed.y);
+    let radius = Offset {
+        x: FILL_RADIUS_TILES * TILE_SIZE,
+        y: FILL_RADIUS_TILES * TILE_SIZE,
+    };
+    (seed_tile - radius, (2 * FILL_RADIUS_TILES + 1) * TILE_SIZE)
+}
+
+/// Collects the elements of `layer` in the tiles a fill from `seed` rasterizes, in drawing
+/// order. This is cheap, so the fill can then be computed away from the layer
+pub fn fill_elements(layer: &Layer, seed: Point) -> Vec<Arc<Element>> {
+    let (upper_left, size) = fill_region(seed);
+    let lower_right = upper_left
+        + Offset {
+            x: size - 1,
+            y: size - 1,
+        };
+
+    // Deduplicated across tiles
+    let mut elements = BTreeSet::new();
+    for tile_offset in tile_ops::compute_bounded_tile_offsets(&upper_left, &lower_right) {
+        elements.append(&mut layer.get_tile_elements(&tile_offset));
+    }
+    elements.into_iter().collect()
+}
+
+/// Rasterizes the elements collected by `fill_elements` and computes the region a flood fill
+/// covers
+pub fn compute_fill(elements: &[Arc<Element>], request: &FloodFill) -> Vec<FillSpan> {
+    let (upper_left, size) = fill_region(request.seed);
+    let mut raster = Raster::new(upper_left, size, size);
+    raster.draw_elements(elements.iter().map(|x| &**x));
+
+    flood_fill(&raster, request.seed, request.tolerance)
+}
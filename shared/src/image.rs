@@ -0,0 +1,51 @@
+use crate::ElementId;
+use crate::Offset;
+use crate::Point;
+use crate::UserId;
+use serde::{Deserialize, Serialize};
+
+/// Lowercase hex encoded SHA-256 of an uploaded asset's contents
+pub type AssetHash = String;
+
+/// Image placed on the canvas, referencing an uploaded asset
+#[derive(Serialize, Deserialize, Debug, PartialEq, Clone)]
+pub struct PlacedImage {
+    pub id: ElementId,
+    pub user_id: UserId,
+    pub asset: AssetHash,
+    /// Top left corner of the image before rotation
+    pub position: Point,
+    /// Natural width of the image in pixels
+    pub width: u32,
+    /// Natural height of the image in pixels
+    pub height: u32,
+    pub scale: f32,
+    /// Clockwise rotation around `position`, in radians
+    pub rotation: f32,
+}
+
+impl PlacedImage {
+    /// Returns upper left and lower right corners of a box guaranteed to contain the image at
+    /// any rotation
+    pub fn bounding_box(&self) -> (Offset, Offset) {
+        let scale = if self.scale.is_finite() {
+            self.scale.abs()
+        } else {
+            0.0
+        };
+        let width = self.width as f32 * scale;
+        let height = self.height as f32 * scale;
+
+        // Rotation is around position, so any rotated point is within the diagonal from it
+        let radius = (width * width + height * height).sqrt().ceil() as i32 + 1;
+        let radius = Offset {
+            x: radius,
+            y: radius,
+        };
+        (self.position - radius, self.position + radius)
+    }
+
+    pub fn shift(&mut self, offset: &Offset) {
+        self.position = self.position + *offset;
+    }
+}
@@ -1,18 +1,522 @@
 use serde::{Deserialize, Serialize};
 use std::cmp::Ordering;
+use std::collections::BTreeMap;
 use std::collections::BTreeSet;
 use std::collections::HashMap;
 use std::collections::HashSet;
 use std::sync::Arc;
 
+pub mod canvas;
+pub mod erase;
+pub mod fill;
+pub mod image;
 pub mod prelude;
+pub mod raster;
+pub mod shape;
+pub mod styles;
+pub mod text;
+pub mod validate;
+
+pub use canvas::{Canvas, CanvasNode, CompositeOp, Compositor, GroupId, LayerTree, NodeId, NodeProperties};
+pub use erase::{ErasedStroke, StrokeErase, StrokeEraseMode};
+pub use fill::{Fill, FillSpan, FloodFill};
+pub use image::{AssetHash, PlacedImage};
+pub use raster::Raster;
+pub use shape::{FillStyle, Shape, ShapeKind, StrokeStyle};
+pub use styles::{BrushPreset, BrushPresetId, Palette, PaletteId, StyleLibrary};
+pub use text::TextLabel;
+pub use validate::{Limits, ValidationError};
 
 #[cfg(test)]
 mod tests {
+    use crate::*;
+
     #[test]
     fn it_works() {
         assert_eq!(2 + 2, 4);
     }
+
+    #[test]
+    fn composite_order_groups_and_clips() {
+        let mut canvas = Canvas::default();
+        assert_eq!(canvas.ensure_layer(2), Some(true));
+        assert_eq!(canvas.ensure_layer(MAX_LAYERS), None);
+
+        let tree = canvas.tree_mut();
+        let group = tree.create_group().unwrap();
+        assert!(tree.move_node(NodeId::Layer(1), Some(group), 0));
+        assert!(tree.move_node(NodeId::Layer(2), Some(group), 1));
+        assert!(tree.set_clip(2, true));
+        assert!(!tree.move_node(NodeId::Group(group), Some(group), 0));
+        assert!(tree.set_properties(
+            NodeId::Group(group),
+            NodeProperties {
+                visible: true,
+                opacity: 0.5
+            }
+        ));
+
+        assert_eq!(
+            tree.composite_order(),
+            vec![
+                CompositeOp::Layer {
+                    id: 0,
+                    opacity: 1.0,
+                    clip_to: None
+                },
+                CompositeOp::PushGroup {
+                    id: group,
+                    opacity: 0.5
+                },
+                CompositeOp::Layer {
+                    id: 1,
+                    opacity: 1.0,
+                    clip_to: None
+                },
+                CompositeOp::Layer {
+                    id: 2,
+                    opacity: 1.0,
+                    clip_to: Some(1)
+                },
+                CompositeOp::PopGroup,
+            ]
+        );
+
+        // Hiding the clip base hides the clipped layer too
+        tree.set_properties(
+            NodeId::Layer(1),
+            NodeProperties {
+                visible: false,
+                opacity: 1.0,
+            },
+        );
+        assert_eq!(tree.composite_order().len(), 3);
+    }
+
+    /// Records what it draws, nesting groups in parentheses
+    struct RecordingCompositor;
+
+    impl Compositor for RecordingCompositor {
+        type Buffer = Vec<String>;
+
+        fn create_buffer(&mut self) -> Vec<String> {
+            Vec::new()
+        }
+
+        fn draw_layer(
+            &mut self,
+            onto: &mut Vec<String>,
+            layer_id: LayerId,
+            opacity: f32,
+            clip_to: Option<LayerId>,
+        ) {
+            match clip_to {
+                Some(clip_to) => onto.push(format!("{}@{}/{}", layer_id, opacity, clip_to)),
+                None => onto.push(format!("{}@{}", layer_id, opacity)),
+            }
+        }
+
+        fn draw_buffer(&mut self, onto: &mut Vec<String>, buffer: Vec<String>, opacity: f32) {
+            onto.push(format!("({})@{}", buffer.join(" "), opacity));
+        }
+    }
+
+    #[test]
+    fn composite_follows_order() {
+        let mut canvas = Canvas::default();
+        canvas.ensure_layer(3);
+        let tree = canvas.tree_mut();
+        let outer = tree.create_group().unwrap();
+        let inner = tree.create_group().unwrap();
+        assert!(tree.move_node(NodeId::Group(inner), Some(outer), 0));
+        assert!(tree.move_node(NodeId::Layer(1), Some(inner), 0));
+        assert!(tree.move_node(NodeId::Layer(2), Some(outer), 1));
+        assert!(tree.set_clip(3, true));
+        assert!(tree.set_properties(
+            NodeId::Group(inner),
+            NodeProperties {
+                visible: true,
+                opacity: 0.5
+            }
+        ));
+        assert!(tree.set_properties(
+            NodeId::Layer(2),
+            NodeProperties {
+                visible: true,
+                opacity: 0.25
+            }
+        ));
+
+        // Layer 3 is clipped to layer 0, its nearest unclipped sibling below
+        assert_eq!(
+            tree.composite(&mut RecordingCompositor).join(" "),
+            "0@1 3@1/0 ((1@1)@0.5 2@0.25)@1"
+        );
+    }
+
+    #[test]
+    fn replace_element_moves_tiles() {
+        let mut layer = Layer::default();
+        let (label, tile_offsets) = layer.add_element(Element::TextLabel(TextLabel {
+            content: "hi".to_string(),
+            ..TextLabel::default()
+        }));
+        assert!(tile_offsets.contains(&Offset { x: 0, y: 0 }));
+
+        let mut moved = match &*label {
+            Element::TextLabel(text_label) => text_label.clone(),
+            _ => unreachable!(),
+        };
+        moved.shift(&Offset { x: 1000, y: 0 });
+        let (_, tile_offsets) = layer.replace_element(Element::TextLabel(moved)).unwrap();
+        assert!(tile_offsets.contains(&Offset { x: 0, y: 0 }));
+        assert!(tile_offsets.contains(&Offset { x: 1000, y: 0 }));
+        assert!(layer.get_tile_elements(&Offset { x: 0, y: 0 }).is_empty());
+        assert_eq!(layer.get_tile_elements(&Offset { x: 1000, y: 0 }).len(), 1);
+    }
+
+    #[test]
+    fn saved_canvas_rebuilds_tiles() {
+        let mut canvas = Canvas::default();
+        canvas.ensure_layer(1);
+        let layer = canvas.layer_mut(1).unwrap();
+        layer.add_element(Element::TextLabel(TextLabel {
+            content: "hi".to_string(),
+            ..TextLabel::default()
+        }));
+        let (removed, _) = layer.add_element(Element::TextLabel(TextLabel::default()));
+        layer.remove_element(removed.id());
+
+        let saved: Canvas = from_zbincode(&to_zbincode(&canvas).unwrap()).unwrap();
+        assert_eq!(saved.tree(), canvas.tree());
+        let layer = saved.layer(1).unwrap();
+        assert_eq!(layer, canvas.layer(1).unwrap());
+        assert_eq!(layer.get_tile_elements(&Offset { x: 0, y: 0 }).len(), 1);
+
+        // Ids keep counting from where they left off
+        let mut saved = saved;
+        let layer = saved.layer_mut(1).unwrap();
+        let (element, _) = layer.add_element(Element::TextLabel(TextLabel::default()));
+        assert_eq!(element.id(), 3);
+    }
+
+    #[test]
+    fn flood_fill_stops_at_rectangle_outline() {
+        let mut layer = Layer::default();
+        layer.add_element(Element::Shape(Shape {
+            id: 0,
+            user_id: 0,
+            kind: ShapeKind::Rectangle {
+                upper_left: Offset { x: 10, y: 10 },
+                lower_right: Offset { x: 50, y: 30 },
+            },
+            stroke: Some(StrokeStyle::default()),
+            fill: None,
+        }));
+
+        let seed = Offset { x: 20, y: 20 };
+        let spans = fill::compute_fill(
+            &fill::fill_elements(&layer, seed),
+            &FloodFill {
+                seed,
+                tolerance: 0,
+                color: Color::default(),
+            },
+        );
+        let fill = Fill {
+            id: 0,
+            user_id: 0,
+            color: Color::default(),
+            spans,
+        };
+        let (upper_left, lower_right) = fill.bounding_box();
+        assert!(upper_left.x > 10 && upper_left.y > 10);
+        assert!(lower_right.x < 50 && lower_right.y < 30);
+    }
+
+    #[test]
+    fn raster_clips_elements() {
+        let red = Color {
+            r: 255,
+            g: 0,
+            b: 0,
+            a: 255,
+        };
+        let elements = vec![
+            Element::Shape(Shape {
+                id: 0,
+                user_id: 0,
+                kind: ShapeKind::Rectangle {
+                    upper_left: Offset { x: 90, y: 90 },
+                    lower_right: Offset { x: 105, y: 200 },
+                },
+                stroke: None,
+                fill: Some(FillStyle { color: red }),
+            }),
+            Element::Fill(Fill {
+                id: 0,
+                user_id: 0,
+                color: red,
+                spans: vec![FillSpan {
+                    y: 110,
+                    x_start: 0,
+                    x_end: 1000,
+                }],
+            }),
+            Element::PaintStroke(PaintStroke {
+                points: vec![StrokePoint {
+                    p: 1.0,
+                    x: -5000,
+                    y: 0,
+                }],
+                ..PaintStroke::default()
+            }),
+        ];
+        let mut raster = Raster::new(Offset { x: 100, y: 100 }, 20, 20);
+        raster.draw_elements(&elements);
+        assert_eq!(raster.get(Offset { x: 100, y: 100 }), Some(red));
+        assert_eq!(raster.get(Offset { x: 110, y: 100 }), Some(Color::default()));
+        assert_eq!(raster.get(Offset { x: 119, y: 110 }), Some(red));
+
+        // Drawing one at a time gives the same pixels
+        let mut one_by_one = Raster::new(Offset { x: 100, y: 100 }, 20, 20);
+        for element in &elements {
+            one_by_one.draw_element(element);
+        }
+        assert_eq!(one_by_one, raster);
+    }
+
+    #[test]
+    fn eraser_clears_to_transparent() {
+        let stroke = |brush: Brush| {
+            Element::PaintStroke(PaintStroke {
+                id: 0,
+                user_id: 0,
+                brush,
+                points: vec![
+                    StrokePoint { p: 1.0, x: 0, y: 5 },
+                    StrokePoint { p: 1.0, x: 10, y: 5 },
+                ],
+            })
+        };
+        let mut raster = Raster::new(Offset::default(), 10, 10);
+        raster.draw_element(&stroke(Brush {
+            width: 4.0,
+            ..Brush::default()
+        }));
+        assert_eq!(raster.get(Offset { x: 5, y: 5 }).unwrap().a, 255);
+
+        raster.draw_element(&stroke(Brush::eraser(2.0)));
+        assert_eq!(raster.get(Offset { x: 5, y: 5 }), Some(Color::default()));
+        assert_eq!(raster.get(Offset { x: 5, y: 3 }).unwrap().a, 255);
+    }
+
+    #[test]
+    fn stroke_eraser_splits_strokes() {
+        let mut layer = Layer::default();
+        layer.add_element(Element::PaintStroke(PaintStroke {
+            id: 0,
+            user_id: 0,
+            brush: Brush::default(),
+            points: (0..=10)
+                .map(|x| StrokePoint {
+                    p: 1.0,
+                    x: x * 10,
+                    y: 0,
+                })
+                .collect(),
+        }));
+
+        let mut erase = StrokeErase {
+            path: vec![Offset { x: 50, y: -20 }, Offset { x: 50, y: 20 }],
+            width: 4.0,
+            mode: StrokeEraseMode::Split,
+        };
+        let erased = layer.erase_strokes(&erase);
+        assert_eq!(erased.len(), 1);
+        assert_eq!(erased[0].id, 1);
+        assert_eq!(erased[0].pieces.len(), 2);
+        assert!(layer.get_element(1).is_none());
+
+        // Misses the pieces entirely
+        erase.path = vec![Offset { x: 500, y: 500 }];
+        assert!(layer.erase_strokes(&erase).is_empty());
+
+        erase.path = vec![Offset { x: 0, y: 0 }, Offset { x: 100, y: 0 }];
+        erase.mode = StrokeEraseMode::Delete;
+        let erased = layer.erase_strokes(&erase);
+        assert_eq!(erased.len(), 2);
+        assert!(erased.iter().all(|x| x.pieces.is_empty()));
+        assert!(layer.get_tile_elements(&Offset { x: 0, y: 0 }).is_empty());
+
+        // Strokes are touched within half the eraser and brush widths, even outside their box
+        let stroke = PaintStroke {
+            points: vec![
+                StrokePoint { p: 1.0, x: 0, y: 0 },
+                StrokePoint { p: 1.0, x: 10, y: 10 },
+            ],
+            ..PaintStroke::default()
+        };
+        erase.path = vec![Offset { x: -2, y: 0 }];
+        assert_eq!(erase.erase_stroke(&stroke), Some(Vec::new()));
+        erase.path = vec![Offset { x: -3, y: 0 }];
+        assert!(erase.erase_stroke(&stroke).is_none());
+    }
+
+    #[test]
+    fn limits_reject_bad_strokes() {
+        let limits = Limits::default();
+        let stroke = |brush: Brush, point: StrokePoint| {
+            ClientMessage::PaintStroke(
+                0,
+                ClientStrokeId::nil(),
+                PaintStroke {
+                    id: 0,
+                    user_id: 0,
+                    brush,
+                    points: vec![point],
+                },
+            )
+        };
+        let point = StrokePoint { p: 0.5, x: 0, y: 0 };
+        assert_eq!(limits.validate(&stroke(Brush::default(), point)), Ok(()));
+        assert_eq!(
+            limits.validate(&stroke(
+                Brush::default(),
+                StrokePoint {
+                    p: f32::NAN,
+                    ..point
+                }
+            )),
+            Err(ValidationError::NotFinite("pressure"))
+        );
+        assert_eq!(
+            limits.validate(&stroke(Brush::eraser(-1.0), point)),
+            Err(ValidationError::OutOfRange("brush width"))
+        );
+        assert_eq!(
+            limits.validate(&stroke(
+                Brush::default(),
+                StrokePoint {
+                    x: i32::MAX,
+                    ..point
+                }
+            )),
+            Err(ValidationError::OutOfRange("paint stroke"))
+        );
+
+        let too_long = ClientMessage::PaintStroke(
+            0,
+            ClientStrokeId::nil(),
+            PaintStroke {
+                points: vec![point; limits.max_stroke_points + 1],
+                ..PaintStroke::default()
+            },
+        );
+        assert!(matches!(
+            limits.validate(&too_long),
+            Err(ValidationError::TooLong { .. })
+        ));
+
+        // A huge viewport would make the server enumerate every tile in it
+        assert!(limits
+            .validate(&ClientMessage::SetViewPort(
+                Offset { x: -1000, y: -1000 },
+                Offset { x: 1000, y: 1000 }
+            ))
+            .is_ok());
+        assert!(limits
+            .validate(&ClientMessage::SetViewPort(
+                Offset {
+                    x: -limits.max_coordinate,
+                    y: -limits.max_coordinate
+                },
+                Offset {
+                    x: limits.max_coordinate,
+                    y: limits.max_coordinate
+                }
+            ))
+            .is_err());
+    }
+
+    #[test]
+    fn limits_reject_huge_elements() {
+        let limits = Limits::default();
+        let corner = Offset {
+            x: limits.max_coordinate,
+            y: limits.max_coordinate,
+        };
+        let rectangle = |lower_right| {
+            ClientMessage::Shape(
+                0,
+                Shape {
+                    id: 0,
+                    user_id: 0,
+                    kind: ShapeKind::Rectangle {
+                        upper_left: Offset::default(),
+                        lower_right,
+                    },
+                    stroke: None,
+                    fill: None,
+                },
+            )
+        };
+        assert!(limits.validate(&rectangle(Offset { x: 500, y: 500 })).is_ok());
+        assert!(matches!(
+            limits.validate(&rectangle(corner)),
+            Err(ValidationError::TooLong { field: "shape", .. })
+        ));
+
+        // Coordinates are in range, but the text would cover millions of tiles
+        let text_label = TextLabel {
+            content: "a".repeat(limits.max_text_len),
+            font_size: limits.max_font_size,
+            ..TextLabel::default()
+        };
+        assert!(limits
+            .validate(&ClientMessage::TextLabel(0, text_label))
+            .is_err());
+
+        let fill = FloodFill {
+            seed: Offset::default(),
+            tolerance: u8::MAX,
+            color: Color::default(),
+        };
+        assert_eq!(
+            limits.validate(&ClientMessage::FloodFill(0, fill)),
+            Err(ValidationError::OutOfRange("fill tolerance"))
+        );
+    }
+
+    #[test]
+    fn bounded_decoder_rejects_bombs() {
+        let msg = ClientMessage::ChatMessage("hello".to_string());
+        let data = to_zbincode(&msg).unwrap();
+        assert_eq!(from_zbincode_bounded::<ClientMessage>(&data, 1024), Ok(msg));
+
+        // Compresses to a few kilobytes, but inflates to a megabyte
+        let bomb = to_zbincode(&ClientMessage::ChatMessage("a".repeat(1 << 20))).unwrap();
+        assert!(bomb.len() < 1 << 14);
+        assert!(from_zbincode_bounded::<ClientMessage>(&bomb, 1 << 16).is_err());
+
+        // Length prefix claiming far more points than the message holds
+        let mut lying = bincode::serialize(&ClientMessage::PaintStroke(
+            0,
+            ClientStrokeId::nil(),
+            PaintStroke::default(),
+        ))
+        .unwrap();
+        let len = lying.len();
+        lying[len - 8..].copy_from_slice(&(u64::MAX / 2).to_le_bytes());
+        let lying = {
+            use std::io::Write;
+            let mut e =
+                flate2::write::DeflateEncoder::new(Vec::new(), flate2::Compression::default());
+            e.write_all(&lying).unwrap();
+            e.finish().unwrap()
+        };
+        assert!(from_zbincode_bounded::<ClientMessage>(&lying, 1 << 16).is_err());
+    }
 }
 
 pub type LayerId = u8;
@@ -20,16 +524,23 @@ pub type UserId = usize;
 pub type Username = String;
 pub type ChatMessage = String;
 
-/// Positive signed integer specifying size of each side of square tile
+/// Positive signed integer specifying size of each side of square tile. Part of the protocol,
+/// clients compute the same tile offsets, so it can't be configured per server
 pub const TILE_SIZE: i32 = 100;
-/// Maximum number of layers supported
+/// Maximum number of layers supported. Part of the protocol like `TILE_SIZE`, and saved rooms
+/// may hold layers up to it
 pub const MAX_LAYERS: u8 = 100;
-/// Maximum levels of undo
-pub const UNDO_SEARCH_DEPTH: usize = 100;
+/// Maximum number of layer groups supported
+pub const MAX_GROUPS: u8 = 100;
 
 pub mod tile_ops {
+    use crate::Element;
+    use crate::Fill;
     use crate::Offset;
     use crate::PaintStroke;
+    use crate::PlacedImage;
+    use crate::Shape;
+    use crate::TextLabel;
     use crate::TILE_SIZE;
     use std::collections::HashSet;
 
@@ -106,71 +617,137 @@ pub mod tile_ops {
         }
         return tile_offsets;
     }
+    /// Finds tile offsets containing shape, using its bounding box
+    pub fn find_shape_tile_offsets(shape: &Shape) -> HashSet<Offset> {
+        let (upper_left, lower_right) = shape.bounding_box();
+        compute_bounded_tile_offsets(&upper_left, &lower_right)
+    }
+    /// Finds tile offsets possibly containing text label
+    pub fn find_textlabel_tile_offsets(text_label: &TextLabel) -> HashSet<Offset> {
+        let (upper_left, lower_right) = text_label.bounding_box();
+        compute_bounded_tile_offsets(&upper_left, &lower_right)
+    }
+    /// Finds tile offsets containing placed image
+    pub fn find_image_tile_offsets(image: &PlacedImage) -> HashSet<Offset> {
+        let (upper_left, lower_right) = image.bounding_box();
+        compute_bounded_tile_offsets(&upper_left, &lower_right)
+    }
+    /// Finds tile offsets containing filled pixels
+    pub fn find_fill_tile_offsets(fill: &Fill) -> HashSet<Offset> {
+        let mut tile_offsets: HashSet<Offset> = HashSet::new();
+        for span in &fill.spans {
+            tile_offsets.extend(compute_bounded_tile_offsets(
+                &Offset {
+                    x: span.x_start,
+                    y: span.y,
+                },
+                &Offset {
+                    x: span.x_end - 1,
+                    y: span.y,
+                },
+            ));
+        }
+        tile_offsets
+    }
+    /// Finds tile offsets containing any kind of canvas element
+    pub fn find_element_tile_offsets(element: &Element) -> HashSet<Offset> {
+        match element {
+            Element::PaintStroke(paint_stroke) => find_paintstroke_tile_offsets(paint_stroke),
+            Element::Shape(shape) => find_shape_tile_offsets(shape),
+            Element::TextLabel(text_label) => find_textlabel_tile_offsets(text_label),
+            Element::Image(image) => find_image_tile_offsets(image),
+            Element::Fill(fill) => find_fill_tile_offsets(fill),
+        }
+    }
 }
 
 
 #[derive(Default, Debug, PartialEq, Clone)]
 pub struct Layer {
-    tiles: HashMap<Offset, BTreeSet<Arc<PaintStroke>>>,
-    last_id: PaintStrokeId
+    tiles: HashMap<Offset, BTreeSet<Arc<Element>>>,
+    /// Index of elements by id
+    elements: BTreeMap<ElementId, Arc<Element>>,
+    last_id: ElementId
 }
 
 impl Layer {
-    pub fn add_paint_stroke(
-        &mut self,
-        mut paint_stroke: PaintStroke,
-    ) -> (Arc<PaintStroke>, HashSet<Offset>) {
+    /// Adds element to layer, assigning it a new id. Returns the stored element and the tile
+    /// offsets it was added to
+    pub fn add_element(&mut self, mut element: Element) -> (Arc<Element>, HashSet<Offset>) {
         self.last_id += 1;
-        paint_stroke.id = self.last_id;
+        element.set_id(self.last_id);
 
-        let paint_stroke = Arc::new(paint_stroke);
+        let element = Arc::new(element);
+        let tile_offsets = self.insert_element(element.clone());
+        (element, tile_offsets)
+    }
 
-        let tile_offsets = tile_ops::find_paintstroke_tile_offsets(&paint_stroke);
+    /// Replaces the element with the same id, keeping its place in the drawing order. Returns
+    /// the stored element and the tile offsets covered by either the old or new version, or None
+    /// if no element has that id
+    pub fn replace_element(&mut self, element: Element) -> Option<(Arc<Element>, HashSet<Offset>)> {
+        let old_element = self.elements.get(&element.id())?.clone();
+        let mut tile_offsets = self.remove_from_tiles(&old_element);
+
+        let element = Arc::new(element);
+        tile_offsets.extend(self.insert_element(element.clone()));
+        Some((element, tile_offsets))
+    }
+
+    /// Removes element by id. Returns the removed element and the tile offsets it covered, or
+    /// None if no element has that id
+    pub fn remove_element(
+        &mut self,
+        element_id: ElementId,
+    ) -> Option<(Arc<Element>, HashSet<Offset>)> {
+        let element = self.elements.remove(&element_id)?;
+        let tile_offsets = self.remove_from_tiles(&element);
+        Some((element, tile_offsets))
+    }
+
+    /// Gets element by id
+    pub fn get_element(&self, element_id: ElementId) -> Option<&Arc<Element>> {
+        self.elements.get(&element_id)
+    }
+
+    fn insert_element(&mut self, element: Arc<Element>) -> HashSet<Offset> {
+        let tile_offsets = tile_ops::find_element_tile_offsets(&element);
 
         for i in &tile_offsets {
-            if let Some(tile) = self.tiles.get_mut(&i) {
-                tile.insert(paint_stroke.clone());
+            if let Some(tile) = self.tiles.get_mut(i) {
+                tile.insert(element.clone());
             } else {
-                let mut tile: BTreeSet<Arc<PaintStroke>> = BTreeSet::new();
-                tile.insert(paint_stroke.clone());
+                let mut tile: BTreeSet<Arc<Element>> = BTreeSet::new();
+                tile.insert(element.clone());
                 self.tiles.insert(*i, tile);
             }
         }
-        return (paint_stroke, tile_offsets);
-    }
-    // /// Undoes actions done by specified user on paint stack. Returns hashset of updated tile
-    // /// offsets
-    // pub fn undo(&mut self, user_id: UserId) -> Option<HashSet<Offset>> {
-    //     let start_idx = if UNDO_SEARCH_DEPTH <= self.paint_strokes.len() {
-    //         self.paint_strokes.len() - UNDO_SEARCH_DEPTH
-    //     } else {
-    //         0
-    //     };
-
-    //     for (i, paint_stroke) in self.paint_strokes[start_idx..].iter().rev().enumerate() {
-    //         if paint_stroke.user_id == user_id {
-    //             let tile_offsets = tile_ops::find_paintstroke_tile_offsets(&paint_stroke);
-    //             self.paint_strokes.remove(i);
-    //             for j in &tile_offsets {
-    //                 if let Some(tile) = self.tiles.get_mut(&j) {
-    //                     for k in (0..tile.len()).rev() {
-    //                         if tile[k] == i {
-    //                             tile.remove(k);
-    //                             break;
-    //                         } else {
-    //                             tile[k] = tile[k] - 1;
-    //                         }
-    //                     }
-    //                 }
-    //             }
-    //             return Some(tile_offsets);
-    //         }
-    //     }
-    //     return None;
-    // }
-
-    /// Gets all strokes belonging to a tile
-    pub fn get_tile_paintstrokes(&self, tile_offset: &Offset) -> BTreeSet<Arc<PaintStroke>> {
+        self.elements.insert(element.id(), element);
+        tile_offsets
+    }
+
+    fn remove_from_tiles(&mut self, element: &Arc<Element>) -> HashSet<Offset> {
+        let tile_offsets = tile_ops::find_element_tile_offsets(element);
+        for i in &tile_offsets {
+            if let Some(tile) = self.tiles.get_mut(i) {
+                tile.remove(element);
+                if tile.is_empty() {
+                    self.tiles.remove(i);
+                }
+            }
+        }
+        tile_offsets
+    }
+    /// Layer as it is saved, without the tile index which is rebuilt on load
+    fn saved(&self) -> SavedLayer<&Element> {
+        SavedLayer {
+            elements: self.elements.values().map(|x| &**x).collect(),
+            last_id: self.last_id,
+        }
+    }
+
+    /// Gets all elements belonging to a tile, ordered by id
+    pub fn get_tile_elements(&self, tile_offset: &Offset) -> BTreeSet<Arc<Element>> {
         if let Some(tile) = self.tiles.get(tile_offset) {
             tile.clone()
         } else {
@@ -179,6 +756,32 @@ impl Layer {
     }
 }
 
+#[derive(Serialize, Deserialize)]
+struct SavedLayer<E> {
+    elements: Vec<E>,
+    last_id: ElementId,
+}
+
+impl Serialize for Layer {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        self.saved().serialize(serializer)
+    }
+}
+
+impl<'de> Deserialize<'de> for Layer {
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let saved = SavedLayer::<Element>::deserialize(deserializer)?;
+        let mut layer = Layer {
+            last_id: saved.last_id,
+            ..Layer::default()
+        };
+        for element in saved.elements {
+            layer.insert_element(Arc::new(element));
+        }
+        Ok(layer)
+    }
+}
+
 pub type Point = Offset;
 
 #[derive(Default, Serialize, Deserialize, PartialEq, Eq, Hash, Debug, Clone, Copy)]
@@ -258,10 +861,26 @@ pub struct Brush {
     /// How much to bleed in from surrounding areas
     pub smudging: f32,
     /// Set to true to replace colors underneath stroke instead of applying on top
-    /// Useful for erase
+    /// Useful for erase, see `Brush::eraser`
     pub replace: bool, 
 }
 
+impl Brush {
+    /// Brush clearing pixels on its layer to transparent
+    pub fn eraser(width: f32) -> Self {
+        Brush {
+            color: Color::default(),
+            width,
+            replace: true,
+            ..Brush::default()
+        }
+    }
+
+    pub fn is_eraser(&self) -> bool {
+        self.replace && self.color.a == 0
+    }
+}
+
 impl Default for Brush {
     fn default() -> Self {
         Brush {
@@ -313,7 +932,11 @@ impl std::ops::Add<&Offset> for &StrokePoint {
     }
 }
 
-pub type PaintStrokeId = usize;
+pub type ElementId = usize;
+pub type PaintStrokeId = ElementId;
+/// Random id chosen by the client for a paint stroke it sends, so the server can acknowledge it
+/// and ignore resubmissions
+pub type ClientStrokeId = uuid::Uuid;
 
 #[derive(Default, Serialize, Deserialize, Debug, Clone)]
 pub struct PaintStroke {
@@ -349,19 +972,229 @@ impl PartialEq for PaintStroke {
 
 impl Eq for PaintStroke {}
 
+/// Anything that can be placed on a layer. Elements share a single id sequence per layer, which
+/// also determines their drawing order
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub enum Element {
+    PaintStroke(PaintStroke),
+    Shape(Shape),
+    TextLabel(TextLabel),
+    Image(PlacedImage),
+    Fill(Fill),
+}
+
+impl Element {
+    pub fn id(&self) -> ElementId {
+        match self {
+            Element::PaintStroke(paint_stroke) => paint_stroke.id,
+            Element::Shape(shape) => shape.id,
+            Element::TextLabel(text_label) => text_label.id,
+            Element::Image(image) => image.id,
+            Element::Fill(fill) => fill.id,
+        }
+    }
+
+    pub fn set_id(&mut self, id: ElementId) {
+        match self {
+            Element::PaintStroke(paint_stroke) => paint_stroke.id = id,
+            Element::Shape(shape) => shape.id = id,
+            Element::TextLabel(text_label) => text_label.id = id,
+            Element::Image(image) => image.id = id,
+            Element::Fill(fill) => fill.id = id,
+        }
+    }
+
+    pub fn user_id(&self) -> UserId {
+        match self {
+            Element::PaintStroke(paint_stroke) => paint_stroke.user_id,
+            Element::Shape(shape) => shape.user_id,
+            Element::TextLabel(text_label) => text_label.user_id,
+            Element::Image(image) => image.user_id,
+            Element::Fill(fill) => fill.user_id,
+        }
+    }
+
+    pub fn set_user_id(&mut self, user_id: UserId) {
+        match self {
+            Element::PaintStroke(paint_stroke) => paint_stroke.user_id = user_id,
+            Element::Shape(shape) => shape.user_id = user_id,
+            Element::TextLabel(text_label) => text_label.user_id = user_id,
+            Element::Image(image) => image.user_id = user_id,
+            Element::Fill(fill) => fill.user_id = user_id,
+        }
+    }
+
+    /// Wraps element in the server message used to send it to clients
+    pub fn to_server_message(&self, layer_id: LayerId) -> ServerMessage {
+        match self {
+            Element::PaintStroke(paint_stroke) => {
+                ServerMessage::PaintStroke(layer_id, paint_stroke.clone())
+            }
+            Element::Shape(shape) => ServerMessage::Shape(layer_id, shape.clone()),
+            Element::TextLabel(text_label) => {
+                ServerMessage::TextLabel(layer_id, text_label.clone())
+            }
+            Element::Image(image) => ServerMessage::Image(layer_id, image.clone()),
+            Element::Fill(fill) => ServerMessage::Fill(layer_id, fill.clone()),
+        }
+    }
+}
+
+impl Ord for Element {
+    fn cmp(&self, other: &Self) -> Ordering {
+        self.id().cmp(&other.id())
+    }
+}
+
+impl PartialOrd for Element {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl PartialEq for Element {
+    fn eq(&self, other: &Self) -> bool {
+        self.id() == other.id()
+    }
+}
+
+impl Eq for Element {}
+
+/// Access level of a connection to a room
+#[derive(Serialize, Deserialize, Debug, PartialEq, Eq, Clone, Copy)]
+pub enum Role {
+    /// Can draw and change the room
+    Editor,
+    /// Can only watch
+    Viewer,
+}
+
+/// Kind of problem reported by `ServerMessage::Error`
+#[derive(Serialize, Deserialize, Debug, PartialEq, Eq, Clone, Copy)]
+pub enum ErrorCode {
+    /// Message couldn't be decoded
+    Malformed,
+    /// Message is outside the server's limits, see `Limits`
+    Invalid,
+    /// Connection's role doesn't allow the change
+    Forbidden,
+    /// Referenced layer, element, node or asset doesn't exist
+    NotFound,
+    /// Room already holds the maximum number of layers, groups, palettes or presets
+    LimitReached,
+    /// Connection is sending too fast, the message was dropped
+    RateLimited,
+    /// Connection couldn't keep up with the messages sent to it and is being closed
+    SlowConsumer,
+}
+
+/// Position of a message in a room's stream of changes
+pub type SequenceNumber = u64;
+
+/// First message sent to a connection after joining a room
+#[derive(Serialize, Deserialize, Debug, PartialEq, Clone)]
+pub struct Welcome {
+    pub user_id: UserId,
+    pub role: Role,
+    /// Token for read-only links to the room, only given to editors
+    pub viewer_token: Option<String>,
+    /// Token to present with the last seen sequence number when reconnecting, to receive the
+    /// messages missed in between
+    pub resume_token: String,
+    /// Sequence number of the last message sent to the room before joining
+    pub seq: SequenceNumber,
+}
+
 #[derive(Serialize, Deserialize, Debug, PartialEq, Clone)]
 pub enum ClientMessage {
-    PaintStroke(LayerId, PaintStroke),
+    /// New paint stroke, acknowledged with `ServerMessage::StrokeAck`. Resending a stroke with
+    /// the same client id only repeats the acknowledgement
+    PaintStroke(LayerId, ClientStrokeId, PaintStroke),
+    Shape(LayerId, Shape),
+    TextLabel(LayerId, TextLabel),
+    /// Replace the contents of a text label previously placed by the same user, identified by
+    /// its id
+    EditTextLabel(LayerId, TextLabel),
+    /// Place an image previously uploaded to the asset store
+    PlaceImage(LayerId, PlacedImage),
+    /// Bucket fill, computed by the server into a `Fill`
+    FloodFill(LayerId, FloodFill),
+    /// Object eraser removing or splitting paint strokes
+    StrokeErase(LayerId, StrokeErase),
+    /// Add a palette to the room. The id is assigned by the server
+    AddPalette(Palette),
+    /// Replace the palette with the same id
+    EditPalette(Palette),
+    RemovePalette(PaletteId),
+    /// Add a brush preset to the room. The id is assigned by the server
+    AddBrushPreset(BrushPreset),
+    /// Replace the brush preset with the same id
+    EditBrushPreset(BrushPreset),
+    RemoveBrushPreset(BrushPresetId),
     SetViewPort(Offset, Offset),
     ChatMessage(String),
     UndoMessage,
     FetchTile(LayerId, Offset),
+    /// Create an empty layer group on top of the layer tree
+    CreateGroup,
+    /// Move a layer or group into a parent group (or the root if None) at an index counted from
+    /// the bottom
+    MoveNode(NodeId, Option<GroupId>, usize),
+    SetNodeProperties(NodeId, NodeProperties),
+    /// Set whether a layer clips to the alpha of the layer below it
+    SetLayerClip(LayerId, bool),
+}
+
+impl ClientMessage {
+    /// Whether the message changes the room, so should be rejected from viewers
+    pub fn is_mutating(&self) -> bool {
+        !matches!(
+            self,
+            ClientMessage::SetViewPort(..)
+                | ClientMessage::ChatMessage(..)
+                | ClientMessage::FetchTile(..)
+        )
+    }
 }
 
 #[derive(Serialize, Deserialize, Debug, PartialEq, Clone)]
 pub enum ServerMessage {
+    Welcome(Welcome),
+    /// Change to the room, numbered so it can be replayed to a client that reconnects
+    Sequenced(SequenceNumber, Box<ServerMessage>),
+    /// Missed messages can't be replayed after reconnecting. Drop all elements and request the
+    /// viewport again
+    Resync,
+    /// Server is shutting down or restarting, and is about to close the connection
+    Restarting,
+    /// Message sent by this client was rejected
+    Error { code: ErrorCode, message: String },
+    /// Paint stroke sent by this client was added with id `id`
+    StrokeAck {
+        client_id: ClientStrokeId,
+        id: PaintStrokeId,
+        layer: LayerId,
+    },
     PaintStroke(LayerId, PaintStroke),
+    Shape(LayerId, Shape),
+    /// New or edited text label. Replaces any label with the same id on the layer
+    TextLabel(LayerId, TextLabel),
+    Image(LayerId, PlacedImage),
+    Fill(LayerId, Fill),
+    /// Element was removed from the layer
+    RemoveElement(LayerId, ElementId),
+    /// Paint stroke was split by an object eraser. Remove the stroke and add the remaining
+    /// pieces, which have new ids
+    ReplaceStroke(LayerId, ElementId, Vec<PaintStroke>),
+    /// New or edited palette. Replaces any palette with the same id
+    Palette(Palette),
+    RemovePalette(PaletteId),
+    /// New or edited brush preset. Replaces any preset with the same id
+    BrushPreset(BrushPreset),
+    RemoveBrushPreset(BrushPresetId),
     ChatMessage(Username, String),
+    /// Full layer tree, sent on viewport changes and whenever the tree is modified
+    LayerTree(LayerTree),
 }
 
 pub fn from_zbincode<T: serde::de::DeserializeOwned>(serialized: &[u8]) -> Result<T, String> {
@@ -378,6 +1211,45 @@ pub fn from_zbincode<T: serde::de::DeserializeOwned>(serialized: &[u8]) -> Resul
         Err(err) => Err(err.to_string()),
     }
 }
+/// Like `from_zbincode`, but for untrusted input. Fails instead of inflating more than
+/// `max_inflated_size` bytes, and limits bincode to reading that much, so a small message can't
+/// make us allocate more than a bounded amount of memory
+pub fn from_zbincode_bounded<T: serde::de::DeserializeOwned>(
+    serialized: &[u8],
+    max_inflated_size: usize,
+) -> Result<T, String> {
+    use bincode::Options;
+    use std::io::prelude::*;
+    let max_inflated_size = max_inflated_size as u64;
+    let mut buf = Vec::new();
+    let inflator = flate2::read::DeflateDecoder::new(serialized);
+    // Read one byte past the limit to tell a message of exactly the maximum size from a larger one
+    if let Err(err) = inflator
+        .take(max_inflated_size.saturating_add(1))
+        .read_to_end(&mut buf)
+    {
+        return Err(err.to_string());
+    }
+    if buf.len() as u64 > max_inflated_size {
+        return Err(format!(
+            "Inflated message larger than {} bytes",
+            max_inflated_size
+        ));
+    }
+
+    // Same encoding as bincode::deserialize, with a limit on bytes read so length prefixes can't
+    // claim more than the message holds
+    let data: bincode::Result<T> = bincode::DefaultOptions::new()
+        .with_fixint_encoding()
+        .allow_trailing_bytes()
+        .with_limit(max_inflated_size)
+        .deserialize(&buf);
+
+    match data {
+        Ok(data) => Ok(data),
+        Err(err) => Err(err.to_string()),
+    }
+}
 pub fn to_zbincode<T: Serialize>(serializable: &T) -> Result<Vec<u8>, String> {
     let bincode = match bincode::serialize(&serializable) {
         Ok(data) => data,
@@ -3,6 +3,14 @@ pub use crate::UserId;
 pub use crate::Username;
 pub use crate::ChatMessage;
 pub use crate::Layer;
+pub use crate::Canvas;
+pub use crate::CanvasNode;
+pub use crate::CompositeOp;
+pub use crate::Compositor;
+pub use crate::GroupId;
+pub use crate::LayerTree;
+pub use crate::NodeId;
+pub use crate::NodeProperties;
 pub use crate::Point;
 pub use crate::Offset;
 pub use crate::Color;
@@ -10,5 +18,33 @@ pub use crate::Brush;
 pub use crate::StrokePoint;
 pub use crate::PaintStrokeId;
 pub use crate::PaintStroke;
+pub use crate::ClientStrokeId;
+pub use crate::ElementId;
+pub use crate::Element;
+pub use crate::Shape;
+pub use crate::ShapeKind;
+pub use crate::StrokeStyle;
+pub use crate::FillStyle;
+pub use crate::TextLabel;
+pub use crate::AssetHash;
+pub use crate::PlacedImage;
+pub use crate::Fill;
+pub use crate::FillSpan;
+pub use crate::FloodFill;
+pub use crate::Raster;
+pub use crate::ErasedStroke;
+pub use crate::StrokeErase;
+pub use crate::StrokeEraseMode;
+pub use crate::BrushPreset;
+pub use crate::BrushPresetId;
+pub use crate::Palette;
+pub use crate::PaletteId;
+pub use crate::StyleLibrary;
+pub use crate::Role;
+pub use crate::ErrorCode;
+pub use crate::Limits;
+pub use crate::ValidationError;
+pub use crate::SequenceNumber;
+pub use crate::Welcome;
 pub use crate::ClientMessage;
 pub use crate::ServerMessage;
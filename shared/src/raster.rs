@@ -0,0 +1,425 @@
+use crate::Color;
+use crate::Element;
+use crate::Fill;
+use crate::Offset;
+use crate::PaintStroke;
+use crate::Point;
+use crate::Shape;
+use crate::ShapeKind;
+
+/// Number of segments used to approximate ellipse outlines
+const ELLIPSE_SEGMENTS: usize = 64;
+
+/// Software RGBA rendering of a rectangular region of a layer, in world coordinates.
+///
+/// Pixel (x, y) covers the square from (x, y) to (x + 1, y + 1), and is considered covered by a
+/// primitive if its center is. Strokes with `Brush::replace` set overwrite pixels instead of
+/// blending, which erases them if the brush color is transparent. Only strokes, shapes and fills
+/// are rasterized; text labels and images are skipped, since their pixels depend on fonts and
+/// image data the server doesn't decode
+#[derive(Debug, PartialEq, Clone)]
+pub struct Raster {
+    origin: Offset,
+    width: i32,
+    height: i32,
+    pixels: Vec<Color>,
+}
+
+/// Pixels covered by a single primitive, so overlapping parts of it are only blended once.
+/// Covers only the part of the raster the primitive can reach, and its buffer is reused from one
+/// primitive to the next
+#[derive(Default)]
+struct Coverage {
+    upper_left: Offset,
+    width: i32,
+    height: i32,
+    covered: Vec<bool>,
+}
+
+impl Coverage {
+    /// Clears coverage and moves it to the box from `upper_left` to `lower_right` inclusive
+    fn reset(&mut self, upper_left: Offset, lower_right: Offset) {
+        self.upper_left = upper_left;
+        self.width = (lower_right.x - upper_left.x + 1).max(0);
+        self.height = (lower_right.y - upper_left.y + 1).max(0);
+        self.covered.clear();
+        self.covered
+            .resize((self.width * self.height) as usize, false);
+    }
+
+    fn mark(&mut self, x: i32, y: i32) {
+        let x = x - self.upper_left.x;
+        let y = y - self.upper_left.y;
+        if x >= 0 && y >= 0 && x < self.width && y < self.height {
+            self.covered[(y * self.width + x) as usize] = true;
+        }
+    }
+
+    /// Marks pixels within `radius` of the segment between two points
+    fn mark_segment(&mut self, from: Point, to: Point, radius: f32) {
+        let r = radius.ceil() as i32 + 1;
+        let x0 = from.x.min(to.x) - r;
+        let x1 = from.x.max(to.x) + r;
+        let y0 = from.y.min(to.y) - r;
+        let y1 = from.y.max(to.y) + r;
+
+        let (fx, fy) = (from.x as f32, from.y as f32);
+        let (dx, dy) = ((to.x - from.x) as f32, (to.y - from.y) as f32);
+        let len_sq = dx * dx + dy * dy;
+
+        for y in y0.max(self.upper_left.y)..=y1.min(self.upper_left.y + self.height - 1) {
+            for x in x0.max(self.upper_left.x)..=x1.min(self.upper_left.x + self.width - 1) {
+                let (px, py) = (x as f32 + 0.5, y as f32 + 0.5);
+                // Project pixel center onto segment to find nearest point
+                let t = if len_sq == 0.0 {
+                    0.0
+                } else {
+                    (((px - fx) * dx + (py - fy) * dy) / len_sq).clamp(0.0, 1.0)
+                };
+                let (nx, ny) = (fx + t * dx - px, fy + t * dy - py);
+                if nx * nx + ny * ny <= radius * radius {
+                    self.mark(x, y);
+                }
+            }
+        }
+    }
+
+    /// Marks pixels inside a closed polygon using the even-odd rule
+    fn mark_polygon(&mut self, points: &[(f32, f32)]) {
+        if points.len() < 3 {
+            return;
+        }
+        let min_y = points
+            .iter()
+            .map(|x| x.1)
+            .fold(f32::INFINITY, f32::min)
+            .floor() as i32;
+        let max_y = points
+            .iter()
+            .map(|x| x.1)
+            .fold(f32::NEG_INFINITY, f32::max)
+            .ceil() as i32;
+
+        let mut crossings = Vec::new();
+        for y in min_y.max(self.upper_left.y)..=max_y.min(self.upper_left.y + self.height - 1) {
+            let py = y as f32 + 0.5;
+            crossings.clear();
+            for i in 0..points.len() {
+                let (ax, ay) = points[i];
+                let (bx, by) = points[(i + 1) % points.len()];
+                if (ay <= py) != (by <= py) {
+                    crossings.push(ax + (py - ay) / (by - ay) * (bx - ax));
+                }
+            }
+            crossings.sort_by(|a, b| a.partial_cmp(b).unwrap_or(std::cmp::Ordering::Equal));
+            for pair in crossings.chunks(2) {
+                if let [start, end] = pair {
+                    // Pixels whose centers are between the crossings
+                    let x0 = (start - 0.5).ceil() as i32;
+                    let x1 = (end - 0.5).floor() as i32;
+                    for x in x0.max(self.upper_left.x)..=x1.min(self.upper_left.x + self.width - 1)
+                    {
+                        self.mark(x, y);
+                    }
+                }
+            }
+        }
+    }
+}
+
+/// Box containing points, widened by `margin` on every side
+fn bounds(points: impl Iterator<Item = Point>, margin: i32) -> Option<(Offset, Offset)> {
+    points
+        .fold(None, |bounds: Option<(Offset, Offset)>, point| {
+            let (min, max) = bounds.unwrap_or((point, point));
+            Some((
+                Offset {
+                    x: min.x.min(point.x),
+                    y: min.y.min(point.y),
+                },
+                Offset {
+                    x: max.x.max(point.x),
+                    y: max.y.max(point.y),
+                },
+            ))
+        })
+        .map(|(min, max)| {
+            let margin = Offset {
+                x: margin,
+                y: margin,
+            };
+            (min - margin, max + margin)
+        })
+}
+
+impl Raster {
+    /// Creates a transparent raster covering `width` by `height` pixels starting at `origin`
+    pub fn new(origin: Offset, width: i32, height: i32) -> Self {
+        let width = width.max(0);
+        let height = height.max(0);
+        Raster {
+            origin,
+            width,
+            height,
+            pixels: vec![Color::default(); (width * height) as usize],
+        }
+    }
+
+    pub fn origin(&self) -> Offset {
+        self.origin
+    }
+
+    pub fn width(&self) -> i32 {
+        self.width
+    }
+
+    pub fn height(&self) -> i32 {
+        self.height
+    }
+
+    pub fn contains(&self, point: Point) -> bool {
+        point.x >= self.origin.x
+            && point.y >= self.origin.y
+            && point.x < self.origin.x + self.width
+            && point.y < self.origin.y + self.height
+    }
+
+    /// Gets color of pixel at world coordinates
+    pub fn get(&self, point: Point) -> Option<Color> {
+        if !self.contains(point) {
+            return None;
+        }
+        let i = (point.y - self.origin.y) * self.width + (point.x - self.origin.x);
+        Some(self.pixels[i as usize])
+    }
+
+    /// Sets pixel at world coordinates, ignoring pixels outside the raster
+    pub fn set(&mut self, point: Point, color: Color) {
+        if self.contains(point) {
+            let i = (point.y - self.origin.y) * self.width + (point.x - self.origin.x);
+            self.pixels[i as usize] = color;
+        }
+    }
+
+    /// Composites color over pixel at world coordinates
+    pub fn blend(&mut self, point: Point, color: Color) {
+        if let Some(dst) = self.get(point) {
+            self.set(point, blend_over(color, dst));
+        }
+    }
+
+    /// Draws an element on top of the raster
+    pub fn draw_element(&mut self, element: &Element) {
+        self.draw_element_with(element, &mut Coverage::default());
+    }
+
+    /// Draws elements in order on top of the raster
+    pub fn draw_elements<'a>(&mut self, elements: impl IntoIterator<Item = &'a Element>) {
+        let mut coverage = Coverage::default();
+        for element in elements {
+            self.draw_element_with(element, &mut coverage);
+        }
+    }
+
+    fn draw_element_with(&mut self, element: &Element, coverage: &mut Coverage) {
+        match element {
+            Element::PaintStroke(paint_stroke) => self.draw_paint_stroke(paint_stroke, coverage),
+            Element::Shape(shape) => self.draw_shape(shape, coverage),
+            Element::Fill(fill) => self.draw_fill(fill, coverage),
+            Element::TextLabel(_) | Element::Image(_) => (),
+        }
+    }
+
+    /// Clears coverage and moves it to the part of the raster inside the box from `upper_left`
+    /// to `lower_right`. Returns false, leaving coverage empty, if none of the raster is
+    fn clip(&self, coverage: &mut Coverage, (upper_left, lower_right): (Offset, Offset)) -> bool {
+        coverage.reset(
+            Offset {
+                x: upper_left.x.max(self.origin.x),
+                y: upper_left.y.max(self.origin.y),
+            },
+            Offset {
+                x: lower_right.x.min(self.origin.x + self.width - 1),
+                y: lower_right.y.min(self.origin.y + self.height - 1),
+            },
+        );
+        !coverage.covered.is_empty()
+    }
+
+    /// Blends color over covered pixels, or overwrites them if `replace` is set, so a
+    /// transparent color erases them
+    fn apply(&mut self, coverage: &Coverage, color: Color, replace: bool) {
+        for y in 0..coverage.height {
+            let row = (coverage.upper_left.y + y - self.origin.y) * self.width
+                + (coverage.upper_left.x - self.origin.x);
+            for x in 0..coverage.width {
+                if coverage.covered[(y * coverage.width + x) as usize] {
+                    let i = (row + x) as usize;
+                    self.pixels[i] = if replace {
+                        color
+                    } else {
+                        blend_over(color, self.pixels[i])
+                    };
+                }
+            }
+        }
+    }
+
+    /// Covers pixels within `radius` of any of the segments
+    fn draw_segments(
+        &mut self,
+        coverage: &mut Coverage,
+        segments: &[(Point, Point)],
+        radius: f32,
+        color: Color,
+        replace: bool,
+    ) {
+        let points = segments
+            .iter()
+            .flat_map(|x| std::iter::once(x.0).chain(std::iter::once(x.1)));
+        match bounds(points, radius.ceil() as i32 + 1) {
+            Some(bounds) if self.clip(coverage, bounds) => (),
+            _ => return,
+        }
+        for (from, to) in segments {
+            coverage.mark_segment(*from, *to, radius);
+        }
+        self.apply(coverage, color, replace);
+    }
+
+    fn draw_paint_stroke(&mut self, paint_stroke: &PaintStroke, coverage: &mut Coverage) {
+        let radius = (paint_stroke.brush.width / 2.0).max(0.5);
+        let points: Vec<Point> = paint_stroke
+            .points
+            .iter()
+            .map(|x| Point { x: x.x, y: x.y })
+            .collect();
+        let segments: Vec<(Point, Point)> = match points.as_slice() {
+            [point] => vec![(*point, *point)],
+            _ => points.windows(2).map(|x| (x[0], x[1])).collect(),
+        };
+        let brush = &paint_stroke.brush;
+        self.draw_segments(coverage, &segments, radius, brush.color, brush.replace);
+    }
+
+    fn draw_shape(&mut self, shape: &Shape, coverage: &mut Coverage) {
+        // Closed outline of the shape, if it has an interior
+        let outline: Option<Vec<(f32, f32)>> = match &shape.kind {
+            ShapeKind::Rectangle {
+                upper_left,
+                lower_right,
+            } => Some(vec![
+                (upper_left.x as f32, upper_left.y as f32),
+                (lower_right.x as f32, upper_left.y as f32),
+                (lower_right.x as f32, lower_right.y as f32),
+                (upper_left.x as f32, lower_right.y as f32),
+            ]),
+            ShapeKind::Ellipse {
+                center,
+                radius_x,
+                radius_y,
+            } => Some(
+                (0..ELLIPSE_SEGMENTS)
+                    .map(|i| {
+                        let angle = 2.0 * std::f32::consts::PI * i as f32 / ELLIPSE_SEGMENTS as f32;
+                        (
+                            center.x as f32 + radius_x.abs() as f32 * angle.cos(),
+                            center.y as f32 + radius_y.abs() as f32 * angle.sin(),
+                        )
+                    })
+                    .collect(),
+            ),
+            ShapeKind::Polygon { points } => {
+                Some(points.iter().map(|x| (x.x as f32, x.y as f32)).collect())
+            }
+            ShapeKind::Line { .. } | ShapeKind::Arrow { .. } => None,
+        };
+        let to_point = |(x, y): (f32, f32)| Point {
+            x: x.round() as i32,
+            y: y.round() as i32,
+        };
+
+        if let (Some(outline), Some(fill)) = (&outline, &shape.fill) {
+            let points = outline.iter().copied().map(to_point);
+            if let Some(bounds) = bounds(points, 1) {
+                if self.clip(coverage, bounds) {
+                    coverage.mark_polygon(outline);
+                    self.apply(coverage, fill.color, false);
+                }
+            }
+        }
+
+        let stroke = match &shape.stroke {
+            Some(stroke) => stroke,
+            None => return,
+        };
+        let segments = match (&shape.kind, &outline) {
+            (ShapeKind::Line { from, to }, _) => vec![(*from, *to)],
+            (
+                ShapeKind::Arrow {
+                    from,
+                    to,
+                    head_size,
+                },
+                _,
+            ) => {
+                // Arrowhead as two lines angled back from the tip
+                let angle = ((to.y - from.y) as f32).atan2((to.x - from.x) as f32);
+                let mut segments = vec![(*from, *to)];
+                for side in &[-1.0, 1.0] {
+                    let head_angle = angle + side * std::f32::consts::FRAC_PI_6;
+                    let end = Point {
+                        x: (to.x as f32 - *head_size as f32 * head_angle.cos()).round() as i32,
+                        y: (to.y as f32 - *head_size as f32 * head_angle.sin()).round() as i32,
+                    };
+                    segments.push((*to, end));
+                }
+                segments
+            }
+            (_, Some(outline)) => (0..outline.len())
+                .map(|i| {
+                    (
+                        to_point(outline[i]),
+                        to_point(outline[(i + 1) % outline.len()]),
+                    )
+                })
+                .collect(),
+            (_, None) => Vec::new(),
+        };
+        let radius = (stroke.width / 2.0).max(0.5);
+        self.draw_segments(coverage, &segments, radius, stroke.color, false);
+    }
+
+    fn draw_fill(&mut self, fill: &Fill, coverage: &mut Coverage) {
+        if fill.spans.is_empty() || !self.clip(coverage, fill.bounding_box()) {
+            return;
+        }
+        let x_min = coverage.upper_left.x;
+        let x_max = coverage.upper_left.x + coverage.width;
+        for span in &fill.spans {
+            for x in span.x_start.max(x_min)..span.x_end.min(x_max) {
+                coverage.mark(x, span.y);
+            }
+        }
+        self.apply(coverage, fill.color, false);
+    }
+}
+
+/// Source-over compositing of straight (non-premultiplied) alpha colors
+pub fn blend_over(src: Color, dst: Color) -> Color {
+    let src_a = src.a as f32 / 255.0;
+    let dst_a = dst.a as f32 / 255.0;
+    let out_a = src_a + dst_a * (1.0 - src_a);
+    if out_a <= 0.0 {
+        return Color::default();
+    }
+    let channel = |s: u8, d: u8| {
+        ((s as f32 * src_a + d as f32 * dst_a * (1.0 - src_a)) / out_a).round() as u8
+    };
+    Color {
+        r: channel(src.r, dst.r),
+        g: channel(src.g, dst.g),
+        b: channel(src.b, dst.b),
+        a: (out_a * 255.0).round() as u8,
+    }
+}
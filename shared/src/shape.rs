@@ -0,0 +1,165 @@
+use crate::Color;
+use crate::ElementId;
+use crate::Offset;
+use crate::Point;
+use crate::UserId;
+use serde::{Deserialize, Serialize};
+
+/// Outline style for shapes
+#[derive(Serialize, Deserialize, Debug, PartialEq, Clone, Copy)]
+pub struct StrokeStyle {
+    pub color: Color,
+    pub width: f32,
+}
+
+impl Default for StrokeStyle {
+    fn default() -> Self {
+        StrokeStyle {
+            color: Color {
+                r: 0,
+                g: 0,
+                b: 0,
+                a: 255,
+            },
+            width: 1.0,
+        }
+    }
+}
+
+/// Interior style for closed shapes
+#[derive(Default, Serialize, Deserialize, Debug, PartialEq, Clone, Copy)]
+pub struct FillStyle {
+    pub color: Color,
+}
+
+#[derive(Serialize, Deserialize, Debug, PartialEq, Clone)]
+pub enum ShapeKind {
+    Line {
+        from: Point,
+        to: Point,
+    },
+    Rectangle {
+        upper_left: Point,
+        lower_right: Point,
+    },
+    /// Axis-aligned ellipse
+    Ellipse {
+        center: Point,
+        radius_x: i32,
+        radius_y: i32,
+    },
+    /// Closed polygon, last point connects back to the first
+    Polygon {
+        points: Vec<Point>,
+    },
+    /// Line with an arrowhead of `head_size` pixels at `to`
+    Arrow {
+        from: Point,
+        to: Point,
+        head_size: i32,
+    },
+}
+
+#[derive(Serialize, Deserialize, Debug, PartialEq, Clone)]
+pub struct Shape {
+    pub id: ElementId,
+    pub user_id: UserId,
+    pub kind: ShapeKind,
+    /// Outline, or None for no outline
+    pub stroke: Option<StrokeStyle>,
+    /// Interior fill, or None for no fill. Ignored for lines and arrows
+    pub fill: Option<FillStyle>,
+}
+
+impl Shape {
+    /// Returns upper left and lower right corners of the box containing the shape, including
+    /// its outline
+    pub fn bounding_box(&self) -> (Offset, Offset) {
+        let (mut upper_left, mut lower_right, extra) = match &self.kind {
+            ShapeKind::Line { from, to } => (*from, *to, 0),
+            ShapeKind::Rectangle {
+                upper_left,
+                lower_right,
+            } => (*upper_left, *lower_right, 0),
+            ShapeKind::Ellipse {
+                center,
+                radius_x,
+                radius_y,
+            } => {
+                let radius = Offset {
+                    x: radius_x.abs(),
+                    y: radius_y.abs(),
+                };
+                (*center - radius, *center + radius, 0)
+            }
+            ShapeKind::Polygon { points } => match points.first() {
+                Some(first) => points.iter().fold((*first, *first, 0), |acc, point| {
+                    (
+                        Offset {
+                            x: acc.0.x.min(point.x),
+                            y: acc.0.y.min(point.y),
+                        },
+                        Offset {
+                            x: acc.1.x.max(point.x),
+                            y: acc.1.y.max(point.y),
+                        },
+                        0,
+                    )
+                }),
+                None => (Offset::default(), Offset::default(), 0),
+            },
+            ShapeKind::Arrow {
+                from,
+                to,
+                head_size,
+            } => (*from, *to, head_size.abs()),
+        };
+
+        // Normalize corners in case they were given in the wrong order
+        let (x0, x1) = (
+            upper_left.x.min(lower_right.x),
+            upper_left.x.max(lower_right.x),
+        );
+        let (y0, y1) = (
+            upper_left.y.min(lower_right.y),
+            upper_left.y.max(lower_right.y),
+        );
+        upper_left = Offset { x: x0, y: y0 };
+        lower_right = Offset { x: x1, y: y1 };
+
+        // Account for outline width and arrowheads
+        let radius = match &self.stroke {
+            Some(stroke) => ((stroke.width + 1.0) / 2.0) as i32,
+            None => 0,
+        } + extra;
+        let radius = Offset {
+            x: radius,
+            y: radius,
+        };
+        (upper_left - radius, lower_right + radius)
+    }
+
+    pub fn shift(&mut self, offset: &Offset) {
+        match &mut self.kind {
+            ShapeKind::Line { from, to } | ShapeKind::Arrow { from, to, .. } => {
+                *from = *from + *offset;
+                *to = *to + *offset;
+            }
+            ShapeKind::Rectangle {
+                upper_left,
+                lower_right,
+            } => {
+                *upper_left = *upper_left + *offset;
+                *lower_right = *lower_right + *offset;
+            }
+            ShapeKind::Ellipse { center, .. } => {
+                *center = *center + *offset;
+            }
+            ShapeKind::Polygon { points } => {
+                for point in points {
+                    *point = *point + *offset;
+                }
+            }
+        }
+    }
+}
@@ -0,0 +1,117 @@
+use crate::Brush;
+use crate::Color;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+
+pub type PaletteId = u32;
+pub type BrushPresetId = u32;
+
+/// Maximum number of palettes per room
+pub const MAX_PALETTES: usize = 100;
+/// Maximum number of colors in a palette
+pub const MAX_PALETTE_COLORS: usize = 256;
+/// Maximum number of brush presets per room
+pub const MAX_BRUSH_PRESETS: usize = 100;
+/// Maximum length in bytes of palette and preset names
+pub const MAX_STYLE_NAME_LEN: usize = 64;
+
+/// Named set of color swatches shared by everyone in a room
+#[derive(Default, Serialize, Deserialize, Debug, PartialEq, Clone)]
+pub struct Palette {
+    pub id: PaletteId,
+    pub name: String,
+    pub colors: Vec<Color>,
+}
+
+/// Named brush shared by everyone in a room
+#[derive(Default, Serialize, Deserialize, Debug, PartialEq, Clone)]
+pub struct BrushPreset {
+    pub id: BrushPresetId,
+    pub name: String,
+    pub brush: Brush,
+}
+
+/// Palettes and brush presets belonging to a room
+#[derive(Default, Serialize, Deserialize, Debug, PartialEq, Clone)]
+pub struct StyleLibrary {
+    palettes: BTreeMap<PaletteId, Palette>,
+    brush_presets: BTreeMap<BrushPresetId, BrushPreset>,
+    next_palette_id: PaletteId,
+    next_brush_preset_id: BrushPresetId,
+}
+
+fn valid_palette(palette: &Palette) -> bool {
+    palette.name.len() <= MAX_STYLE_NAME_LEN && palette.colors.len() <= MAX_PALETTE_COLORS
+}
+
+fn valid_brush_preset(brush_preset: &BrushPreset) -> bool {
+    brush_preset.name.len() <= MAX_STYLE_NAME_LEN
+}
+
+impl StyleLibrary {
+    pub fn palettes(&self) -> impl Iterator<Item = &Palette> {
+        self.palettes.values()
+    }
+
+    pub fn brush_presets(&self) -> impl Iterator<Item = &BrushPreset> {
+        self.brush_presets.values()
+    }
+
+    /// Adds palette, assigning it a new id. Returns the stored palette, or None if it's too
+    /// large or MAX_PALETTES has been reached
+    pub fn add_palette(&mut self, mut palette: Palette) -> Option<&Palette> {
+        if !valid_palette(&palette) || self.palettes.len() >= MAX_PALETTES {
+            return None;
+        }
+        palette.id = self.next_palette_id;
+        self.next_palette_id += 1;
+        Some(self.palettes.entry(palette.id).or_insert(palette))
+    }
+
+    /// Replaces the palette with the same id. Returns the stored palette, or None if it doesn't
+    /// exist or is too large
+    pub fn edit_palette(&mut self, palette: Palette) -> Option<&Palette> {
+        if !valid_palette(&palette) {
+            return None;
+        }
+        let existing = self.palettes.get_mut(&palette.id)?;
+        *existing = palette;
+        Some(existing)
+    }
+
+    /// Removes palette. Returns true if it existed
+    pub fn remove_palette(&mut self, palette_id: PaletteId) -> bool {
+        self.palettes.remove(&palette_id).is_some()
+    }
+
+    /// Adds brush preset, assigning it a new id. Returns the stored preset, or None if its name
+    /// is too long or MAX_BRUSH_PRESETS has been reached
+    pub fn add_brush_preset(&mut self, mut brush_preset: BrushPreset) -> Option<&BrushPreset> {
+        if !valid_brush_preset(&brush_preset) || self.brush_presets.len() >= MAX_BRUSH_PRESETS {
+            return None;
+        }
+        brush_preset.id = self.next_brush_preset_id;
+        self.next_brush_preset_id += 1;
+        Some(
+            self.brush_presets
+                .entry(brush_preset.id)
+                .or_insert(brush_preset),
+        )
+    }
+
+    /// Replaces the brush preset with the same id. Returns the stored preset, or None if it
+    /// doesn't exist or its name is too long
+    pub fn edit_brush_preset(&mut self, brush_preset: BrushPreset) -> Option<&BrushPreset> {
+        if !valid_brush_preset(&brush_preset) {
+            return None;
+        }
+        let existing = self.brush_presets.get_mut(&brush_preset.id)?;
+        *existing = brush_preset;
+        Some(existing)
+    }
+
+    /// Removes brush preset. Returns true if it existed
+    pub fn remove_brush_preset(&mut self, brush_preset_id: BrushPresetId) -> bool {
+        self.brush_presets.remove(&brush_preset_id).is_some()
+    }
+}
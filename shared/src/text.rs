@@ -0,0 +1,78 @@
+use crate::Color;
+use crate::ElementId;
+use crate::Offset;
+use crate::Point;
+use crate::UserId;
+use serde::{Deserialize, Serialize};
+
+/// Approximate glyph width relative to font size, used to estimate text extents without font
+/// metrics
+const APPROX_GLYPH_WIDTH: f32 = 0.6;
+
+/// Text label anchored at a world coordinate
+#[derive(Serialize, Deserialize, Debug, PartialEq, Clone)]
+pub struct TextLabel {
+    pub id: ElementId,
+    pub user_id: UserId,
+    /// Top left corner of the first line of text before rotation
+    pub position: Point,
+    pub content: String,
+    /// Font size in pixels
+    pub font_size: f32,
+    pub color: Color,
+    /// Clockwise rotation around `position`, in radians
+    pub rotation: f32,
+}
+
+impl Default for TextLabel {
+    fn default() -> Self {
+        TextLabel {
+            id: 0,
+            user_id: 0,
+            position: Point::default(),
+            content: String::new(),
+            font_size: 16.0,
+            color: Color {
+                r: 0,
+                g: 0,
+                b: 0,
+                a: 255,
+            },
+            rotation: 0.0,
+        }
+    }
+}
+
+impl TextLabel {
+    /// Returns upper left and lower right corners of a box guaranteed to contain the label at
+    /// any rotation. Since renderers may use different fonts, the unrotated extents are only
+    /// estimated from the character count
+    pub fn bounding_box(&self) -> (Offset, Offset) {
+        let font_size = if self.font_size.is_finite() {
+            self.font_size.abs()
+        } else {
+            0.0
+        };
+        let lines = self.content.lines().count().max(1);
+        let columns = self
+            .content
+            .lines()
+            .map(|line| line.chars().count())
+            .max()
+            .unwrap_or(0);
+        let width = columns as f32 * font_size * APPROX_GLYPH_WIDTH;
+        let height = lines as f32 * font_size;
+
+        // Rotation is around position, so any rotated point is within the diagonal from it
+        let radius = (width * width + height * height).sqrt().ceil() as i32 + 1;
+        let radius = Offset {
+            x: radius,
+            y: radius,
+        };
+        (self.position - radius, self.position + radius)
+    }
+
+    pub fn shift(&mut self, offset: &Offset) {
+        self.position = self.position + *offset;
+    }
+}
@@ -0,0 +1,374 @@
+use crate::styles::{MAX_PALETTE_COLORS, MAX_STYLE_NAME_LEN};
+use crate::tile_ops;
+use crate::Brush;
+use crate::BrushPreset;
+use crate::ClientMessage;
+use crate::ErrorCode;
+use crate::FloodFill;
+use crate::NodeProperties;
+use crate::Offset;
+use crate::PaintStroke;
+use crate::Palette;
+use crate::PlacedImage;
+use crate::Shape;
+use crate::ShapeKind;
+use crate::StrokeErase;
+use crate::TextLabel;
+use crate::TILE_SIZE;
+use serde::{Deserialize, Serialize};
+use std::fmt;
+
+/// Limits on the size and values of messages sent by clients. Values within these limits keep
+/// coordinate arithmetic from overflowing, and bound the tiles a message touches and the work
+/// done for it
+#[derive(Serialize, Deserialize, Debug, PartialEq, Clone)]
+#[serde(default)]
+pub struct Limits {
+    /// Maximum size in bytes of a compressed message as received
+    pub max_message_size: usize,
+    /// Maximum size in bytes of a message after inflating, see `from_zbincode_bounded`
+    pub max_inflated_size: usize,
+    /// Maximum absolute value of any x or y coordinate
+    pub max_coordinate: i32,
+    /// Maximum number of points in a paint stroke
+    pub max_stroke_points: usize,
+    /// Maximum brush, outline and eraser width
+    pub max_brush_width: f32,
+    /// Maximum number of points in a polygon
+    pub max_polygon_points: usize,
+    /// Maximum number of points in an object eraser path
+    pub max_erase_points: usize,
+    /// Maximum length in bytes of text label contents
+    pub max_text_len: usize,
+    /// Maximum text label font size in pixels
+    pub max_font_size: f32,
+    /// Maximum natural width or height of a placed image in pixels
+    pub max_image_size: u32,
+    /// Maximum scale of a placed image
+    pub max_image_scale: f32,
+    /// Maximum length in bytes of a chat message
+    pub max_chat_len: usize,
+    /// Maximum number of tiles covered by a viewport
+    pub max_viewport_tiles: usize,
+    /// Maximum number of tiles covered by the bounding box of a shape, text label or image
+    pub max_element_tiles: usize,
+    /// Maximum flood fill tolerance
+    pub max_fill_tolerance: u8,
+    /// Maximum number of elements drawn to compute a flood fill
+    pub max_fill_elements: usize,
+    /// Maximum number of spans in a computed flood fill
+    pub max_fill_spans: usize,
+}
+
+impl Default for Limits {
+    fn default() -> Self {
+        Limits {
+            max_message_size: 1 << 18,
+            max_inflated_size: 1 << 20,
+            max_coordinate: 1 << 24,
+            max_stroke_points: 10_000,
+            max_brush_width: 1000.0,
+            max_polygon_points: 1000,
+            max_erase_points: 10_000,
+            max_text_len: 4096,
+            max_font_size: 1000.0,
+            max_image_size: 16384,
+            max_image_scale: 100.0,
+            max_chat_len: 4096,
+            max_viewport_tiles: 10_000,
+            max_element_tiles: 10_000,
+            max_fill_tolerance: 128,
+            max_fill_elements: 1000,
+            max_fill_spans: 20_000,
+        }
+    }
+}
+
+/// Reason a client message was rejected
+#[derive(Debug, PartialEq, Clone)]
+pub enum ValidationError {
+    /// List or string has more than `max` items or bytes
+    TooLong {
+        field: &'static str,
+        len: usize,
+        max: usize,
+    },
+    /// Number is NaN or infinite
+    NotFinite(&'static str),
+    /// Number or coordinate is outside the allowed range
+    OutOfRange(&'static str),
+}
+
+impl ValidationError {
+    /// Error code sent to the client
+    pub fn code(&self) -> ErrorCode {
+        ErrorCode::Invalid
+    }
+}
+
+impl fmt::Display for ValidationError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ValidationError::TooLong { field, len, max } => {
+                write!(f, "{} has length {}, maximum is {}", field, len, max)
+            }
+            ValidationError::NotFinite(field) => write!(f, "{} is not a finite number", field),
+            ValidationError::OutOfRange(field) => write!(f, "{} is out of range", field),
+        }
+    }
+}
+
+impl std::error::Error for ValidationError {}
+
+type Result = std::result::Result<(), ValidationError>;
+
+fn check_len(field: &'static str, len: usize, max: usize) -> Result {
+    if len > max {
+        return Err(ValidationError::TooLong { field, len, max });
+    }
+    Ok(())
+}
+
+fn check_finite(field: &'static str, value: f32) -> Result {
+    if !value.is_finite() {
+        return Err(ValidationError::NotFinite(field));
+    }
+    Ok(())
+}
+
+/// Checks that `value` is finite and within `min..=max`
+fn check_f32(field: &'static str, value: f32, min: f32, max: f32) -> Result {
+    check_finite(field, value)?;
+    if value < min || value > max {
+        return Err(ValidationError::OutOfRange(field));
+    }
+    Ok(())
+}
+
+impl Limits {
+    /// Checks a message from a client against the limits
+    pub fn validate(&self, msg: &ClientMessage) -> Result {
+        match msg {
+            ClientMessage::PaintStroke(_, _, paint_stroke) => {
+                self.validate_paint_stroke(paint_stroke)
+            }
+            ClientMessage::Shape(_, shape) => self.validate_shape(shape),
+            ClientMessage::TextLabel(_, text_label)
+            | ClientMessage::EditTextLabel(_, text_label) => self.validate_text_label(text_label),
+            ClientMessage::PlaceImage(_, image) => self.validate_image(image),
+            ClientMessage::FloodFill(_, flood_fill) => self.validate_flood_fill(flood_fill),
+            ClientMessage::StrokeErase(_, stroke_erase) => self.validate_stroke_erase(stroke_erase),
+            ClientMessage::AddPalette(palette) | ClientMessage::EditPalette(palette) => {
+                self.validate_palette(palette)
+            }
+            ClientMessage::AddBrushPreset(brush_preset)
+            | ClientMessage::EditBrushPreset(brush_preset) => {
+                self.validate_brush_preset(brush_preset)
+            }
+            ClientMessage::SetViewPort(upper_left, lower_right) => {
+                self.validate_viewport(upper_left, lower_right)
+            }
+            ClientMessage::ChatMessage(chat_message) => {
+                check_len("chat message", chat_message.len(), self.max_chat_len)
+            }
+            ClientMessage::FetchTile(_, tile_offset) => self.check_point("tile", tile_offset),
+            ClientMessage::SetNodeProperties(_, properties) => {
+                self.validate_node_properties(properties)
+            }
+            // Ids are looked up by the room, and unknown ones rejected there
+            ClientMessage::RemovePalette(_)
+            | ClientMessage::RemoveBrushPreset(_)
+            | ClientMessage::UndoMessage
+            | ClientMessage::CreateGroup
+            | ClientMessage::MoveNode(..)
+            | ClientMessage::SetLayerClip(..) => Ok(()),
+        }
+    }
+
+    fn check_coordinate(&self, field: &'static str, value: i32) -> Result {
+        if value < -self.max_coordinate || value > self.max_coordinate {
+            return Err(ValidationError::OutOfRange(field));
+        }
+        Ok(())
+    }
+
+    fn check_point(&self, field: &'static str, point: &Offset) -> Result {
+        self.check_coordinate(field, point.x)?;
+        self.check_coordinate(field, point.y)
+    }
+
+    /// Checks that the box from `upper_left` to `lower_right` covers at most `max` tiles
+    fn check_tiles(
+        &self,
+        field: &'static str,
+        (upper_left, lower_right): (Offset, Offset),
+        max: usize,
+    ) -> Result {
+        let upper_left = tile_ops::point_to_tile_offset(upper_left.x, upper_left.y);
+        let lower_right = tile_ops::point_to_tile_offset(lower_right.x, lower_right.y);
+        let num_x_tiles = (lower_right.x - upper_left.x) / TILE_SIZE + 1;
+        let num_y_tiles = (lower_right.y - upper_left.y) / TILE_SIZE + 1;
+        if num_x_tiles <= 0 || num_y_tiles <= 0 {
+            return Ok(());
+        }
+        check_len(
+            field,
+            (num_x_tiles as usize).saturating_mul(num_y_tiles as usize),
+            max,
+        )
+    }
+
+    pub fn validate_brush(&self, brush: &Brush) -> Result {
+        check_f32("brush width", brush.width, 0.0, self.max_brush_width)?;
+        check_f32("brush hardness", brush.hardness, 0.0, 1.0)?;
+        check_f32("brush smudging", brush.smudging, 0.0, 1.0)
+    }
+
+    pub fn validate_paint_stroke(&self, paint_stroke: &PaintStroke) -> Result {
+        check_len(
+            "paint stroke",
+            paint_stroke.points.len(),
+            self.max_stroke_points,
+        )?;
+        self.validate_brush(&paint_stroke.brush)?;
+        for point in &paint_stroke.points {
+            check_f32("pressure", point.p, 0.0, 1.0)?;
+            self.check_coordinate("paint stroke", point.x)?;
+            self.check_coordinate("paint stroke", point.y)?;
+        }
+        Ok(())
+    }
+
+    /// Checks the shape's points, and that it doesn't cover too many tiles
+    pub fn validate_shape(&self, shape: &Shape) -> Result {
+        if let Some(stroke) = &shape.stroke {
+            check_f32("outline width", stroke.width, 0.0, self.max_brush_width)?;
+        }
+        self.validate_shape_kind(shape)?;
+        self.check_tiles("shape", shape.bounding_box(), self.max_element_tiles)
+    }
+
+    fn validate_shape_kind(&self, shape: &Shape) -> Result {
+        match &shape.kind {
+            ShapeKind::Line { from, to } => {
+                self.check_point("line", from)?;
+                self.check_point("line", to)
+            }
+            ShapeKind::Rectangle {
+                upper_left,
+                lower_right,
+            } => {
+                self.check_point("rectangle", upper_left)?;
+                self.check_point("rectangle", lower_right)
+            }
+            ShapeKind::Ellipse {
+                center,
+                radius_x,
+                radius_y,
+            } => {
+                self.check_point("ellipse", center)?;
+                self.check_coordinate("ellipse radius", *radius_x)?;
+                self.check_coordinate("ellipse radius", *radius_y)
+            }
+            ShapeKind::Polygon { points } => {
+                check_len("polygon", points.len(), self.max_polygon_points)?;
+                for point in points {
+                    self.check_point("polygon", point)?;
+                }
+                Ok(())
+            }
+            ShapeKind::Arrow {
+                from,
+                to,
+                head_size,
+            } => {
+                self.check_point("arrow", from)?;
+                self.check_point("arrow", to)?;
+                self.check_coordinate("arrowhead size", *head_size)
+            }
+        }
+    }
+
+    pub fn validate_text_label(&self, text_label: &TextLabel) -> Result {
+        check_len("text", text_label.content.len(), self.max_text_len)?;
+        check_f32("font size", text_label.font_size, 0.0, self.max_font_size)?;
+        check_finite("rotation", text_label.rotation)?;
+        self.check_point("text position", &text_label.position)?;
+        self.check_tiles(
+            "text label",
+            text_label.bounding_box(),
+            self.max_element_tiles,
+        )
+    }
+
+    pub fn validate_image(&self, image: &PlacedImage) -> Result {
+        check_len(
+            "image width",
+            image.width as usize,
+            self.max_image_size as usize,
+        )?;
+        check_len(
+            "image height",
+            image.height as usize,
+            self.max_image_size as usize,
+        )?;
+        check_f32("image scale", image.scale, 0.0, self.max_image_scale)?;
+        check_finite("rotation", image.rotation)?;
+        self.check_point("image position", &image.position)?;
+        self.check_tiles("image", image.bounding_box(), self.max_element_tiles)
+    }
+
+    pub fn validate_flood_fill(&self, flood_fill: &FloodFill) -> Result {
+        if flood_fill.tolerance > self.max_fill_tolerance {
+            return Err(ValidationError::OutOfRange("fill tolerance"));
+        }
+        self.check_point("seed", &flood_fill.seed)
+    }
+
+    pub fn validate_palette(&self, palette: &Palette) -> Result {
+        check_len("palette name", palette.name.len(), MAX_STYLE_NAME_LEN)?;
+        check_len("palette", palette.colors.len(), MAX_PALETTE_COLORS)
+    }
+
+    pub fn validate_brush_preset(&self, brush_preset: &BrushPreset) -> Result {
+        check_len(
+            "brush preset name",
+            brush_preset.name.len(),
+            MAX_STYLE_NAME_LEN,
+        )?;
+        self.validate_brush(&brush_preset.brush)
+    }
+
+    pub fn validate_node_properties(&self, properties: &NodeProperties) -> Result {
+        check_f32("opacity", properties.opacity, 0.0, 1.0)
+    }
+
+    pub fn validate_stroke_erase(&self, stroke_erase: &StrokeErase) -> Result {
+        check_len(
+            "eraser path",
+            stroke_erase.path.len(),
+            self.max_erase_points,
+        )?;
+        check_f32(
+            "eraser width",
+            stroke_erase.width,
+            0.0,
+            self.max_brush_width,
+        )?;
+        for point in &stroke_erase.path {
+            self.check_point("eraser path", point)?;
+        }
+        Ok(())
+    }
+
+    /// Checks the viewport corners, and that the viewport doesn't cover too many tiles
+    pub fn validate_viewport(&self, upper_left: &Offset, lower_right: &Offset) -> Result {
+        self.check_point("viewport", upper_left)?;
+        self.check_point("viewport", lower_right)?;
+        self.check_tiles(
+            "viewport",
+            (*upper_left, *lower_right),
+            self.max_viewport_tiles,
+        )
+    }
+}